@@ -0,0 +1,57 @@
+use serde::{Deserialize, Serialize};
+
+/// Writes to several keys, applied atomically with [`KvStore::write`](crate::KvStore::write).
+///
+/// See `write` for what is guaranteed after a crash.
+///
+/// ```rust
+/// # use kvs::{KvStore, Result, WriteBatch};
+/// # fn try_main() -> Result<()> {
+/// # let dir = tempfile::TempDir::new()?;
+/// let mut store = KvStore::open(dir.path())?;
+/// let mut batch = WriteBatch::new();
+/// batch.set("from".to_owned(), "90".to_owned());
+/// batch.set("to".to_owned(), "110".to_owned());
+/// store.write(batch)?;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct WriteBatch {
+    // the keys and values written in order, `None` for removals.
+    pub(crate) writes: Vec<(String, Option<String>)>,
+}
+
+impl WriteBatch {
+    /// Creates an empty batch.
+    pub fn new() -> WriteBatch {
+        WriteBatch::default()
+    }
+
+    /// Sets the value of a key.
+    pub fn set(&mut self, key: String, value: String) -> &mut WriteBatch {
+        self.writes.push((key, Some(value)));
+        self
+    }
+
+    /// Removes a key, which has to exist when the removal is applied.
+    pub fn remove(&mut self, key: String) -> &mut WriteBatch {
+        self.writes.push((key, None));
+        self
+    }
+
+    /// Returns the number of writes in the batch.
+    pub fn len(&self) -> usize {
+        self.writes.len()
+    }
+
+    /// Returns whether the batch has no writes.
+    pub fn is_empty(&self) -> bool {
+        self.writes.is_empty()
+    }
+
+    /// Removes all writes from the batch.
+    pub fn clear(&mut self) {
+        self.writes.clear();
+    }
+}
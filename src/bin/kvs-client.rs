@@ -0,0 +1,120 @@
+use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
+use kvs::encoding::Encoding;
+use kvs::{KvsClient, KvsError, Result};
+use std::process::exit;
+
+fn main() -> Result<()> {
+    let addr_arg = Arg::with_name("addr")
+        .long("addr")
+        .takes_value(true)
+        .value_name("IP:PORT")
+        .default_value("127.0.0.1:4000")
+        .help("The address of the server");
+    let encoding_arg = |name| {
+        Arg::with_name(name)
+            .long(name)
+            .takes_value(true)
+            .value_name("ENCODING")
+            .possible_values(Encoding::NAMES)
+            .default_value("utf8")
+    };
+    let key_encoding_arg = encoding_arg("key-encoding").help("The encoding of the key passed");
+    let value_encoding_arg =
+        encoding_arg("value-encoding").help("The encoding of the value passed or printed");
+    let matches = App::new("kvs-client")
+        .version(env!("CARGO_PKG_VERSION"))
+        .author(env!("CARGO_PKG_AUTHORS"))
+        .about("Access a store served by kvs-server")
+        .setting(AppSettings::DisableHelpSubcommand)
+        .setting(AppSettings::SubcommandRequiredElseHelp)
+        .setting(AppSettings::VersionlessSubcommands)
+        .subcommand(
+            SubCommand::with_name("set")
+                .about("Set the value of a string key to a string")
+                .arg(Arg::with_name("KEY").help("A string key").required(true))
+                .arg(
+                    Arg::with_name("VALUE")
+                        .help("The string value of the key")
+                        .required(true),
+                )
+                .arg(addr_arg.clone())
+                .arg(key_encoding_arg.clone())
+                .arg(value_encoding_arg.clone()),
+        )
+        .subcommand(
+            SubCommand::with_name("get")
+                .about("Get the string value of a given string key")
+                .arg(Arg::with_name("KEY").help("A string key").required(true))
+                .arg(addr_arg.clone())
+                .arg(key_encoding_arg.clone())
+                .arg(value_encoding_arg)
+                .arg(
+                    Arg::with_name("strict")
+                        .long("strict")
+                        .help("Exit with status 1 if the key is not found, printing nothing"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("rm")
+                .about("Remove a given key")
+                .arg(Arg::with_name("KEY").help("A string key").required(true))
+                .arg(addr_arg)
+                .arg(key_encoding_arg),
+        )
+        .get_matches();
+
+    match matches.subcommand() {
+        ("set", Some(matches)) => {
+            let key = matches.value_of("KEY").expect("KEY argument missing");
+            let value = matches.value_of("VALUE").expect("VALUE argument missing");
+            let addr = matches.value_of("addr").expect("addr argument missing");
+            let key = encoding(matches, "key-encoding").decode(key)?;
+            let value = encoding(matches, "value-encoding").decode(value)?;
+
+            let mut client = KvsClient::connect(addr)?;
+            client.set(key, value)?;
+        }
+        ("get", Some(matches)) => {
+            let key = matches.value_of("KEY").expect("KEY argument missing");
+            let addr = matches.value_of("addr").expect("addr argument missing");
+
+            let key = encoding(matches, "key-encoding").decode(key)?;
+
+            let mut client = KvsClient::connect(addr)?;
+            match client.get(key)? {
+                Some(value) => println!("{}", encoding(matches, "value-encoding").encode(&value)),
+                None if matches.is_present("strict") => {
+                    eprintln!("Key not found");
+                    exit(1);
+                }
+                None => println!("Key not found"),
+            }
+        }
+        ("rm", Some(matches)) => {
+            let key = matches.value_of("KEY").expect("KEY argument missing");
+            let addr = matches.value_of("addr").expect("addr argument missing");
+
+            let key = encoding(matches, "key-encoding").decode(key)?;
+
+            let mut client = KvsClient::connect(addr)?;
+            match client.remove(key) {
+                Err(KvsError::KeyNotFound) => {
+                    println!("Key not found");
+                    exit(1);
+                }
+                result => result?,
+            }
+        }
+        _ => unreachable!(),
+    }
+    Ok(())
+}
+
+/// Returns the encoding of the given argument.
+fn encoding(matches: &ArgMatches, arg: &str) -> Encoding {
+    matches
+        .value_of(arg)
+        .unwrap_or("utf8")
+        .parse()
+        .expect("encoding argument invalid")
+}
@@ -0,0 +1,306 @@
+use clap::{App, Arg};
+#[cfg(target_os = "linux")]
+use kvs::handoff::{self, Offer};
+use kvs::protocol::{Limits, Protocol};
+use kvs::redact::Prefixes;
+use kvs::{KvStore, KvsServer, Options, Result, VerifyLevel};
+use log::{LevelFilter, Log, Metadata, Record};
+use std::env::{self, current_dir};
+use std::io;
+use std::net::TcpListener;
+#[cfg(target_os = "linux")]
+use std::net::TcpStream;
+#[cfg(target_os = "linux")]
+use std::os::linux::net::SocketAddrExt;
+#[cfg(unix)]
+use std::os::unix::{
+    ffi::OsStrExt,
+    io::FromRawFd,
+    net::{SocketAddr, UnixDatagram},
+};
+use std::path::Path;
+use std::process;
+#[cfg(target_os = "linux")]
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    mpsc, Arc,
+};
+#[cfg(target_os = "linux")]
+use std::thread;
+
+// the first file descriptor passed by systemd socket activation.
+#[cfg(unix)]
+const SD_LISTEN_FDS_START: i32 = 3;
+
+fn main() -> Result<()> {
+    let matches = App::new("kvs-server")
+        .version(env!("CARGO_PKG_VERSION"))
+        .author(env!("CARGO_PKG_AUTHORS"))
+        .about("Serve the store in the current directory over TCP")
+        .arg(
+            Arg::with_name("addr")
+                .long("addr")
+                .takes_value(true)
+                .value_name("IP:PORT")
+                .default_value("127.0.0.1:4000")
+                .help("The address to listen on, unless systemd passes a socket"),
+        )
+        .arg(
+            Arg::with_name("engine")
+                .long("engine")
+                .takes_value(true)
+                .value_name("ENGINE")
+                .possible_values(&["kvs"])
+                .default_value("kvs")
+                .help("The storage engine"),
+        )
+        .arg(
+            Arg::with_name("protocol")
+                .long("protocol")
+                .takes_value(true)
+                .value_name("PROTOCOL")
+                .possible_values(Protocol::NAMES)
+                .default_value("kvs")
+                .help("The protocol spoken with clients, resp for redis clients"),
+        )
+        .arg(
+            Arg::with_name("max-frame")
+                .long("max-frame")
+                .takes_value(true)
+                .value_name("BYTES")
+                .help("The largest request accepted, 4 MiB by default"),
+        )
+        .arg(
+            Arg::with_name("max-key-len")
+                .long("max-key-len")
+                .takes_value(true)
+                .value_name("BYTES")
+                .help("The longest key accepted, 64 KiB by default"),
+        )
+        .arg(
+            Arg::with_name("verify-on-start")
+                .long("verify-on-start")
+                .takes_value(true)
+                .value_name("LEVEL")
+                .possible_values(&["full", "quick", "none"])
+                .default_value("quick")
+                .help("How the log is checked against the manifest on start"),
+        )
+        .arg(
+            Arg::with_name("record-trace")
+                .long("record-trace")
+                .takes_value(true)
+                .value_name("TRACE")
+                .help("Record the served operations for kvs replay"),
+        )
+        .arg(
+            Arg::with_name("sample")
+                .long("sample")
+                .takes_value(true)
+                .value_name("RATIO")
+                .help("Log the given fraction of requests with their responses"),
+        )
+        .arg(
+            Arg::with_name("redact-values")
+                .long("redact-values")
+                .help("Log the lengths of sampled values instead of the values"),
+        )
+        .arg(
+            Arg::with_name("redact-prefix")
+                .long("redact-prefix")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .value_name("PREFIX")
+                .help(
+                    "Log the lengths of the values of keys with the prefix, in samples and traces",
+                ),
+        )
+        .arg(
+            Arg::with_name("takeover")
+                .long("takeover")
+                .help("Take the socket over from the server running on the store"),
+        )
+        .get_matches();
+
+    let mut limits = Limits::new();
+    if let Some(bytes) = matches.value_of("max-frame") {
+        limits = limits.max_frame(bytes.parse().expect("max-frame argument invalid"));
+    }
+    if let Some(bytes) = matches.value_of("max-key-len") {
+        limits = limits.max_key_len(bytes.parse().expect("max-key-len argument invalid"));
+    }
+    let verify = match matches.value_of("verify-on-start") {
+        Some("full") => VerifyLevel::Full,
+        Some("none") => VerifyLevel::None,
+        _ => VerifyLevel::Quick,
+    };
+    let mut options = Options::new().verify(verify).exclusive(true);
+    if let Some(path) = matches.value_of("record-trace") {
+        options = options.record_trace(path);
+    }
+    let redacted = match matches.values_of("redact-prefix") {
+        Some(prefixes) => Prefixes(prefixes.map(str::to_owned).collect()),
+        None => Prefixes::default(),
+    };
+    if !redacted.0.is_empty() {
+        options = options.redactor(redacted.clone());
+    }
+    let sample = match matches.value_of("sample") {
+        Some(ratio) => ratio.parse().expect("sample argument invalid"),
+        None => 0.0,
+    };
+    let addr = matches.value_of("addr").expect("addr argument missing");
+    let protocol: Protocol = matches
+        .value_of("protocol")
+        .expect("protocol argument missing")
+        .parse()?;
+
+    log::set_logger(&STDERR_LOGGER).expect("logger already set");
+    log::set_max_level(LevelFilter::Info);
+    let dir = current_dir()?;
+    // the passed socket is claimed before opening files could reuse its descriptor.
+    let listener = if matches.is_present("takeover") {
+        take_over(&dir)?
+    } else {
+        match activated_listener() {
+            Some(listener) => listener,
+            None => TcpListener::bind(addr)?,
+        }
+    };
+    let store = KvStore::open_with_options(&dir, options)?;
+    eprintln!(
+        "kvs-server {} listening on {}",
+        env!("CARGO_PKG_VERSION"),
+        listener.local_addr()?
+    );
+    if let Err(e) = notify_ready() {
+        eprintln!("failed to notify systemd of readiness: {}", e);
+    }
+    let mut server = KvsServer::new(store)
+        .protocol(protocol)
+        .limits(limits)
+        .sample(sample);
+    if matches.is_present("redact-values") {
+        server = server.redact_values(true);
+    } else if !redacted.0.is_empty() {
+        server = server.redactor(redacted);
+    }
+    serve(server, &listener, &dir)
+}
+
+static STDERR_LOGGER: StderrLogger = StderrLogger;
+
+/// Writes log records to stderr, next to the other messages of the server.
+struct StderrLogger;
+
+impl Log for StderrLogger {
+    fn enabled(&self, _metadata: &Metadata) -> bool {
+        true
+    }
+
+    fn log(&self, record: &Record) {
+        eprintln!("{} {}: {}", record.level(), record.target(), record.args());
+    }
+
+    fn flush(&self) {}
+}
+
+/// Serves until another server takes the socket over, then closes the store and lets
+/// the other server open it.
+#[cfg(target_os = "linux")]
+fn serve(mut server: KvsServer<KvStore>, listener: &TcpListener, dir: &Path) -> Result<()> {
+    let offer = Offer::bind(dir)?;
+    let socket = listener.try_clone()?;
+    let stop = Arc::new(AtomicBool::new(false));
+    let waker = Arc::clone(&stop);
+    let (handover_tx, handover_rx) = mpsc::channel();
+    thread::spawn(move || match offer.wait(&socket) {
+        Ok(handover) => {
+            waker.store(true, Ordering::SeqCst);
+            // wakes the server up if it waits for a connection.
+            let _ = socket.local_addr().and_then(TcpStream::connect);
+            let _ = handover_tx.send(handover);
+        }
+        Err(e) => eprintln!("failed to hand the socket over: {}", e),
+    });
+    server.serve_until(listener, &stop)?;
+    drop(server);
+    // dropping the handover tells the other server that the store is closed.
+    drop(handover_rx.recv());
+    eprintln!("handed the socket over to the new server");
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn serve(mut server: KvsServer<KvStore>, listener: &TcpListener, _dir: &Path) -> Result<()> {
+    server.serve(listener)
+}
+
+#[cfg(target_os = "linux")]
+fn take_over(dir: &Path) -> Result<TcpListener> {
+    handoff::take_over(dir)
+}
+
+/// Socket handoff is only supported on Linux.
+#[cfg(not(target_os = "linux"))]
+fn take_over(_dir: &Path) -> Result<TcpListener> {
+    Err(io::Error::new(
+        io::ErrorKind::Other,
+        "--takeover is only supported on Linux",
+    )
+    .into())
+}
+
+/// Returns the listening socket passed by systemd socket activation, if any.
+///
+/// The socket outlives restarts of the server, so connections arriving while it
+/// restarts wait instead of being refused.
+#[cfg(unix)]
+fn activated_listener() -> Option<TcpListener> {
+    let pid: u32 = env::var("LISTEN_PID").ok()?.parse().ok()?;
+    if pid != process::id() {
+        return None;
+    }
+    let fds: i32 = env::var("LISTEN_FDS").ok()?.parse().ok()?;
+    // the variables are meant for this process only, not for its children.
+    env::remove_var("LISTEN_PID");
+    env::remove_var("LISTEN_FDS");
+    env::remove_var("LISTEN_FDNAMES");
+    if fds > 1 {
+        eprintln!("serving the first of {} sockets passed by systemd", fds);
+    }
+    if fds < 1 {
+        return None;
+    }
+    // systemd passes the sockets open and bound; the server owns them from now on.
+    Some(unsafe { TcpListener::from_raw_fd(SD_LISTEN_FDS_START) })
+}
+
+#[cfg(not(unix))]
+fn activated_listener() -> Option<TcpListener> {
+    None
+}
+
+/// Tells systemd that the server is ready, if it is run as a `Type=notify` service.
+#[cfg(unix)]
+fn notify_ready() -> io::Result<()> {
+    let path = match env::var_os("NOTIFY_SOCKET") {
+        Some(path) => path,
+        None => return Ok(()),
+    };
+    env::remove_var("NOTIFY_SOCKET");
+    let socket = UnixDatagram::unbound()?;
+    let addr = match path.as_bytes() {
+        #[cfg(target_os = "linux")]
+        [b'@', name @ ..] => SocketAddr::from_abstract_name(name)?,
+        _ => SocketAddr::from_pathname(&path)?,
+    };
+    socket.send_to_addr(b"READY=1", &addr)?;
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn notify_ready() -> io::Result<()> {
+    Ok(())
+}
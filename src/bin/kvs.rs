@@ -1,7 +1,17 @@
-use clap::{App, AppSettings, Arg, SubCommand};
-use kvs::{KvStore, KvsError, Result};
+use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
+use kvs::encoding::Encoding;
+use kvs::export::{self, Format};
+use kvs::genload::{Distribution, Generator};
+use kvs::redact::{Prefixes, Redactor};
+use kvs::schema::Versioned;
+#[cfg(feature = "server")]
+use kvs::KvsClient;
+use kvs::{trace, Change, KeyPattern, KvStore, KvsEngine, KvsError, Result, Stats, StoreMeta};
+use serde::Serialize;
 use std::env::current_dir;
+use std::io;
 use std::process::exit;
+use std::time::{Duration, Instant, UNIX_EPOCH};
 
 fn main() -> Result<()> {
     let matches = App::new(env!("CARGO_PKG_NAME"))
@@ -11,6 +21,55 @@ fn main() -> Result<()> {
         .setting(AppSettings::DisableHelpSubcommand)
         .setting(AppSettings::SubcommandRequiredElseHelp)
         .setting(AppSettings::VersionlessSubcommands)
+        .arg(
+            Arg::with_name("engine")
+                .long("engine")
+                .takes_value(true)
+                .value_name("ENGINE")
+                .possible_values(&["kvs"])
+                .default_value("kvs")
+                .global(true)
+                .help("The storage engine of set, get, rm, copy, ttl, expire and persist"),
+        )
+        .arg(
+            Arg::with_name("key-encoding")
+                .long("key-encoding")
+                .takes_value(true)
+                .value_name("ENCODING")
+                .possible_values(Encoding::NAMES)
+                .default_value("utf8")
+                .global(true)
+                .help("The encoding of the keys passed and printed"),
+        )
+        .arg(
+            Arg::with_name("value-encoding")
+                .long("value-encoding")
+                .takes_value(true)
+                .value_name("ENCODING")
+                .possible_values(Encoding::NAMES)
+                .default_value("utf8")
+                .global(true)
+                .help("The encoding of the values passed and printed"),
+        )
+        .arg(
+            Arg::with_name("addr")
+                .long("addr")
+                .takes_value(true)
+                .value_name("IP:PORT")
+                .global(true)
+                .help(
+                    "Run set, get, rm, copy, ttl, expire and persist on the kvs-server at IP:PORT",
+                ),
+        )
+        .arg(
+            Arg::with_name("timeout")
+                .long("timeout")
+                .takes_value(true)
+                .value_name("SECONDS")
+                .validator(is_positive)
+                .global(true)
+                .help("Give up on get, or on each request to the server, after SECONDS"),
+        )
         .subcommand(
             SubCommand::with_name("set")
                 .about("Set the value of a string key to a string")
@@ -19,44 +78,490 @@ fn main() -> Result<()> {
                     Arg::with_name("VALUE")
                         .help("The string value of the key")
                         .required(true),
+                )
+                .arg(
+                    Arg::with_name("ttl")
+                        .long("ttl")
+                        .takes_value(true)
+                        .value_name("SECONDS")
+                        .validator(is_seconds)
+                        .help("Expire the key after SECONDS"),
                 ),
         )
         .subcommand(
             SubCommand::with_name("get")
                 .about("Get the string value of a given string key")
-                .arg(Arg::with_name("KEY").help("A string key").required(true)),
+                .arg(Arg::with_name("KEY").help("A string key").required(true))
+                .arg(
+                    Arg::with_name("strict")
+                        .long("strict")
+                        .help("Exit with status 1 if the key is not found, printing nothing"),
+                ),
         )
         .subcommand(
             SubCommand::with_name("rm")
-                .about("Remove a given key")
+                .about("Remove a given key, or the keys matching a pattern")
+                .arg(
+                    Arg::with_name("KEY")
+                        .help("A string key")
+                        .required_unless("pattern")
+                        .conflicts_with("pattern"),
+                )
+                .arg(
+                    Arg::with_name("pattern")
+                        .long("pattern")
+                        .takes_value(true)
+                        .value_name("PATTERN")
+                        .help("A glob matching the keys to remove"),
+                )
+                .arg(regex_arg()),
+        )
+        .subcommand(
+            SubCommand::with_name("incr")
+                .about("Add to the integer value of a key and print the new value")
+                .setting(AppSettings::AllowNegativeNumbers)
+                .arg(Arg::with_name("KEY").help("A string key").required(true))
+                .arg(
+                    Arg::with_name("DELTA")
+                        .help("The integer to add, which may be negative")
+                        .default_value("1")
+                        .validator(is_integer),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("ls")
+                .about("List the keys matching a pattern")
+                .arg(
+                    Arg::with_name("PATTERN")
+                        .help("A glob such as user:*:email")
+                        .default_value("*"),
+                )
+                .arg(regex_arg())
+                .arg(
+                    Arg::with_name("after")
+                        .long("after")
+                        .takes_value(true)
+                        .value_name("KEY")
+                        .help("List the keys after KEY, such as the last key of a page"),
+                )
+                .arg(
+                    Arg::with_name("limit")
+                        .long("limit")
+                        .takes_value(true)
+                        .value_name("N")
+                        .validator(is_count)
+                        .help("List at most N keys"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("scan")
+                .about("Print the keys and values under a key prefix")
+                .arg(
+                    Arg::with_name("PREFIX")
+                        .help("A key prefix such as user:123:")
+                        .default_value(""),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("copy")
+                .about("Copy the value of a given key to another key")
+                .arg(Arg::with_name("SRC").help("The key to copy").required(true))
+                .arg(
+                    Arg::with_name("DST")
+                        .help("The key to copy to")
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name("overwrite")
+                        .long("overwrite")
+                        .help("Overwrite DST if it exists"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("count")
+                .about("Count the keys starting with a prefix or matching a pattern")
+                .arg(
+                    Arg::with_name("prefix")
+                        .long("prefix")
+                        .takes_value(true)
+                        .value_name("PREFIX")
+                        .default_value("")
+                        .help("A key prefix"),
+                )
+                .arg(
+                    Arg::with_name("pattern")
+                        .long("pattern")
+                        .takes_value(true)
+                        .value_name("PATTERN")
+                        .help("A glob matching the keys to count"),
+                )
+                .arg(regex_arg()),
+        )
+        .subcommand(
+            SubCommand::with_name("du")
+                .about("Summarize the keys and log bytes by key prefix")
+                .arg(
+                    Arg::with_name("depth")
+                        .long("depth")
+                        .takes_value(true)
+                        .value_name("N")
+                        .default_value("1")
+                        .validator(is_count)
+                        .help("The number of prefix components to group by"),
+                )
+                .arg(
+                    Arg::with_name("sep")
+                        .long("sep")
+                        .takes_value(true)
+                        .value_name("SEP")
+                        .default_value(":")
+                        .help("The separator of prefix components"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("sample")
+                .about("Print keys chosen uniformly at random")
+                .arg(
+                    Arg::with_name("COUNT")
+                        .help("The number of keys")
+                        .default_value("1")
+                        .validator(is_count),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("ttl")
+                .about("Get the remaining time to live of a given key in seconds")
                 .arg(Arg::with_name("KEY").help("A string key").required(true)),
         )
+        .subcommand(
+            SubCommand::with_name("expire")
+                .about("Set the time to live of a given key")
+                .arg(Arg::with_name("KEY").help("A string key").required(true))
+                .arg(
+                    Arg::with_name("SECONDS")
+                        .help("The time to live in seconds")
+                        .required(true)
+                        .validator(is_seconds),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("persist")
+                .about("Remove the time to live of a given key")
+                .arg(Arg::with_name("KEY").help("A string key").required(true)),
+        )
+        .subcommand(
+            SubCommand::with_name("repair")
+                .about("Rebuild the log from its readable records and report lost data"),
+        )
+        .subcommand(
+            SubCommand::with_name("backup")
+                .about("Copy the store to an empty directory as a consistent backup")
+                .arg(
+                    Arg::with_name("DIR")
+                        .help("The directory to back up to")
+                        .required(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("restore")
+                .about("Recreate the store in the empty current directory from a backup")
+                .arg(
+                    Arg::with_name("DIR")
+                        .help("The directory of the backup")
+                        .required(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("export")
+                .about("Print the keys and values in a portable format")
+                .arg(format_arg()),
+        )
+        .subcommand(
+            SubCommand::with_name("import")
+                .about("Set the keys and values read from an export on stdin")
+                .arg(format_arg()),
+        )
+        .subcommand(
+            SubCommand::with_name("stats")
+                .about("Print the identity and size of the store")
+                .arg(
+                    Arg::with_name("json")
+                        .long("json")
+                        .help("Print a versioned JSON object for tooling"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("diff")
+                .about("Print the keys added, removed or changed from one store to another")
+                .arg(
+                    Arg::with_name("DIR_A")
+                        .help("A store directory")
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name("DIR_B")
+                        .help("A store directory")
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name("redact-prefix")
+                        .long("redact-prefix")
+                        .takes_value(true)
+                        .multiple(true)
+                        .number_of_values(1)
+                        .value_name("PREFIX")
+                        .help("Print the lengths of the values of keys with the prefix"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("replay")
+                .about("Replay a trace and print the latencies of its operations")
+                .arg(
+                    Arg::with_name("TRACE")
+                        .help("A trace file recorded with Options::record_trace")
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name("fast")
+                        .long("fast")
+                        .help("Ignore the recorded times of the operations"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("genload")
+                .about("Populate the store with synthetic keys and values")
+                .arg(
+                    Arg::with_name("keys")
+                        .long("keys")
+                        .takes_value(true)
+                        .value_name("N")
+                        .default_value("1000")
+                        .validator(is_positive)
+                        .help("The number of distinct keys"),
+                )
+                .arg(
+                    Arg::with_name("value-size")
+                        .long("value-size")
+                        .takes_value(true)
+                        .value_name("S")
+                        .default_value("100")
+                        .validator(is_count)
+                        .help("The size of the values in bytes"),
+                )
+                .arg(
+                    Arg::with_name("distribution")
+                        .long("distribution")
+                        .takes_value(true)
+                        .value_name("DISTRIBUTION")
+                        .possible_values(Distribution::NAMES)
+                        .default_value("uniform")
+                        .help("The distribution of the keys written"),
+                )
+                .arg(
+                    Arg::with_name("count")
+                        .long("count")
+                        .takes_value(true)
+                        .value_name("M")
+                        .validator(is_count)
+                        .help("The number of values to write [default: N]"),
+                )
+                .arg(
+                    Arg::with_name("seed")
+                        .long("seed")
+                        .takes_value(true)
+                        .value_name("SEED")
+                        .default_value("0")
+                        .validator(is_seed)
+                        .help("The seed of the generated data"),
+                ),
+        )
         .get_matches();
 
+    if let (name, Some(matches)) = matches.subcommand() {
+        let remote = matches!(
+            name,
+            "set" | "get" | "rm" | "copy" | "ttl" | "expire" | "persist"
+        ) && !matches.is_present("ttl")
+            && !matches.is_present("pattern");
+        if matches.is_present("addr") && !remote {
+            eprintln!("{} is only supported on a local store", name);
+            exit(1);
+        }
+    }
+
     match matches.subcommand() {
         ("set", Some(matches)) => {
             let key = matches.value_of("KEY").expect("KEY argument missing");
             let value = matches.value_of("VALUE").expect("VALUE argument missing");
+            let (keys, values) = encodings(matches);
+            let (key, value) = (keys.decode(key)?, values.decode(value)?);
 
-            let mut store = KvStore::open(current_dir()?)?;
-            store.set(key.to_string(), value.to_string())?;
+            match matches.value_of("ttl") {
+                Some(seconds) => {
+                    let seconds = seconds.parse().expect("ttl argument invalid");
+                    let mut store = KvStore::open(current_dir()?)?;
+                    store.set_with_ttl(key, value, Duration::from_secs(seconds))?;
+                }
+                None => open_engine(matches)?.set(key, value)?,
+            }
         }
         ("get", Some(matches)) => {
             let key = matches.value_of("KEY").expect("KEY argument missing");
+            let (keys, values) = encodings(matches);
 
-            let mut store = KvStore::open(current_dir()?)?;
-            if let Some(value) = store.get(key.to_string())? {
-                println!("{}", value);
-            } else {
-                println!("Key not found");
+            let key = keys.decode(key)?;
+            let value = match timeout(matches) {
+                Some(timeout) if !matches.is_present("addr") => {
+                    let mut store = KvStore::open(current_dir()?)?;
+                    store.get_with_deadline(key, Instant::now() + timeout)?
+                }
+                _ => open_engine(matches)?.get(key)?,
+            };
+            match value {
+                Some(value) => println!("{}", values.encode(&value)),
+                None => key_not_found(matches.is_present("strict")),
             }
         }
-        ("rm", Some(matches)) => {
+        ("rm", Some(matches)) => match matches.value_of("pattern") {
+            Some(pattern) => {
+                let mut store = KvStore::open(current_dir()?)?;
+                let pattern = key_pattern(pattern, matches.is_present("regex"))?;
+                println!("{}", store.remove_matching(&pattern)?);
+            }
+            None => {
+                let key = matches.value_of("KEY").expect("KEY argument missing");
+                let key = encodings(matches).0.decode(key)?;
+                let mut engine = open_engine(matches)?;
+                key_not_found_exit(engine.remove(key))?;
+            }
+        },
+        ("incr", Some(matches)) => {
             let key = matches.value_of("KEY").expect("KEY argument missing");
+            let delta = matches
+                .value_of("DELTA")
+                .expect("DELTA argument missing")
+                .parse()
+                .expect("DELTA argument invalid");
+            let key = encodings(matches).0.decode(key)?;
+
+            let mut store = KvStore::open(current_dir()?)?;
+            match store.incr(key, delta) {
+                Ok(value) => println!("{}", value),
+                Err(e @ KvsError::NotAnInteger(_)) | Err(e @ KvsError::Overflow(_)) => {
+                    eprintln!("{}", e);
+                    exit(1);
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        ("ls", Some(matches)) => {
+            let pattern = matches
+                .value_of("PATTERN")
+                .expect("PATTERN argument missing");
+            let pattern = key_pattern(pattern, matches.is_present("regex"))?;
+
+            let keys = encodings(matches).0;
+            let after = matches
+                .value_of("after")
+                .map(|after| keys.decode(after))
+                .transpose()?;
+            let limit = matches.value_of("limit").map_or(usize::MAX, |limit| {
+                limit.parse().expect("limit argument invalid")
+            });
+
+            let store = KvStore::open(current_dir()?)?;
+            for key in store.keys_page(&pattern, after.as_deref(), limit) {
+                println!("{}", keys.encode(&key));
+            }
+        }
+        ("scan", Some(matches)) => {
+            let prefix = matches.value_of("PREFIX").expect("PREFIX argument missing");
+            let (keys, values) = encodings(matches);
 
             let mut store = KvStore::open(current_dir()?)?;
-            match store.remove(key.to_string()) {
-                Ok(()) => {}
+            for entry in store.scan_prefix(&keys.decode(prefix)?) {
+                let (key, value) = entry?;
+                println!("{}\t{}", keys.encode(&key), values.encode(&value));
+            }
+        }
+        ("copy", Some(matches)) => {
+            let src = matches.value_of("SRC").expect("SRC argument missing");
+            let dst = matches.value_of("DST").expect("DST argument missing");
+            let keys = encodings(matches).0;
+
+            let mut engine = open_engine(matches)?;
+            match engine.copy(
+                keys.decode(src)?,
+                keys.decode(dst)?,
+                matches.is_present("overwrite"),
+            ) {
+                Ok(true) => {}
+                Ok(false) => {
+                    println!("Key exists");
+                    exit(1);
+                }
+                Err(KvsError::KeyNotFound) => {
+                    println!("Key not found");
+                    exit(1);
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        ("count", Some(matches)) => {
+            let prefix = matches.value_of("prefix").expect("prefix argument missing");
+
+            let store = KvStore::open(current_dir()?)?;
+            match matches.value_of("pattern") {
+                Some(pattern) => {
+                    let pattern = key_pattern(pattern, matches.is_present("regex"))?;
+                    println!("{}", store.count_matching(&pattern));
+                }
+                None => println!("{}", store.count_prefix(prefix)),
+            }
+        }
+        ("du", Some(matches)) => {
+            let depth = matches
+                .value_of("depth")
+                .expect("depth argument missing")
+                .parse()
+                .expect("depth argument invalid");
+            let sep = matches.value_of("sep").expect("sep argument missing");
+
+            let store = KvStore::open(current_dir()?)?;
+            let usage = store.usage(sep, depth);
+            for prefix in &usage {
+                println!("{}\t{}\t{}", prefix.bytes, prefix.keys, prefix.prefix);
+            }
+            let total = store.usage(sep, 1);
+            println!(
+                "{}\t{}\ttotal",
+                total.iter().map(|prefix| prefix.bytes).sum::<u64>(),
+                total.iter().map(|prefix| prefix.keys).sum::<usize>()
+            );
+        }
+        ("sample", Some(matches)) => {
+            let count = matches
+                .value_of("COUNT")
+                .expect("COUNT argument missing")
+                .parse()
+                .expect("COUNT argument invalid");
+
+            let keys = encodings(matches).0;
+
+            let store = KvStore::open(current_dir()?)?;
+            for key in store.sample(count) {
+                println!("{}", keys.encode(&key));
+            }
+        }
+        ("ttl", Some(matches)) => {
+            let key = matches.value_of("KEY").expect("KEY argument missing");
+
+            let key = encodings(matches).0.decode(key)?;
+
+            let mut engine = open_engine(matches)?;
+            match engine.ttl(key) {
+                Ok(Some(ttl)) => println!("{}", ttl.as_secs()),
+                Ok(None) => println!("No expiry"),
                 Err(KvsError::KeyNotFound) => {
                     println!("Key not found");
                     exit(1);
@@ -64,7 +569,343 @@ fn main() -> Result<()> {
                 Err(e) => return Err(e),
             }
         }
+        ("expire", Some(matches)) => {
+            let key = matches.value_of("KEY").expect("KEY argument missing");
+            let seconds = matches
+                .value_of("SECONDS")
+                .expect("SECONDS argument missing")
+                .parse()
+                .expect("SECONDS argument invalid");
+            let key = encodings(matches).0.decode(key)?;
+
+            let mut engine = open_engine(matches)?;
+            key_not_found_exit(engine.expire(key, Duration::from_secs(seconds)))?;
+        }
+        ("persist", Some(matches)) => {
+            let key = matches.value_of("KEY").expect("KEY argument missing");
+
+            let key = encodings(matches).0.decode(key)?;
+
+            let mut engine = open_engine(matches)?;
+            key_not_found_exit(engine.persist(key))?;
+        }
+        ("repair", Some(_)) => {
+            let report = KvStore::repair(current_dir()?)?;
+            for lost in &report.lost {
+                println!(
+                    "Lost {} bytes at offset {} of log file {}",
+                    lost.len, lost.offset, lost.gen
+                );
+            }
+            println!(
+                "Recovered {} keys, lost {} bytes",
+                report.keys,
+                report.lost.iter().map(|lost| lost.len).sum::<u64>()
+            );
+        }
+        ("backup", Some(matches)) => {
+            let dir = matches.value_of("DIR").expect("DIR argument missing");
+            KvStore::open(current_dir()?)?.backup(dir)?;
+        }
+        ("restore", Some(matches)) => {
+            let dir = matches.value_of("DIR").expect("DIR argument missing");
+            KvStore::restore(dir, current_dir()?)?;
+        }
+        ("export", Some(matches)) => {
+            let format = matches
+                .value_of("format")
+                .expect("format argument missing")
+                .parse()?;
+
+            let mut store = KvStore::open(current_dir()?)?;
+            export::export(&mut store, format, io::stdout().lock())?;
+        }
+        ("import", Some(matches)) => {
+            let format = matches
+                .value_of("format")
+                .expect("format argument missing")
+                .parse()?;
+
+            let mut store = KvStore::open(current_dir()?)?;
+            println!(
+                "{}",
+                export::import(&mut store, format, io::stdin().lock())?
+            );
+        }
+        ("stats", Some(matches)) => {
+            let store = KvStore::open(current_dir()?)?;
+            let meta = store.meta();
+            if matches.is_present("json") {
+                let report = StatsReport {
+                    meta,
+                    keys: store.count_prefix(""),
+                    stats: store.stats(),
+                };
+                println!("{}", serde_json::to_string(&Versioned::new(report))?);
+                return Ok(());
+            }
+            let created = meta
+                .created
+                .and_then(|created| created.duration_since(UNIX_EPOCH).ok())
+                .map_or("unknown".to_owned(), |created| {
+                    created.as_secs().to_string()
+                });
+            println!("id\t{}", meta.id);
+            println!("created\t{}", created);
+            println!("version\t{}", meta.version.as_deref().unwrap_or("unknown"));
+            println!("comparator\t{}", meta.comparator);
+            println!(
+                "normalization\t{}",
+                meta.normalization.as_deref().unwrap_or("none")
+            );
+            println!("keys\t{}", store.count_prefix(""));
+            println!("compaction_debt\t{}", store.stats().compaction_debt);
+        }
+        ("diff", Some(matches)) => {
+            let dir_a = matches.value_of("DIR_A").expect("DIR_A argument missing");
+            let dir_b = matches.value_of("DIR_B").expect("DIR_B argument missing");
+
+            let redactor = Prefixes(
+                matches
+                    .values_of("redact-prefix")
+                    .map_or_else(Vec::new, |prefixes| prefixes.map(str::to_owned).collect()),
+            );
+            let (keys, values) = encodings(matches);
+            // redacted values are printed as they are, without encoding.
+            let show = |key: &str, value: &str| {
+                let redacted = redactor.redact(key, value);
+                if redacted == value {
+                    values.encode(value)
+                } else {
+                    redacted
+                }
+            };
+            let mut store_a = KvStore::open(dir_a)?;
+            let mut store_b = KvStore::open(dir_b)?;
+            let mut differ = false;
+            for change in store_a.diff(&mut store_b)? {
+                differ = true;
+                match change? {
+                    Change::Added { key, value } => {
+                        println!("+{}\t{}", keys.encode(&key), show(&key, &value))
+                    }
+                    Change::Removed { key, value } => {
+                        println!("-{}\t{}", keys.encode(&key), show(&key, &value))
+                    }
+                    Change::Changed { key, old, new } => println!(
+                        "~{}\t{}\t{}",
+                        keys.encode(&key),
+                        show(&key, &old),
+                        show(&key, &new)
+                    ),
+                }
+            }
+            drop((store_a, store_b));
+            if differ {
+                exit(1);
+            }
+        }
+        ("replay", Some(matches)) => {
+            let trace = matches.value_of("TRACE").expect("TRACE argument missing");
+
+            let events = trace::read(trace)?;
+            let mut store = KvStore::open(current_dir()?)?;
+            let stats = trace::replay(&mut store, &events, !matches.is_present("fast"))?;
+            println!("op\tcount\tp50\tp90\tp99\tmax");
+            for (name, histogram) in &[
+                ("get", &stats.get),
+                ("set", &stats.set),
+                ("remove", &stats.remove),
+                ("compaction", &stats.compaction),
+            ] {
+                println!(
+                    "{}\t{}\t{:?}\t{:?}\t{:?}\t{:?}",
+                    name,
+                    histogram.count(),
+                    histogram.percentile(50.0),
+                    histogram.percentile(90.0),
+                    histogram.percentile(99.0),
+                    histogram.max()
+                );
+            }
+        }
+        ("genload", Some(matches)) => {
+            let keys = matches
+                .value_of("keys")
+                .expect("keys argument missing")
+                .parse()
+                .expect("keys argument invalid");
+            let value_size = matches
+                .value_of("value-size")
+                .expect("value-size argument missing")
+                .parse()
+                .expect("value-size argument invalid");
+            let distribution = matches
+                .value_of("distribution")
+                .expect("distribution argument missing")
+                .parse()?;
+            let count = match matches.value_of("count") {
+                Some(count) => count.parse().expect("count argument invalid"),
+                None => keys as usize,
+            };
+            let seed = matches
+                .value_of("seed")
+                .expect("seed argument missing")
+                .parse()
+                .expect("seed argument invalid");
+
+            let mut store = KvStore::open(current_dir()?)?;
+            for (key, value) in Generator::new(keys, value_size, distribution, seed).take(count) {
+                store.set(key, value)?;
+            }
+        }
         _ => unreachable!(),
     }
     Ok(())
 }
+
+/// Reports a missing key of `get`. In strict mode, the key is missing if and only if the
+/// exit status is 1, even for empty values, which print an empty line.
+fn key_not_found(strict: bool) {
+    if strict {
+        eprintln!("Key not found");
+        exit(1);
+    }
+    println!("Key not found");
+}
+
+/// Connects to the server at `--addr`, or opens the store in the current directory with
+/// the engine selected by `--engine`.
+fn open_engine(matches: &ArgMatches) -> Result<Box<dyn KvsEngine>> {
+    if let Some(addr) = matches.value_of("addr") {
+        return connect(addr, timeout(matches));
+    }
+    match matches.value_of("engine") {
+        Some("kvs") | None => Ok(Box::new(KvStore::open(current_dir()?)?)),
+        Some(engine) => unreachable!("unknown engine {}", engine),
+    }
+}
+
+#[cfg(feature = "server")]
+fn connect(addr: &str, timeout: Option<Duration>) -> Result<Box<dyn KvsEngine>> {
+    let client = match timeout {
+        Some(timeout) => KvsClient::connect_timeout(addr, timeout)?,
+        None => KvsClient::connect(addr)?,
+    };
+    Ok(Box::new(client))
+}
+
+#[cfg(not(feature = "server"))]
+fn connect(_: &str, _: Option<Duration>) -> Result<Box<dyn KvsEngine>> {
+    eprintln!("kvs was built without the server feature");
+    exit(1);
+}
+
+/// Returns the `--timeout` on the command line.
+fn timeout(matches: &ArgMatches) -> Option<Duration> {
+    matches
+        .value_of("timeout")
+        .map(|seconds| Duration::from_secs(seconds.parse().expect("timeout argument invalid")))
+}
+
+/// Returns the encodings of the keys and values on the command line.
+fn encodings(matches: &ArgMatches) -> (Encoding, Encoding) {
+    let encoding = |arg| {
+        matches
+            .value_of(arg)
+            .unwrap_or("utf8")
+            .parse()
+            .expect("encoding argument invalid")
+    };
+    (encoding("key-encoding"), encoding("value-encoding"))
+}
+
+/// The output of `kvs stats --json`.
+#[derive(Serialize)]
+struct StatsReport {
+    meta: StoreMeta,
+    keys: usize,
+    stats: Stats,
+}
+
+/// Prints "Key not found" and exits with a non-zero code if the key was not found.
+fn key_not_found_exit(result: Result<()>) -> Result<()> {
+    match result {
+        Err(KvsError::KeyNotFound) => {
+            println!("Key not found");
+            exit(1);
+        }
+        result => result,
+    }
+}
+
+fn format_arg<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name("format")
+        .long("format")
+        .takes_value(true)
+        .value_name("FORMAT")
+        .possible_values(Format::NAMES)
+        .default_value("json")
+        .help("The format of the keys and values")
+}
+
+fn regex_arg<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name("regex")
+        .long("regex")
+        .help("Match keys with a regular expression instead of a glob")
+}
+
+/// Parses a glob, or a regular expression if `regex` is set.
+fn key_pattern(pattern: &str, regex: bool) -> Result<KeyPattern> {
+    if regex {
+        regex_pattern(pattern)
+    } else {
+        Ok(KeyPattern::glob(pattern))
+    }
+}
+
+#[cfg(feature = "regex")]
+fn regex_pattern(pattern: &str) -> Result<KeyPattern> {
+    KeyPattern::regex(pattern)
+}
+
+#[cfg(not(feature = "regex"))]
+fn regex_pattern(_: &str) -> Result<KeyPattern> {
+    eprintln!("kvs was built without the regex feature");
+    exit(1);
+}
+
+fn is_count(value: String) -> std::result::Result<(), String> {
+    value
+        .parse::<usize>()
+        .map(|_| ())
+        .map_err(|_| format!("{} is not a count", value))
+}
+
+fn is_integer(value: String) -> std::result::Result<(), String> {
+    value
+        .parse::<i64>()
+        .map(|_| ())
+        .map_err(|_| format!("{} is not an integer", value))
+}
+
+fn is_positive(value: String) -> std::result::Result<(), String> {
+    match value.parse::<u64>() {
+        Ok(n) if n > 0 => Ok(()),
+        _ => Err(format!("{} is not a positive count", value)),
+    }
+}
+
+fn is_seed(value: String) -> std::result::Result<(), String> {
+    value
+        .parse::<u64>()
+        .map(|_| ())
+        .map_err(|_| format!("{} is not a seed", value))
+}
+
+fn is_seconds(value: String) -> std::result::Result<(), String> {
+    value
+        .parse::<u64>()
+        .map(|_| ())
+        .map_err(|_| format!("{} is not a number of seconds", value))
+}
@@ -0,0 +1,194 @@
+use std::io::{self, BufReader, BufWriter};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::time::Duration;
+
+use crate::protocol::{self, ErrorCode, Limits, Request, Response};
+use crate::{KvsError, Result};
+
+/// A connection to a `kvs-server`.
+///
+/// Requests failed by the server return the variant of `KvsError` for the
+/// [`ErrorCode`](crate::protocol::ErrorCode) of the failure: `KeyNotFound`, `Busy`,
+/// `ReadOnly` or `Unauthorized`, and `Server` with the message of the server for invalid
+/// requests and internal errors. `ErrorCode::from(&e).retryable()` tells whether a
+/// request is worth sending again.
+///
+/// ```rust,no_run
+/// # use kvs::{KvsClient, Result};
+/// # fn try_main() -> Result<()> {
+/// let mut client = KvsClient::connect("127.0.0.1:4000")?;
+/// client.set("key1".to_owned(), "value1".to_owned())?;
+/// assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+/// # Ok(())
+/// # }
+/// ```
+pub struct KvsClient {
+    reader: BufReader<TcpStream>,
+    writer: BufWriter<TcpStream>,
+    limits: Limits,
+}
+
+impl KvsClient {
+    /// Connects to a server at the given address.
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O errors connecting to the server.
+    pub fn connect(addr: impl ToSocketAddrs) -> Result<KvsClient> {
+        KvsClient::from_stream(TcpStream::connect(addr)?)
+    }
+
+    /// Connects to a server at the given address, giving up on connecting and on every
+    /// read or write of a request after `timeout`.
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O errors connecting to the server, such as `TimedOut`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `timeout` is zero.
+    pub fn connect_timeout(addr: impl ToSocketAddrs, timeout: Duration) -> Result<KvsClient> {
+        assert!(
+            timeout > Duration::from_secs(0),
+            "the timeout must not be zero"
+        );
+        let mut last_err = None;
+        for addr in addr.to_socket_addrs()? {
+            match TcpStream::connect_timeout(&addr, timeout) {
+                Ok(stream) => {
+                    stream.set_read_timeout(Some(timeout))?;
+                    stream.set_write_timeout(Some(timeout))?;
+                    return KvsClient::from_stream(stream);
+                }
+                Err(e) => last_err = Some(e),
+            }
+        }
+        let e = last_err.unwrap_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidInput, "no address to connect to")
+        });
+        Err(e.into())
+    }
+
+    fn from_stream(stream: TcpStream) -> Result<KvsClient> {
+        Ok(KvsClient {
+            reader: BufReader::new(stream.try_clone()?),
+            writer: BufWriter::new(stream),
+            limits: Limits::default(),
+        })
+    }
+
+    /// Sets the limits of the frames read from the server.
+    pub fn limits(mut self, limits: Limits) -> KvsClient {
+        self.limits = limits;
+        self
+    }
+
+    /// Gets the string value of a given string key.
+    ///
+    /// Returns `None` if the given key does not exist.
+    ///
+    /// # Errors
+    ///
+    /// It returns the error of the server as described in [`KvsClient`].
+    pub fn get(&mut self, key: String) -> Result<Option<String>> {
+        self.request(&Request::Get { key })
+    }
+
+    /// Sets the value of a string key to a string.
+    ///
+    /// # Errors
+    ///
+    /// It returns the error of the server as described in [`KvsClient`].
+    pub fn set(&mut self, key: String, value: String) -> Result<()> {
+        self.request(&Request::Set { key, value }).map(|_| ())
+    }
+
+    /// Removes a given key.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::KeyNotFound` if the given key is not found, and the error
+    /// of the server as described in [`KvsClient`] otherwise.
+    pub fn remove(&mut self, key: String) -> Result<()> {
+        self.request(&Request::Remove { key }).map(|_| ())
+    }
+
+    /// Returns the remaining time to live of a given key.
+    ///
+    /// Returns `None` if the key does not expire.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::KeyNotFound` if the given key is not found, and the error
+    /// of the server as described in [`KvsClient`] otherwise.
+    pub fn ttl(&mut self, key: String) -> Result<Option<Duration>> {
+        match self.request(&Request::Ttl { key })? {
+            Some(millis) => match millis.parse() {
+                Ok(millis) => Ok(Some(Duration::from_millis(millis))),
+                Err(_) => Err(KvsError::Server(format!("invalid ttl {}", millis))),
+            },
+            None => Ok(None),
+        }
+    }
+
+    /// Sets the TTL of an existing key, replacing any previous TTL.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::KeyNotFound` if the given key is not found, and the error
+    /// of the server as described in [`KvsClient`] otherwise.
+    pub fn expire(&mut self, key: String, ttl: Duration) -> Result<()> {
+        let millis = ttl.as_millis() as u64;
+        self.request(&Request::Expire { key, millis }).map(|_| ())
+    }
+
+    /// Removes the TTL of a given key so that it never expires.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::KeyNotFound` if the given key is not found, and the error
+    /// of the server as described in [`KvsClient`] otherwise.
+    pub fn persist(&mut self, key: String) -> Result<()> {
+        self.request(&Request::Persist { key }).map(|_| ())
+    }
+
+    /// Copies the value of a given key to `dst` on the server, without reading it.
+    ///
+    /// If `dst` exists, it is overwritten only if `overwrite` is `true`. Returns
+    /// whether the value was copied.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::KeyNotFound` if the given key is not found, and the error
+    /// of the server as described in [`KvsClient`] otherwise.
+    pub fn copy(&mut self, key: String, dst: String, overwrite: bool) -> Result<bool> {
+        let request = Request::Copy {
+            key,
+            dst,
+            overwrite,
+        };
+        match self.request(&request)?.as_deref() {
+            Some("true") => Ok(true),
+            Some("false") => Ok(false),
+            other => Err(KvsError::Server(format!(
+                "invalid copy response {:?}",
+                other
+            ))),
+        }
+    }
+
+    fn request(&mut self, request: &Request) -> Result<Option<String>> {
+        protocol::write_request(&mut self.writer, request, &self.limits)?;
+        match protocol::read_response(&mut self.reader, &self.limits)? {
+            Response::Ok(value) => Ok(value),
+            Response::Err(e) => Err(match e.code {
+                ErrorCode::NotFound => KvsError::KeyNotFound,
+                ErrorCode::Busy => KvsError::Busy,
+                ErrorCode::ReadOnly => KvsError::ReadOnly,
+                ErrorCode::Unauthorized => KvsError::Unauthorized,
+                ErrorCode::InvalidRequest | ErrorCode::Internal => KvsError::Server(e.message),
+            }),
+        }
+    }
+}
@@ -0,0 +1,76 @@
+use std::cmp::Ordering;
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+
+/// A vector clock tracking causality between updates made on different replicas.
+///
+/// Each replica is identified by a string node id and owns one counter. A clock
+/// happened before another one if none of its counters is greater and at least one
+/// is smaller. Two clocks that are not ordered either way are concurrent, which
+/// means the updates they describe conflict and must be resolved by the application.
+///
+/// ```rust
+/// # use kvs::VectorClock;
+/// let mut a = VectorClock::new();
+/// a.increment("node1");
+/// let mut b = a.clone();
+/// b.increment("node2");
+/// assert!(a < b);
+///
+/// a.increment("node1");
+/// assert!(a.concurrent_with(&b));
+/// ```
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq, Eq)]
+pub struct VectorClock(BTreeMap<String, u64>);
+
+impl VectorClock {
+    /// Creates an empty clock.
+    pub fn new() -> VectorClock {
+        VectorClock::default()
+    }
+
+    /// Returns the counter of the given node, which is 0 if the node never updated.
+    pub fn get(&self, node: &str) -> u64 {
+        self.0.get(node).cloned().unwrap_or(0)
+    }
+
+    /// Increments the counter of the given node.
+    pub fn increment(&mut self, node: &str) {
+        *self.0.entry(node.to_owned()).or_insert(0) += 1;
+    }
+
+    /// Merges another clock into this one by taking the maximum of every counter.
+    pub fn merge(&mut self, other: &VectorClock) {
+        for (node, &counter) in &other.0 {
+            let entry = self.0.entry(node.clone()).or_insert(0);
+            *entry = (*entry).max(counter);
+        }
+    }
+
+    /// Returns `true` if neither clock happened before the other.
+    pub fn concurrent_with(&self, other: &VectorClock) -> bool {
+        self.partial_cmp(other).is_none()
+    }
+
+    /// Returns `true` if no node has ever updated this clock.
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+impl PartialOrd for VectorClock {
+    fn partial_cmp(&self, other: &VectorClock) -> Option<Ordering> {
+        let mut ordering = Ordering::Equal;
+        for node in self.0.keys().chain(other.0.keys()) {
+            let cmp = self.get(node).cmp(&other.get(node));
+            match (ordering, cmp) {
+                (_, Ordering::Equal) => {}
+                (Ordering::Equal, _) => ordering = cmp,
+                (prev, cmp) if prev != cmp => return None,
+                _ => {}
+            }
+        }
+        Some(ordering)
+    }
+}
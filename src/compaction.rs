@@ -0,0 +1,288 @@
+//! Policies deciding when a `KvStore` compacts its log.
+
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Log statistics a `CompactionPolicy` bases its decision on.
+#[derive(Clone, Debug)]
+pub struct CompactionStats {
+    /// Bytes of stale commands that would be reclaimed by a compaction.
+    pub uncompacted: u64,
+    /// Total bytes of all log files.
+    pub total: u64,
+    /// When the store was last compacted, or opened if it has not been compacted since.
+    pub last_compaction: Instant,
+    /// Recent reads and writes per second, averaged over about a second.
+    pub ops_per_sec: f64,
+}
+
+/// Decides when a `KvStore` compacts its log.
+///
+/// The policy is consulted after every write. Besides the provided policies, any
+/// `Fn(&CompactionStats) -> bool` closure can be used as a policy.
+pub trait CompactionPolicy: Send + Sync {
+    /// Returns `true` if the store should be compacted now.
+    fn should_compact(&self, stats: &CompactionStats) -> bool;
+}
+
+impl<F> CompactionPolicy for F
+where
+    F: Fn(&CompactionStats) -> bool + Send + Sync,
+{
+    fn should_compact(&self, stats: &CompactionStats) -> bool {
+        self(stats)
+    }
+}
+
+/// Compacts once the stale bytes exceed a threshold.
+///
+/// This is the default policy with a threshold of 1 MiB.
+#[derive(Clone, Copy, Debug)]
+pub struct SizeThreshold(pub u64);
+
+impl CompactionPolicy for SizeThreshold {
+    fn should_compact(&self, stats: &CompactionStats) -> bool {
+        stats.uncompacted > self.0
+    }
+}
+
+/// Compacts once the stale bytes exceed a ratio of the total log size.
+///
+/// Logs smaller than `min_bytes` are never compacted so that tiny stores don't
+/// compact on every overwrite.
+#[derive(Clone, Copy, Debug)]
+pub struct StaleRatio {
+    /// The ratio of stale bytes to total bytes, between 0 and 1.
+    pub ratio: f64,
+    /// The minimum total log size before compacting.
+    pub min_bytes: u64,
+}
+
+impl CompactionPolicy for StaleRatio {
+    fn should_compact(&self, stats: &CompactionStats) -> bool {
+        stats.total >= self.min_bytes && stats.uncompacted as f64 > stats.total as f64 * self.ratio
+    }
+}
+
+/// Compacts stale data at most once per time window.
+#[derive(Clone, Copy, Debug)]
+pub struct TimeWindow(pub Duration);
+
+impl CompactionPolicy for TimeWindow {
+    fn should_compact(&self, stats: &CompactionStats) -> bool {
+        stats.uncompacted > 0 && stats.last_compaction.elapsed() >= self.0
+    }
+}
+
+/// Postpones the compactions of another policy while the store is busy.
+///
+/// Once the inner policy calls for a compaction, it is held off as long as the store
+/// serves more than `busy_ops_per_sec` reads and writes per second, but no longer than
+/// `max_delay`. Call `KvStore::poll_compaction` periodically so that a postponed
+/// compaction can run as soon as the store becomes idle.
+///
+/// ```rust
+/// # use kvs::compaction::{Adaptive, SizeThreshold};
+/// use std::time::Duration;
+/// let policy = Adaptive::new(SizeThreshold(1024 * 1024), 1000.0, Duration::from_secs(60));
+/// ```
+#[derive(Debug)]
+pub struct Adaptive<P> {
+    inner: P,
+    busy_ops_per_sec: f64,
+    max_delay: Duration,
+    // when the inner policy first called for the pending compaction.
+    due_since: Mutex<Option<Instant>>,
+}
+
+impl<P: CompactionPolicy> Adaptive<P> {
+    /// Creates a policy postponing the compactions of `inner`.
+    pub fn new(inner: P, busy_ops_per_sec: f64, max_delay: Duration) -> Adaptive<P> {
+        Adaptive {
+            inner,
+            busy_ops_per_sec,
+            max_delay,
+            due_since: Mutex::new(None),
+        }
+    }
+}
+
+impl<P: CompactionPolicy> CompactionPolicy for Adaptive<P> {
+    fn should_compact(&self, stats: &CompactionStats) -> bool {
+        let mut due_since = self.due_since.lock().unwrap();
+        if !self.inner.should_compact(stats) {
+            *due_since = None;
+            return false;
+        }
+        let since = *due_since.get_or_insert_with(Instant::now);
+        if stats.ops_per_sec < self.busy_ops_per_sec || since.elapsed() >= self.max_delay {
+            *due_since = None;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+/// Never compacts automatically. `KvStore::compact` can still be called explicitly.
+#[derive(Clone, Copy, Debug)]
+pub struct Never;
+
+impl CompactionPolicy for Never {
+    fn should_compact(&self, _stats: &CompactionStats) -> bool {
+        false
+    }
+}
+
+/// How much of the log a triggered compaction rewrites.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CompactionMode {
+    /// Rewrites all live entries into a new log file in one pass.
+    Full,
+    /// Compacts the oldest log file with stale commands.
+    OldestSegment,
+    /// Compacts the log file with the most stale commands.
+    MostStaleSegment,
+    /// Merges log files of similar size, within a factor of two, once `min_files` of
+    /// them have accumulated.
+    ///
+    /// Every entry is rewritten about once per size tier, which keeps the write cost
+    /// low for insert-heavy workloads.
+    SizeTiered {
+        /// The number of similar log files to merge, at least 2.
+        min_files: usize,
+    },
+    /// Organizes log files into levels by size. Level `n` targets at most
+    /// `base * multiplier^n` bytes; once the log files of a level exceed the target,
+    /// they are merged with the log files of the next level.
+    ///
+    /// Stale data is merged away sooner than with `SizeTiered`, which keeps the log
+    /// small for overwrite-heavy workloads at the cost of rewriting entries more often.
+    Leveled {
+        /// The target size of level 0 in bytes.
+        base: u64,
+        /// The growth of the target size per level, at least 2.
+        multiplier: u64,
+    },
+}
+
+/// Measures the rate of foreground requests as a moving average.
+pub(crate) struct LoadMeter {
+    // requests per second at `last`.
+    rate: f64,
+    last: Instant,
+}
+
+impl LoadMeter {
+    // the time over which requests are averaged.
+    const WINDOW: f64 = 1.0;
+
+    pub(crate) fn new() -> LoadMeter {
+        LoadMeter {
+            rate: 0.0,
+            last: Instant::now(),
+        }
+    }
+
+    /// Accounts for a request.
+    pub(crate) fn record(&mut self) {
+        self.rate = self.rate_at(Instant::now()) + 1.0 / Self::WINDOW;
+        self.last = Instant::now();
+    }
+
+    /// Returns the requests per second, decayed to now.
+    pub(crate) fn rate(&self) -> f64 {
+        self.rate_at(Instant::now())
+    }
+
+    fn rate_at(&self, now: Instant) -> f64 {
+        let elapsed = now.duration_since(self.last).as_secs_f64();
+        self.rate * (-elapsed / Self::WINDOW).exp()
+    }
+}
+
+/// Paces compaction I/O to a maximum number of bytes per second.
+pub(crate) struct RateLimiter {
+    bytes_per_sec: Option<u64>,
+    start: Instant,
+    consumed: u64,
+}
+
+impl RateLimiter {
+    /// Creates a limiter. `None` means unlimited.
+    pub(crate) fn new(bytes_per_sec: Option<u64>) -> RateLimiter {
+        RateLimiter {
+            bytes_per_sec,
+            start: Instant::now(),
+            consumed: 0,
+        }
+    }
+
+    /// Accounts for `bytes` of I/O, sleeping if the rate has been exceeded.
+    pub(crate) fn consume(&mut self, bytes: u64) {
+        if let Some(bytes_per_sec) = self.bytes_per_sec {
+            self.consumed += bytes;
+            let expected = Duration::from_secs_f64(self.consumed as f64 / bytes_per_sec as f64);
+            let elapsed = self.start.elapsed();
+            if expected > elapsed {
+                thread::sleep(expected - elapsed);
+            }
+        }
+    }
+}
+
+/// Drops entries older than a maximum age during compaction, whether they are live
+/// or not.
+///
+/// This suits time-series-like data where old entries lose their value. The maximum
+/// age can be overridden for keys starting with a prefix; the longest matching
+/// prefix wins. Expired entries stay readable until they are compacted.
+///
+/// ```rust
+/// # use kvs::compaction::Retention;
+/// use std::time::Duration;
+/// let retention = Retention::new(Duration::from_secs(7 * 24 * 3600))
+///     .prefix("metrics:", Duration::from_secs(3600));
+/// ```
+#[derive(Clone, Debug)]
+pub struct Retention {
+    max_age: Duration,
+    prefixes: Vec<(String, Duration)>,
+}
+
+impl Retention {
+    /// Creates a retention policy dropping entries older than `max_age`.
+    pub fn new(max_age: Duration) -> Retention {
+        Retention {
+            max_age,
+            prefixes: Vec::new(),
+        }
+    }
+
+    /// Overrides the maximum age for keys starting with `prefix`.
+    pub fn prefix(mut self, prefix: impl Into<String>, max_age: Duration) -> Retention {
+        self.prefixes.push((prefix.into(), max_age));
+        self
+    }
+
+    /// Returns the maximum age of the given key.
+    pub fn max_age(&self, key: &str) -> Duration {
+        self.prefixes
+            .iter()
+            .filter(|(prefix, _)| key.starts_with(prefix.as_str()))
+            .max_by_key(|(prefix, _)| prefix.len())
+            .map_or(self.max_age, |&(_, max_age)| max_age)
+    }
+
+    /// Returns `true` if an entry written at `ts` milliseconds since the Unix epoch
+    /// is older than its maximum age at `now`.
+    ///
+    /// Entries without a timestamp never expire.
+    pub(crate) fn expired(&self, key: &str, ts: Option<u64>, now: u64) -> bool {
+        match ts {
+            Some(ts) => now.saturating_sub(ts) > self.max_age(key).as_millis() as u64,
+            None => false,
+        }
+    }
+}
@@ -0,0 +1,110 @@
+//! Comparators defining the order and equality of the keys of a `KvStore`.
+//!
+//! The comparator is chosen with [`Options::comparator`](crate::Options::comparator)
+//! when a store is created. Its name is recorded in the store, and opening the store
+//! with a comparator of another name fails, because the keys in the log would be
+//! indexed differently.
+
+use std::cmp::Ordering;
+use std::sync::Arc;
+
+/// Defines the order and equality of keys.
+///
+/// Keys that compare equal are the same key: a write to one replaces the value of the
+/// other, and the key keeps the spelling it was first written with.
+pub trait Comparator: Send + Sync {
+    /// Returns the name recorded in the store, which must change whenever the order
+    /// changes.
+    fn name(&self) -> &str;
+
+    /// Compares two keys.
+    fn compare(&self, a: &str, b: &str) -> Ordering;
+}
+
+/// Orders keys by their UTF-8 bytes.
+///
+/// This is the default comparator.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Bytewise;
+
+impl Comparator for Bytewise {
+    fn name(&self) -> &str {
+        "bytewise"
+    }
+
+    fn compare(&self, a: &str, b: &str) -> Ordering {
+        a.cmp(b)
+    }
+}
+
+/// Orders keys ignoring case, so that keys differing only in case are the same key.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct CaseInsensitive;
+
+impl Comparator for CaseInsensitive {
+    fn name(&self) -> &str {
+        "case-insensitive"
+    }
+
+    fn compare(&self, a: &str, b: &str) -> Ordering {
+        a.chars()
+            .flat_map(char::to_lowercase)
+            .cmp(b.chars().flat_map(char::to_lowercase))
+    }
+}
+
+/// Orders runs of ASCII digits by their numeric value, so that `item2` comes before
+/// `item10`.
+///
+/// Keys whose numbers only differ in leading zeros are ordered by their bytes, so they
+/// are still different keys.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Numeric;
+
+impl Comparator for Numeric {
+    fn name(&self) -> &str {
+        "numeric"
+    }
+
+    fn compare(&self, a: &str, b: &str) -> Ordering {
+        let (mut x, mut y) = (a.as_bytes(), b.as_bytes());
+        while let (Some(&c), Some(&d)) = (x.first(), y.first()) {
+            let ordering = if c.is_ascii_digit() && d.is_ascii_digit() {
+                let (m, rest_x) = split_number(x);
+                let (n, rest_y) = split_number(y);
+                x = rest_x;
+                y = rest_y;
+                // without leading zeros, a longer number is larger.
+                m.len().cmp(&n.len()).then_with(|| m.cmp(n))
+            } else {
+                x = &x[1..];
+                y = &y[1..];
+                c.cmp(&d)
+            };
+            if ordering != Ordering::Equal {
+                return ordering;
+            }
+        }
+        x.len().cmp(&y.len()).then_with(|| a.cmp(b))
+    }
+}
+
+/// Splits the leading digits off, returning them without leading zeros.
+fn split_number(s: &[u8]) -> (&[u8], &[u8]) {
+    let end = s
+        .iter()
+        .position(|c| !c.is_ascii_digit())
+        .unwrap_or(s.len());
+    let zeros = s[..end].iter().take_while(|&&c| c == b'0').count();
+    (&s[zeros..end], &s[end..])
+}
+
+/// Returns the provided comparator of the given name.
+pub(crate) fn builtin(name: &str) -> Option<Arc<dyn Comparator>> {
+    match name {
+        "bytewise" => Some(Arc::new(Bytewise)),
+        "case-insensitive" => Some(Arc::new(CaseInsensitive)),
+        "numeric" => Some(Arc::new(Numeric)),
+        _ => None,
+    }
+}
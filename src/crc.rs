@@ -0,0 +1,56 @@
+/// Lookup table of the reflected CRC-32 (IEEE 802.3) polynomial.
+const TABLE: [u32; 256] = table();
+
+const fn table() -> [u32; 256] {
+    let mut table = [0; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 == 1 {
+                (crc >> 1) ^ 0xedb8_8320
+            } else {
+                crc >> 1
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+}
+
+/// A streaming CRC-32 checksum, as used by zlib and PNG.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct Crc32(u32);
+
+impl Crc32 {
+    /// Creates the checksum of no bytes.
+    pub(crate) fn new() -> Crc32 {
+        Crc32(!0)
+    }
+
+    /// Resumes the checksum of bytes whose checksum is `value`.
+    pub(crate) fn resume(value: u32) -> Crc32 {
+        Crc32(!value)
+    }
+
+    /// Adds bytes to the checksum.
+    pub(crate) fn update(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            self.0 = TABLE[((self.0 ^ byte as u32) & 0xff) as usize] ^ (self.0 >> 8);
+        }
+    }
+
+    /// Returns the checksum of the bytes added so far.
+    pub(crate) fn value(&self) -> u32 {
+        !self.0
+    }
+}
+
+impl Default for Crc32 {
+    fn default() -> Crc32 {
+        Crc32::new()
+    }
+}
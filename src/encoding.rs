@@ -0,0 +1,133 @@
+//! Text encodings of keys and values on the command line.
+//!
+//! `kvs` and `kvs-client` accept and print keys and values in the encodings of
+//! `--key-encoding` and `--value-encoding`, so that data that is awkward to type in a
+//! shell can be passed as base64 or hex.
+
+use std::str::FromStr;
+
+use crate::{KvsError, Result};
+
+const BASE64: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+const HEX: &[u8; 16] = b"0123456789abcdef";
+
+/// The encoding of a key or value on the command line.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Encoding {
+    /// The text itself.
+    #[default]
+    Utf8,
+    /// Standard base64 with padding, as in RFC 4648.
+    Base64,
+    /// Lowercase hex digits, two per byte.
+    Hex,
+}
+
+impl Encoding {
+    /// The names accepted by `from_str`.
+    pub const NAMES: &'static [&'static str] = &["utf8", "base64", "hex"];
+
+    /// Encodes the bytes of a key or value.
+    pub fn encode(self, text: &str) -> String {
+        let bytes = text.as_bytes();
+        match self {
+            Encoding::Utf8 => text.to_owned(),
+            Encoding::Base64 => {
+                let mut encoded = String::with_capacity(bytes.len().div_ceil(3) * 4);
+                for chunk in bytes.chunks(3) {
+                    let n = chunk
+                        .iter()
+                        .enumerate()
+                        .fold(0u32, |n, (i, &b)| n | (b as u32) << (16 - 8 * i));
+                    for i in 0..4 {
+                        if i <= chunk.len() {
+                            encoded.push(BASE64[((n >> (18 - 6 * i)) & 0x3f) as usize] as char);
+                        } else {
+                            encoded.push('=');
+                        }
+                    }
+                }
+                encoded
+            }
+            Encoding::Hex => {
+                let mut encoded = String::with_capacity(bytes.len() * 2);
+                for &b in bytes {
+                    encoded.push(HEX[(b >> 4) as usize] as char);
+                    encoded.push(HEX[(b & 0xf) as usize] as char);
+                }
+                encoded
+            }
+        }
+    }
+
+    /// Decodes a key or value.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::InvalidEncoding` if the text is not valid in the encoding,
+    /// or if the decoded bytes are not UTF-8, which keys and values have to be.
+    pub fn decode(self, text: &str) -> Result<String> {
+        let invalid = || KvsError::InvalidEncoding(format!("{:?} is not {}", text, self.name()));
+        let bytes = match self {
+            Encoding::Utf8 => return Ok(text.to_owned()),
+            Encoding::Base64 => {
+                let digits = text.trim_end_matches('=').as_bytes();
+                if text.len() - digits.len() > 2 || digits.len() % 4 == 1 {
+                    return Err(invalid());
+                }
+                let mut bytes = Vec::with_capacity(digits.len() * 3 / 4);
+                for chunk in digits.chunks(4) {
+                    let mut n = 0u32;
+                    for (i, &digit) in chunk.iter().enumerate() {
+                        let value = BASE64
+                            .iter()
+                            .position(|&c| c == digit)
+                            .ok_or_else(invalid)?;
+                        n |= (value as u32) << (18 - 6 * i);
+                    }
+                    for i in 0..chunk.len() - 1 {
+                        bytes.push((n >> (16 - 8 * i)) as u8);
+                    }
+                }
+                bytes
+            }
+            Encoding::Hex => {
+                if !text.len().is_multiple_of(2) {
+                    return Err(invalid());
+                }
+                let digit = |c: u8| (c as char).to_digit(16).ok_or_else(invalid);
+                text.as_bytes()
+                    .chunks(2)
+                    .map(|pair| Ok((digit(pair[0])? << 4 | digit(pair[1])?) as u8))
+                    .collect::<Result<_>>()?
+            }
+        };
+        String::from_utf8(bytes)
+            .map_err(|_| KvsError::InvalidEncoding(format!("{:?} doesn't decode to UTF-8", text)))
+    }
+
+    /// Returns the name of the encoding.
+    pub fn name(self) -> &'static str {
+        match self {
+            Encoding::Utf8 => "utf8",
+            Encoding::Base64 => "base64",
+            Encoding::Hex => "hex",
+        }
+    }
+}
+
+impl FromStr for Encoding {
+    type Err = KvsError;
+
+    fn from_str(name: &str) -> Result<Encoding> {
+        match name {
+            "utf8" => Ok(Encoding::Utf8),
+            "base64" => Ok(Encoding::Base64),
+            "hex" => Ok(Encoding::Hex),
+            _ => Err(KvsError::InvalidEncoding(format!(
+                "unknown encoding {}",
+                name
+            ))),
+        }
+    }
+}
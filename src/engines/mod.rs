@@ -0,0 +1,177 @@
+//! Storage engines behind a common interface.
+
+use std::time::Duration;
+
+#[cfg(feature = "server")]
+use crate::KvsClient;
+use crate::{KvStore, KvsError, Result, SharedKvStore};
+
+/// The interface of a key/value storage engine.
+///
+/// Code written against this trait, like the `kvs` command, can run on any engine.
+/// `KvStore` is the log-structured engine of this crate.
+///
+/// Only `set`, `get` and `remove` must be implemented. The TTL operations fail with
+/// `KvsError::Unsupported` unless an engine overrides them, and `copy` reads and writes
+/// the value with `get` and `set`.
+pub trait KvsEngine {
+    /// Sets the value of a string key to a string.
+    ///
+    /// If the key already exists, the previous value will be overwritten.
+    fn set(&mut self, key: String, value: String) -> Result<()>;
+
+    /// Gets the string value of a given string key.
+    ///
+    /// Returns `None` if the given key does not exist.
+    fn get(&mut self, key: String) -> Result<Option<String>>;
+
+    /// Removes a given key.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::KeyNotFound` if the given key is not found.
+    fn remove(&mut self, key: String) -> Result<()>;
+
+    /// Returns the remaining time to live of a given key.
+    ///
+    /// Returns `None` if the key does not expire.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::KeyNotFound` if the given key is not found, and
+    /// `KvsError::Unsupported` unless the engine implements it.
+    fn ttl(&mut self, key: String) -> Result<Option<Duration>> {
+        let _ = key;
+        Err(KvsError::Unsupported("ttl".to_owned()))
+    }
+
+    /// Sets the TTL of an existing key, replacing any previous TTL.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::KeyNotFound` if the given key is not found, and
+    /// `KvsError::Unsupported` unless the engine implements it.
+    fn expire(&mut self, key: String, ttl: Duration) -> Result<()> {
+        let _ = (key, ttl);
+        Err(KvsError::Unsupported("expire".to_owned()))
+    }
+
+    /// Removes the TTL of a given key so that it never expires.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::KeyNotFound` if the given key is not found, and
+    /// `KvsError::Unsupported` unless the engine implements it.
+    fn persist(&mut self, key: String) -> Result<()> {
+        let _ = key;
+        Err(KvsError::Unsupported("persist".to_owned()))
+    }
+
+    /// Copies the value of a given key to `dst`, overwriting an existing `dst` only if
+    /// `overwrite` is `true`. Returns whether the value was copied.
+    ///
+    /// The default implementation copies only the value, with a `get` and a `set`.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::KeyNotFound` if the given key is not found.
+    fn copy(&mut self, key: String, dst: String, overwrite: bool) -> Result<bool> {
+        let value = self.get(key)?.ok_or(KvsError::KeyNotFound)?;
+        if !overwrite && self.get(dst.clone())?.is_some() {
+            return Ok(false);
+        }
+        self.set(dst, value)?;
+        Ok(true)
+    }
+}
+
+impl KvsEngine for KvStore {
+    fn set(&mut self, key: String, value: String) -> Result<()> {
+        KvStore::set(self, key, value)
+    }
+
+    fn get(&mut self, key: String) -> Result<Option<String>> {
+        KvStore::get(self, key)
+    }
+
+    fn remove(&mut self, key: String) -> Result<()> {
+        KvStore::remove(self, key)
+    }
+
+    fn ttl(&mut self, key: String) -> Result<Option<Duration>> {
+        KvStore::ttl(self, key)
+    }
+
+    fn expire(&mut self, key: String, ttl: Duration) -> Result<()> {
+        KvStore::expire(self, key, ttl)
+    }
+
+    fn persist(&mut self, key: String) -> Result<()> {
+        KvStore::persist(self, key)
+    }
+
+    fn copy(&mut self, key: String, dst: String, overwrite: bool) -> Result<bool> {
+        KvStore::copy(self, key, dst, overwrite)
+    }
+}
+
+impl KvsEngine for SharedKvStore {
+    fn set(&mut self, key: String, value: String) -> Result<()> {
+        SharedKvStore::set(self, key, value)
+    }
+
+    fn get(&mut self, key: String) -> Result<Option<String>> {
+        SharedKvStore::get(self, key)
+    }
+
+    fn remove(&mut self, key: String) -> Result<()> {
+        SharedKvStore::remove(self, key)
+    }
+
+    fn ttl(&mut self, key: String) -> Result<Option<Duration>> {
+        self.lock().ttl(key)
+    }
+
+    fn expire(&mut self, key: String, ttl: Duration) -> Result<()> {
+        self.lock().expire(key, ttl)
+    }
+
+    fn persist(&mut self, key: String) -> Result<()> {
+        self.lock().persist(key)
+    }
+
+    fn copy(&mut self, key: String, dst: String, overwrite: bool) -> Result<bool> {
+        self.lock().copy(key, dst, overwrite)
+    }
+}
+
+#[cfg(feature = "server")]
+impl KvsEngine for KvsClient {
+    fn set(&mut self, key: String, value: String) -> Result<()> {
+        KvsClient::set(self, key, value)
+    }
+
+    fn get(&mut self, key: String) -> Result<Option<String>> {
+        KvsClient::get(self, key)
+    }
+
+    fn remove(&mut self, key: String) -> Result<()> {
+        KvsClient::remove(self, key)
+    }
+
+    fn ttl(&mut self, key: String) -> Result<Option<Duration>> {
+        KvsClient::ttl(self, key)
+    }
+
+    fn expire(&mut self, key: String, ttl: Duration) -> Result<()> {
+        KvsClient::expire(self, key, ttl)
+    }
+
+    fn persist(&mut self, key: String) -> Result<()> {
+        KvsClient::persist(self, key)
+    }
+
+    fn copy(&mut self, key: String, dst: String, overwrite: bool) -> Result<bool> {
+        KvsClient::copy(self, key, dst, overwrite)
+    }
+}
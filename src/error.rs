@@ -1,3 +1,6 @@
+// `failure_derive` expands to impls nested in anonymous consts.
+#![allow(non_local_definitions)]
+
 use failure::Fail;
 use std::io;
 
@@ -17,6 +20,138 @@ pub enum KvsError {
     /// It indicated a corrupted log or a program bug.
     #[fail(display = "Unexpected command type")]
     UnexpectedCommandType,
+    /// An index entry points into a log generation that no longer exists.
+    /// It indicates a program bug.
+    #[fail(display = "Log generation {} not found", _0)]
+    StaleIndex(u64),
+    /// A log file does not match the length or checksum recorded in the manifest.
+    /// It indicates a truncated or externally modified log.
+    #[fail(display = "Log file {} is {}", gen, reason)]
+    CorruptedLog {
+        /// The generation of the log file.
+        gen: u64,
+        /// How the log file differs from the manifest.
+        reason: &'static str,
+    },
+    /// A record of the log can't be read or doesn't match its checksum.
+    /// It indicates a corrupted log.
+    #[fail(display = "Corrupt record at {} of log file {}: {}", pos, gen, reason)]
+    Corruption {
+        /// The generation of the log file.
+        gen: u64,
+        /// The position of the record in the log file.
+        pos: u64,
+        /// What is wrong with the record.
+        reason: String,
+    },
+    /// Rebuilding the index of a `LazyKvStore` failed, so it can't serve requests.
+    #[fail(display = "Index rebuild failed")]
+    RebuildFailed,
+    /// Writing to the log failed earlier, so the store only serves reads until
+    /// `KvStore::clear_read_only` is called.
+    #[fail(display = "Store is read-only after a failed write")]
+    ReadOnly,
+    /// The disk can't hold the space configured with `Options::preallocate` for a
+    /// new log file.
+    #[fail(display = "Not enough disk space to preallocate {} bytes", _0)]
+    NoSpace(u64),
+    /// The store is opened with `Options::exclusive` by another `KvStore`.
+    #[fail(display = "Store is locked by another process")]
+    Locked,
+    /// A key pattern is not a valid regular expression.
+    #[fail(display = "Invalid key pattern: {}", _0)]
+    InvalidPattern(String),
+    /// The store was created with a comparator of another name than the one in
+    /// `Options::comparator`, or with a custom comparator that has to be configured.
+    #[fail(display = "Store was created with the {} comparator", _0)]
+    ComparatorMismatch(String),
+    /// `Options::normalize_keys` was set for a store created without it.
+    #[fail(display = "Store was created without key normalization")]
+    NotNormalized,
+    /// A key or value is not valid in its `encoding::Encoding`.
+    #[fail(display = "Invalid encoding: {}", _0)]
+    InvalidEncoding(String),
+    /// `KvStore::incr` was called on a key whose value is not an integer.
+    #[fail(display = "Value of {} is not an integer", _0)]
+    NotAnInteger(String),
+    /// `KvStore::incr` would make the value of a key overflow an `i64`.
+    #[fail(display = "Value of {} would overflow", _0)]
+    Overflow(String),
+    /// `KvStore::commit` or `KvStore::rollback` was called with a token that is not
+    /// pending, because its batch was committed or rolled back already.
+    #[fail(display = "No prepared batch {}", _0)]
+    NotPrepared(String),
+    /// A compaction was requested while snapshots taken with `KvStore::snapshot` are
+    /// live.
+    #[fail(display = "Log is pinned by {} live snapshots", _0)]
+    Pinned(usize),
+    /// A key decoded with `keys::decode` was not built with `keys::Tuple`.
+    #[fail(display = "Key is not an encoded tuple: {:?}", _0)]
+    InvalidTuple(String),
+    /// The path a store is opened with exists but is not a directory.
+    #[fail(display = "{} is not a store directory", _0)]
+    NotADirectory(String),
+    /// A store is backed up or restored to a directory that has files.
+    #[fail(display = "{} is not empty", _0)]
+    DirectoryNotEmpty(String),
+    /// `KvStore::restore` was called with a directory that has no manifest, such as a
+    /// backup cut short.
+    #[fail(display = "{} is not a complete backup", _0)]
+    InvalidBackup(String),
+    /// Data was serialized with another version of `schema::VERSION`.
+    #[fail(display = "Unsupported format version {}", _0)]
+    UnsupportedVersion(u32),
+    /// A name passed to `genload::Distribution::from_str` is not a distribution.
+    #[fail(display = "Unknown distribution: {}", _0)]
+    UnknownDistribution(String),
+    /// A name passed to `export::Format::from_str` is not a format.
+    #[fail(display = "Unknown format: {}", _0)]
+    UnknownFormat(String),
+    /// A name passed to `protocol::Protocol::from_str` is not a protocol.
+    #[fail(display = "Unknown protocol: {}", _0)]
+    UnknownProtocol(String),
+    /// The input of `export::import` is not in its format.
+    #[fail(display = "Invalid export: {}", _0)]
+    InvalidExport(String),
+    /// A value is longer than `PrefixConfig::max_value_len` of its key.
+    #[fail(display = "Value of {} is longer than {} bytes", key, limit)]
+    ValueTooLarge {
+        /// The key of the value.
+        key: String,
+        /// The configured limit.
+        limit: usize,
+    },
+    /// The deadline of `KvStore::get_with_deadline` passed before the value was read.
+    #[fail(display = "Deadline exceeded")]
+    DeadlineExceeded,
+    /// A write was rejected because the stale data reached the stop limit of
+    /// `Options::write_stall`.
+    #[fail(display = "Store is busy until stale data is compacted")]
+    Busy,
+    /// A protocol frame is longer than `protocol::Limits::max_frame`.
+    #[fail(
+        display = "Frame of {} bytes exceeds the limit of {} bytes",
+        len, limit
+    )]
+    FrameTooLarge {
+        /// The length of the frame.
+        len: usize,
+        /// The configured limit.
+        limit: usize,
+    },
+    /// A protocol frame is not a valid message.
+    #[fail(display = "Invalid request: {}", _0)]
+    InvalidRequest(String),
+    /// The server rejected a request of a client that is not allowed to make it.
+    #[fail(display = "Not authorized")]
+    Unauthorized,
+    /// `kvs-server` failed a request of `KvsClient` with the given message, for a reason
+    /// without a variant of its own.
+    #[fail(display = "Server error: {}", _0)]
+    Server(String),
+    /// A `KvsEngine` doesn't implement the named operation.
+    #[fail(display = "Unsupported operation: {}", _0)]
+    Unsupported(String),
 }
 
 impl From<io::Error> for KvsError {
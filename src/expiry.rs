@@ -0,0 +1,65 @@
+use std::collections::{BTreeSet, HashMap};
+
+use crate::crc::Crc32;
+
+/// Index of key expiration times.
+///
+/// Expiration times are milliseconds since the Unix epoch. Keys are ordered by their
+/// expiration time so that expired keys can be found without scanning all keys.
+#[derive(Clone, Default)]
+pub(crate) struct ExpiryIndex {
+    by_key: HashMap<String, u64>,
+    by_time: BTreeSet<(u64, String)>,
+}
+
+impl ExpiryIndex {
+    /// Sets or clears the expiration time of a key.
+    pub(crate) fn set(&mut self, key: &str, expires_at: Option<u64>) {
+        self.remove(key);
+        if let Some(expires_at) = expires_at {
+            self.by_key.insert(key.to_owned(), expires_at);
+            self.by_time.insert((expires_at, key.to_owned()));
+        }
+    }
+
+    /// Clears the expiration time of a key.
+    pub(crate) fn remove(&mut self, key: &str) {
+        if let Some(expires_at) = self.by_key.remove(key) {
+            self.by_time.remove(&(expires_at, key.to_owned()));
+        }
+    }
+
+    /// Returns the expiration time of a key.
+    pub(crate) fn get(&self, key: &str) -> Option<u64> {
+        self.by_key.get(key).cloned()
+    }
+
+    /// Returns `true` if the key has expired at `now`.
+    pub(crate) fn is_expired(&self, key: &str, now: u64) -> bool {
+        self.get(key).is_some_and(|expires_at| expires_at <= now)
+    }
+
+    /// Returns up to `limit` keys that have expired at `now`, the earliest first.
+    ///
+    /// Each key is delayed by a jitter below `jitter` milliseconds derived from the key,
+    /// which spreads out keys that expire at the same time.
+    pub(crate) fn expired(&self, now: u64, jitter: u64, limit: usize) -> Vec<String> {
+        self.by_time
+            .iter()
+            .take_while(|(expires_at, _)| *expires_at <= now)
+            .filter(|(expires_at, key)| expires_at + delay(key, jitter) <= now)
+            .take(limit)
+            .map(|(_, key)| key.clone())
+            .collect()
+    }
+}
+
+/// Returns the jitter of a key, which is stable across runs.
+fn delay(key: &str, jitter: u64) -> u64 {
+    if jitter == 0 {
+        return 0;
+    }
+    let mut crc = Crc32::new();
+    crc.update(key.as_bytes());
+    u64::from(crc.value()) % jitter
+}
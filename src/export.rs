@@ -0,0 +1,205 @@
+//! Exporting and importing the entries of a store in portable formats.
+//!
+//! [`export`] writes the live entries of a store in key order and [`import`] sets them
+//! in another store, so that data is moved between machines and into other tools
+//! without reading the log format.
+
+use std::convert::TryFrom;
+use std::io::{BufRead, Write};
+use std::str::FromStr;
+
+use serde::{Deserialize, Serialize};
+
+use crate::{KvStore, KvsError, Result};
+
+/// The format of exported entries.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Format {
+    /// One JSON object per line, such as `{"key":"key1","value":"value1"}`.
+    #[default]
+    Json,
+    /// A `key,value` header followed by one record per entry, with fields quoted as in
+    /// RFC 4180 when they contain commas, quotes or line breaks.
+    Csv,
+}
+
+impl Format {
+    /// The names accepted by `from_str`.
+    pub const NAMES: &'static [&'static str] = &["json", "csv"];
+}
+
+impl FromStr for Format {
+    type Err = KvsError;
+
+    fn from_str(name: &str) -> Result<Format> {
+        match name {
+            "json" => Ok(Format::Json),
+            "csv" => Ok(Format::Csv),
+            _ => Err(KvsError::UnknownFormat(name.to_owned())),
+        }
+    }
+}
+
+/// An exported entry in `Format::Json`.
+#[derive(Serialize, Deserialize)]
+struct Entry {
+    key: String,
+    value: String,
+}
+
+/// Writes the live entries of a store in key order and returns their number.
+///
+/// The entries are read as they are written, so the whole store is never held in
+/// memory.
+///
+/// # Errors
+///
+/// It propagates errors during reading the entries and I/O errors during writing.
+pub fn export(store: &mut KvStore, format: Format, mut out: impl Write) -> Result<u64> {
+    if format == Format::Csv {
+        out.write_all(b"key,value\n")?;
+    }
+    let mut count = 0;
+    for entry in store.scan() {
+        let (key, value) = entry?;
+        match format {
+            Format::Json => {
+                serde_json::to_writer(&mut out, &Entry { key, value })?;
+                out.write_all(b"\n")?;
+            }
+            Format::Csv => writeln!(out, "{},{}", csv_field(&key), csv_field(&value))?,
+        }
+        count += 1;
+    }
+    out.flush()?;
+    Ok(count)
+}
+
+/// Sets the entries read from an export in a store and returns their number.
+///
+/// Entries are set as they are read, so the entries before a malformed one are kept.
+///
+/// # Errors
+///
+/// It returns `KvsError::InvalidExport` if the input is not in the given format.
+///
+/// It propagates I/O errors during reading and errors of `KvStore::set`.
+pub fn import(store: &mut KvStore, format: Format, input: impl BufRead) -> Result<u64> {
+    let mut count = 0;
+    match format {
+        Format::Json => {
+            for (i, line) in input.lines().enumerate() {
+                let line = line?;
+                if line.trim().is_empty() {
+                    continue;
+                }
+                let entry: Entry = serde_json::from_str(&line)
+                    .map_err(|e| KvsError::InvalidExport(format!("line {}: {}", i + 1, e)))?;
+                store.set(entry.key, entry.value)?;
+                count += 1;
+            }
+        }
+        Format::Csv => {
+            let mut records = CsvRecords {
+                lines: input.lines(),
+                line: 0,
+            };
+            match records.next().transpose()? {
+                Some(header) if header == ["key", "value"] => {}
+                _ => {
+                    return Err(KvsError::InvalidExport(
+                        "line 1: expected a key,value header".to_owned(),
+                    ))
+                }
+            }
+            while let Some(record) = records.next().transpose()? {
+                let line = records.line;
+                let (key, value) = match <[String; 2]>::try_from(record) {
+                    Ok([key, value]) => (key, value),
+                    Err(record) => {
+                        return Err(KvsError::InvalidExport(format!(
+                            "line {}: expected 2 fields, found {}",
+                            line,
+                            record.len()
+                        )))
+                    }
+                };
+                store.set(key, value)?;
+                count += 1;
+            }
+        }
+    }
+    Ok(count)
+}
+
+/// Quotes a CSV field if it contains a comma, a quote or a line break.
+fn csv_field(field: &str) -> String {
+    if field.contains(&[',', '"', '\n', '\r'][..]) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_owned()
+    }
+}
+
+/// Yields the fields of CSV records, which span several lines when a quoted field has
+/// line breaks.
+struct CsvRecords<L> {
+    lines: L,
+    // the number of lines read so far.
+    line: usize,
+}
+
+impl<L: Iterator<Item = std::io::Result<String>>> Iterator for CsvRecords<L> {
+    type Item = Result<Vec<String>>;
+
+    fn next(&mut self) -> Option<Result<Vec<String>>> {
+        let mut line = loop {
+            match self.lines.next()? {
+                Ok(line) => {
+                    self.line += 1;
+                    if !line.is_empty() {
+                        break line;
+                    }
+                }
+                Err(e) => return Some(Err(e.into())),
+            }
+        };
+        let mut fields = Vec::new();
+        let mut field = String::new();
+        let mut quoted = false;
+        loop {
+            let mut chars = line.chars().peekable();
+            while let Some(c) = chars.next() {
+                match (quoted, c) {
+                    (true, '"') if chars.peek() == Some(&'"') => {
+                        chars.next();
+                        field.push('"');
+                    }
+                    (true, '"') => quoted = false,
+                    (true, c) => field.push(c),
+                    (false, '"') if field.is_empty() => quoted = true,
+                    (false, ',') => fields.push(std::mem::take(&mut field)),
+                    (false, c) => field.push(c),
+                }
+            }
+            if !quoted {
+                break;
+            }
+            // the quoted field continues on the next line.
+            field.push('\n');
+            line = match self.lines.next() {
+                Some(Ok(line)) => line,
+                Some(Err(e)) => return Some(Err(e.into())),
+                None => {
+                    return Some(Err(KvsError::InvalidExport(format!(
+                        "line {}: unterminated quoted field",
+                        self.line
+                    ))))
+                }
+            };
+            self.line += 1;
+        }
+        fields.push(field);
+        Some(Ok(fields))
+    }
+}
@@ -0,0 +1,124 @@
+//! Synthetic data for benchmarks and demos.
+//!
+//! A [`Generator`] yields keys and values from a seed, so that `kvs genload` and
+//! benchmarks populate the same store on every run.
+
+use std::str::FromStr;
+
+use crate::random::Rng;
+use crate::{KvsError, Result};
+
+const ALPHANUMERIC: &[u8; 62] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789";
+/// The exponent of the Zipf distribution, as in YCSB.
+const ZIPF_EXPONENT: f64 = 0.99;
+
+/// The distribution of the keys written by a [`Generator`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Distribution {
+    /// Every key is equally likely.
+    #[default]
+    Uniform,
+    /// The `n`th most frequent key is written about `1 / n` times as often as the most
+    /// frequent one, which is the first key.
+    Zipf,
+}
+
+impl Distribution {
+    /// The names accepted by `from_str`.
+    pub const NAMES: &'static [&'static str] = &["uniform", "zipf"];
+}
+
+impl FromStr for Distribution {
+    type Err = KvsError;
+
+    fn from_str(name: &str) -> Result<Distribution> {
+        match name {
+            "uniform" => Ok(Distribution::Uniform),
+            "zipf" => Ok(Distribution::Zipf),
+            _ => Err(KvsError::UnknownDistribution(name.to_owned())),
+        }
+    }
+}
+
+/// Yields keys drawn from a distribution over `key0` to `keyN-1`, with random
+/// alphanumeric values of a fixed size.
+///
+/// The same arguments yield the same sequence:
+///
+/// ```rust
+/// # use kvs::genload::{Distribution, Generator};
+/// let first: Vec<_> = Generator::new(100, 8, Distribution::Zipf, 7).take(10).collect();
+/// let second: Vec<_> = Generator::new(100, 8, Distribution::Zipf, 7).take(10).collect();
+/// assert_eq!(first, second);
+/// ```
+pub struct Generator {
+    rng: Rng,
+    keys: u64,
+    width: usize,
+    value_size: usize,
+    // the cumulative probabilities of the keys for `Distribution::Zipf`.
+    cdf: Option<Vec<f64>>,
+}
+
+impl Generator {
+    /// Creates a generator of values of `value_size` bytes for `keys` keys.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `keys` is 0.
+    pub fn new(keys: u64, value_size: usize, distribution: Distribution, seed: u64) -> Generator {
+        assert!(keys > 0, "a generator needs at least one key");
+        let cdf = match distribution {
+            Distribution::Uniform => None,
+            Distribution::Zipf => {
+                let mut sum = 0.0;
+                let mut cdf: Vec<f64> = (1..=keys)
+                    .map(|rank| {
+                        sum += 1.0 / (rank as f64).powf(ZIPF_EXPONENT);
+                        sum
+                    })
+                    .collect();
+                for p in &mut cdf {
+                    *p /= sum;
+                }
+                Some(cdf)
+            }
+        };
+        Generator {
+            rng: Rng::with_seed(seed),
+            keys,
+            width: (keys - 1).to_string().len(),
+            value_size,
+            cdf,
+        }
+    }
+
+    /// Returns the next key.
+    pub fn key(&mut self) -> String {
+        let n = match &self.cdf {
+            None => self.rng.below(self.keys),
+            Some(cdf) => {
+                let draw = self.rng.unit();
+                cdf.partition_point(|&p| p <= draw).min(cdf.len() - 1) as u64
+            }
+        };
+        // zero-padded, so that keys sort in the order of their numbers.
+        format!("key{:0width$}", n, width = self.width)
+    }
+
+    /// Returns the next value.
+    pub fn value(&mut self) -> String {
+        (0..self.value_size)
+            .map(|_| ALPHANUMERIC[self.rng.below(ALPHANUMERIC.len() as u64) as usize] as char)
+            .collect()
+    }
+}
+
+impl Iterator for Generator {
+    type Item = (String, String);
+
+    fn next(&mut self) -> Option<(String, String)> {
+        let key = self.key();
+        Some((key, self.value()))
+    }
+}
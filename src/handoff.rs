@@ -0,0 +1,144 @@
+//! Handing the listening socket of `kvs-server` over to a new process.
+//!
+//! A server offers its listening socket on the `HANDOFF` socket in the store
+//! directory. A new server connects to it with [`take_over`] and receives the
+//! listening socket. Meanwhile the old server stops accepting connections, finishes
+//! the one it serves, closes the store and hangs up. Connections arriving in between
+//! wait in the backlog of the socket, so upgrades refuse none of them.
+
+use std::fs;
+use std::io::{self, Read};
+use std::mem;
+use std::net::TcpListener;
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::Path;
+use std::ptr;
+
+use crate::Result;
+
+const HANDOFF: &str = "HANDOFF";
+
+/// The listening socket of a server, offered to the next server of the store.
+pub struct Offer {
+    listener: UnixListener,
+}
+
+/// The connection to a new server, which is told that the store is closed when it is
+/// dropped.
+pub struct Handover {
+    _stream: UnixStream,
+}
+
+impl Offer {
+    /// Offers listening sockets on the `HANDOFF` socket of a store directory.
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O errors binding the socket.
+    pub fn bind(dir: &Path) -> Result<Offer> {
+        let path = dir.join(HANDOFF);
+        // left behind by the previous server, which can't remove it while it's in use.
+        match fs::remove_file(&path) {
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => {}
+            result => result?,
+        }
+        Ok(Offer {
+            listener: UnixListener::bind(path)?,
+        })
+    }
+
+    /// Waits for a new server and sends it the given listening socket.
+    ///
+    /// The caller should stop accepting connections and close the store before it
+    /// drops the returned `Handover`.
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O errors sending the socket.
+    pub fn wait(&self, socket: &TcpListener) -> Result<Handover> {
+        let (stream, _) = self.listener.accept()?;
+        send_fd(&stream, socket.as_raw_fd())?;
+        Ok(Handover { _stream: stream })
+    }
+}
+
+/// Takes the listening socket over from the server of a store directory, and waits
+/// until that server has closed the store.
+///
+/// # Errors
+///
+/// It propagates I/O errors connecting to the server, e.g. if no server runs.
+pub fn take_over(dir: &Path) -> Result<TcpListener> {
+    let mut stream = UnixStream::connect(dir.join(HANDOFF))?;
+    let fd = recv_fd(&stream)?;
+    // SAFETY: the descriptor was just received and nothing else owns it.
+    let listener = unsafe { TcpListener::from_raw_fd(fd) };
+    // the old server hangs up once it has closed the store.
+    stream.read_to_end(&mut Vec::new())?;
+    Ok(listener)
+}
+
+/// Sends a file descriptor over a Unix socket.
+fn send_fd(stream: &UnixStream, fd: RawFd) -> io::Result<()> {
+    let mut byte = [0u8];
+    let mut iov = libc::iovec {
+        iov_base: byte.as_mut_ptr() as *mut libc::c_void,
+        iov_len: byte.len(),
+    };
+    // SAFETY: CMSG_SPACE only computes a size.
+    let space = unsafe { libc::CMSG_SPACE(mem::size_of::<RawFd>() as u32) } as usize;
+    let mut control = vec![0u8; space];
+    // SAFETY: an all-zero `msghdr` is valid; the pointers set below outlive the call.
+    unsafe {
+        let mut msg: libc::msghdr = mem::zeroed();
+        msg.msg_iov = &mut iov;
+        msg.msg_iovlen = 1;
+        msg.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+        msg.msg_controllen = space as _;
+        let cmsg = libc::CMSG_FIRSTHDR(&msg);
+        (*cmsg).cmsg_level = libc::SOL_SOCKET;
+        (*cmsg).cmsg_type = libc::SCM_RIGHTS;
+        (*cmsg).cmsg_len = libc::CMSG_LEN(mem::size_of::<RawFd>() as u32) as _;
+        ptr::write_unaligned(libc::CMSG_DATA(cmsg) as *mut RawFd, fd);
+        if libc::sendmsg(stream.as_raw_fd(), &msg, 0) < 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+/// Receives a file descriptor sent with `send_fd`.
+fn recv_fd(stream: &UnixStream) -> io::Result<RawFd> {
+    let mut byte = [0u8];
+    let mut iov = libc::iovec {
+        iov_base: byte.as_mut_ptr() as *mut libc::c_void,
+        iov_len: byte.len(),
+    };
+    // SAFETY: CMSG_SPACE only computes a size.
+    let space = unsafe { libc::CMSG_SPACE(mem::size_of::<RawFd>() as u32) } as usize;
+    let mut control = vec![0u8; space];
+    // SAFETY: an all-zero `msghdr` is valid; the pointers set below outlive the call,
+    // and the control message is only read after checking its type.
+    unsafe {
+        let mut msg: libc::msghdr = mem::zeroed();
+        msg.msg_iov = &mut iov;
+        msg.msg_iovlen = 1;
+        msg.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+        msg.msg_controllen = space as _;
+        if libc::recvmsg(stream.as_raw_fd(), &mut msg, libc::MSG_CMSG_CLOEXEC) < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let cmsg = libc::CMSG_FIRSTHDR(&msg);
+        if cmsg.is_null()
+            || (*cmsg).cmsg_level != libc::SOL_SOCKET
+            || (*cmsg).cmsg_type != libc::SCM_RIGHTS
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "the server sent no listening socket",
+            ));
+        }
+        Ok(ptr::read_unaligned(libc::CMSG_DATA(cmsg) as *const RawFd))
+    }
+}
@@ -0,0 +1,80 @@
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+use log::warn;
+use serde::{Deserialize, Serialize};
+use serde_json::Deserializer;
+
+use crate::Result;
+
+/// The location of a "set" command in a log file written by a compaction.
+///
+/// The hint file of a log file lists the hints of all its commands, so that the index
+/// can be rebuilt without reading the values.
+#[derive(Serialize, Deserialize, Debug)]
+pub(crate) struct Hint {
+    pub(crate) key: String,
+    pub(crate) pos: u64,
+    pub(crate) len: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) expires_at: Option<u64>,
+}
+
+/// Writes the hint file of the log file of the given generation and length.
+pub(crate) fn write(dir: &Path, gen: u64, log_len: u64, hints: &[Hint]) -> Result<()> {
+    let path = hint_path(dir, gen);
+    let tmp_path = path.with_extension("hint.tmp");
+    let mut writer = BufWriter::new(File::create(&tmp_path)?);
+    // the length of the log file tells whether it was rewritten after the hints.
+    writeln!(writer, "{}", log_len)?;
+    for hint in hints {
+        serde_json::to_writer(&mut writer, hint)?;
+        writer.write_all(b"\n")?;
+    }
+    writer.flush()?;
+    writer.get_ref().sync_all()?;
+    fs::rename(&tmp_path, path)?;
+    Ok(())
+}
+
+/// Reads the hint file of the log file of the given generation and length.
+///
+/// Returns `None` if there is no hint file, or if it doesn't match the log file or
+/// can't be read, in which case the log file has to be read instead.
+pub(crate) fn read(dir: &Path, gen: u64, log_len: u64) -> Result<Option<Vec<Hint>>> {
+    let path = hint_path(dir, gen);
+    let mut reader = match File::open(&path) {
+        Ok(file) => BufReader::new(file),
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    let mut line = String::new();
+    reader.read_line(&mut line)?;
+    if line.trim_end().parse::<u64>().ok() != Some(log_len) {
+        warn!(
+            "ignored {}, which doesn't match its log file",
+            path.display()
+        );
+        return Ok(None);
+    }
+    match Deserializer::from_reader(reader).into_iter().collect() {
+        Ok(hints) => Ok(Some(hints)),
+        Err(e) => {
+            warn!("ignored {}: {}", path.display(), e);
+            Ok(None)
+        }
+    }
+}
+
+/// Removes the hint file of the log file of the given generation, if any.
+pub(crate) fn remove(dir: &Path, gen: u64) -> Result<()> {
+    match fs::remove_file(hint_path(dir, gen)) {
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        result => Ok(result?),
+    }
+}
+
+fn hint_path(dir: &Path, gen: u64) -> PathBuf {
+    dir.join(format!("{}.hint", gen))
+}
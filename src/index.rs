@@ -0,0 +1,308 @@
+use std::borrow::{Borrow, Cow};
+use std::cmp::Ordering;
+use std::collections::BTreeMap;
+use std::ops::Bound;
+use std::sync::Arc;
+
+use crate::comparator::Comparator;
+use crate::nfc;
+
+/// An ordered map from keys to values, ordered by a comparator.
+///
+/// Without a comparator, keys are ordered by their bytes like in a `BTreeMap<String, V>`.
+/// Keys are resolved in NFC once the index is `normalized`.
+#[derive(Clone)]
+pub(crate) struct Index<V> {
+    map: BTreeMap<Key, V>,
+    comparator: Option<Arc<dyn Comparator>>,
+    normalized: bool,
+}
+
+/// A key carrying the comparator of its index, which `Ord` has no other access to.
+#[derive(Clone)]
+struct Key {
+    key: String,
+    comparator: Option<Arc<dyn Comparator>>,
+}
+
+/// A key to compare, which lets the index be searched for a `&str` without allocating
+/// a `Key`.
+trait Lookup {
+    fn key(&self) -> &str;
+    fn comparator(&self) -> Option<&dyn Comparator>;
+}
+
+impl Lookup for Key {
+    fn key(&self) -> &str {
+        &self.key
+    }
+
+    fn comparator(&self) -> Option<&dyn Comparator> {
+        self.comparator.as_deref()
+    }
+}
+
+struct Probe<'a> {
+    key: &'a str,
+    comparator: Option<&'a dyn Comparator>,
+}
+
+impl Lookup for Probe<'_> {
+    fn key(&self) -> &str {
+        self.key
+    }
+
+    fn comparator(&self) -> Option<&dyn Comparator> {
+        self.comparator
+    }
+}
+
+impl<'a> Borrow<dyn Lookup + 'a> for Key {
+    fn borrow(&self) -> &(dyn Lookup + 'a) {
+        self
+    }
+}
+
+impl Ord for dyn Lookup + '_ {
+    fn cmp(&self, other: &Self) -> Ordering {
+        match self.comparator() {
+            Some(comparator) => comparator.compare(self.key(), other.key()),
+            None => self.key().cmp(other.key()),
+        }
+    }
+}
+
+impl PartialOrd for dyn Lookup + '_ {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for dyn Lookup + '_ {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for dyn Lookup + '_ {}
+
+impl Ord for Key {
+    fn cmp(&self, other: &Self) -> Ordering {
+        (self as &dyn Lookup).cmp(other as &dyn Lookup)
+    }
+}
+
+impl PartialOrd for Key {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for Key {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Key {}
+
+fn bound_probe<'a>(
+    bound: Bound<&'a str>,
+    comparator: Option<&'a dyn Comparator>,
+) -> Bound<Probe<'a>> {
+    match bound {
+        Bound::Included(key) => Bound::Included(Probe { key, comparator }),
+        Bound::Excluded(key) => Bound::Excluded(Probe { key, comparator }),
+        Bound::Unbounded => Bound::Unbounded,
+    }
+}
+
+fn as_lookup<'a>(bound: &'a Bound<Probe<'a>>) -> Bound<&'a (dyn Lookup + 'a)> {
+    match bound {
+        Bound::Included(probe) => Bound::Included(probe),
+        Bound::Excluded(probe) => Bound::Excluded(probe),
+        Bound::Unbounded => Bound::Unbounded,
+    }
+}
+
+impl<V> Index<V> {
+    /// Creates an empty index ordered by the given comparator, or by bytes if it is
+    /// `None`.
+    pub(crate) fn new(comparator: Option<Arc<dyn Comparator>>) -> Index<V> {
+        Index {
+            map: BTreeMap::new(),
+            comparator,
+            normalized: false,
+        }
+    }
+
+    /// Sets whether keys are normalized to NFC when they are resolved.
+    pub(crate) fn set_normalized(&mut self, normalized: bool) {
+        self.normalized = normalized;
+    }
+
+    pub(crate) fn is_normalized(&self) -> bool {
+        self.normalized
+    }
+
+    /// Returns `true` if keys are ordered by their bytes.
+    pub(crate) fn is_bytewise(&self) -> bool {
+        self.comparator.is_none()
+    }
+
+    /// Compares two keys in the order of the index.
+    pub(crate) fn compare(&self, a: &str, b: &str) -> Ordering {
+        match &self.comparator {
+            Some(comparator) => comparator.compare(a, b),
+            None => a.cmp(b),
+        }
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.map.len()
+    }
+
+    pub(crate) fn get(&self, key: &str) -> Option<&V> {
+        self.map.get(&self.probe(key) as &dyn Lookup)
+    }
+
+    pub(crate) fn get_mut(&mut self, key: &str) -> Option<&mut V> {
+        let probe = Probe {
+            key,
+            comparator: self.comparator.as_deref(),
+        };
+        self.map.get_mut(&probe as &dyn Lookup)
+    }
+
+    pub(crate) fn contains_key(&self, key: &str) -> bool {
+        self.get(key).is_some()
+    }
+
+    /// Inserts a value, returning the previous value of an equal key, which keeps its
+    /// spelling.
+    pub(crate) fn insert(&mut self, key: String, value: V) -> Option<V> {
+        let key = Key {
+            key,
+            comparator: self.comparator.clone(),
+        };
+        self.map.insert(key, value)
+    }
+
+    pub(crate) fn remove(&mut self, key: &str) -> Option<V> {
+        let probe = Probe {
+            key,
+            comparator: self.comparator.as_deref(),
+        };
+        self.map.remove(&probe as &dyn Lookup)
+    }
+
+    /// Returns an empty index ordered and normalized like this one.
+    pub(crate) fn emptied(&self) -> Index<V> {
+        Index {
+            map: BTreeMap::new(),
+            comparator: self.comparator.clone(),
+            normalized: self.normalized,
+        }
+    }
+
+    /// Returns the spelling of the key equal to the given one, or the given key if
+    /// there is none.
+    pub(crate) fn resolve(&self, key: String) -> String {
+        let key = if self.normalized {
+            nfc::nfc_owned(key)
+        } else {
+            key
+        };
+        if self.is_bytewise() {
+            return key;
+        }
+        match self.map.get_key_value(&self.probe(&key) as &dyn Lookup) {
+            Some((stored, _)) => stored.key.clone(),
+            None => key,
+        }
+    }
+
+    /// Like [`resolve`](Index::resolve), without taking ownership of the key.
+    pub(crate) fn resolve_str<'a>(&'a self, key: &'a str) -> Cow<'a, str> {
+        let key = self.normalize(key);
+        if self.is_bytewise() {
+            return key;
+        }
+        match self.map.get_key_value(&self.probe(&key) as &dyn Lookup) {
+            Some((stored, _)) => Cow::Borrowed(&stored.key),
+            None => key,
+        }
+    }
+
+    /// Returns the NFC form of the given key if keys are normalized.
+    pub(crate) fn normalize<'a>(&self, key: &'a str) -> Cow<'a, str> {
+        if self.normalized {
+            nfc::nfc(key)
+        } else {
+            Cow::Borrowed(key)
+        }
+    }
+
+    pub(crate) fn iter(&self) -> impl DoubleEndedIterator<Item = (&str, &V)> + '_ {
+        self.map
+            .iter()
+            .map(|(key, value)| (key.key.as_str(), value))
+    }
+
+    pub(crate) fn iter_mut(&mut self) -> impl DoubleEndedIterator<Item = (&str, &mut V)> + '_ {
+        self.map
+            .iter_mut()
+            .map(|(key, value)| (key.key.as_str(), value))
+    }
+
+    pub(crate) fn keys(&self) -> impl DoubleEndedIterator<Item = &str> + '_ {
+        self.map.keys().map(|key| key.key.as_str())
+    }
+
+    /// Returns the entries between the given bounds, in order.
+    ///
+    /// Unlike `BTreeMap::range`, it doesn't panic if the start is after the end but
+    /// returns no entries.
+    pub(crate) fn range<'a, 'b>(
+        &'a self,
+        start: Bound<&'b str>,
+        end: Bound<&'b str>,
+    ) -> impl DoubleEndedIterator<Item = (&'a str, &'a V)> + 'a {
+        let start = bound_probe(start, self.comparator.as_deref());
+        let end = bound_probe(end, self.comparator.as_deref());
+        let empty = match (&start, &end) {
+            (Bound::Included(start), Bound::Included(end))
+            | (Bound::Included(start), Bound::Excluded(end))
+            | (Bound::Excluded(start), Bound::Included(end)) => {
+                (start as &dyn Lookup) > (end as &dyn Lookup)
+            }
+            (Bound::Excluded(start), Bound::Excluded(end)) => {
+                (start as &dyn Lookup) >= (end as &dyn Lookup)
+            }
+            _ => false,
+        };
+        let range = if empty {
+            None
+        } else {
+            Some(
+                self.map
+                    .range::<dyn Lookup, _>((as_lookup(&start), as_lookup(&end))),
+            )
+        };
+        range
+            .into_iter()
+            .flatten()
+            .map(|(key, value)| (key.key.as_str(), value))
+    }
+
+    pub(crate) fn into_iter(self) -> impl DoubleEndedIterator<Item = (String, V)> {
+        self.map.into_iter().map(|(key, value)| (key.key, value))
+    }
+
+    fn probe<'a>(&'a self, key: &'a str) -> Probe<'a> {
+        Probe {
+            key,
+            comparator: self.comparator.as_deref(),
+        }
+    }
+}
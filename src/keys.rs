@@ -0,0 +1,174 @@
+//! Order-preserving encoding of tuples into keys.
+//!
+//! Joining the parts of a composite key with a delimiter breaks its order as soon as a
+//! part contains the delimiter or numbers of different lengths are compared. A key
+//! built with [`Tuple`] is instead ordered like the tuple it encodes, element by
+//! element, and the key of a tuple is a prefix of the keys of all tuples starting with
+//! it. This holds for stores ordered with the default `Bytewise` comparator.
+//!
+//! ```rust
+//! # use kvs::keys::{self, Element, Tuple};
+//! # fn try_main() -> kvs::Result<()> {
+//! let key = Tuple::new().push_str("user").push_u64(42).into_key();
+//! assert!(key < Tuple::new().push_str("user").push_u64(100).into_key());
+//! assert!(key.starts_with(Tuple::new().push_str("user").as_str()));
+//! assert_eq!(
+//!     keys::decode(&key)?,
+//!     vec![Element::Str("user".to_owned()), Element::U64(42)]
+//! );
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! Elements are encoded as a tag followed by the value. Strings end with `\0`, and a
+//! `\0` in a string is escaped as `\0ÿ`, which sorts after the end of a string.
+//! Numbers are written with 20 decimal digits, so that they sort by value.
+
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::{KvsError, Result};
+
+const STR: char = 's';
+const TIMESTAMP: char = 't';
+const U64: char = 'u';
+const END: char = '\0';
+const ESCAPE: char = 'ÿ';
+const DIGITS: usize = 20;
+
+/// A tuple encoded into a key.
+#[derive(Clone, Debug, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Tuple {
+    key: String,
+}
+
+impl Tuple {
+    /// Creates an empty tuple.
+    pub fn new() -> Tuple {
+        Tuple::default()
+    }
+
+    /// Appends a string.
+    pub fn push_str(mut self, s: &str) -> Tuple {
+        self.key.push(STR);
+        for c in s.chars() {
+            self.key.push(c);
+            if c == END {
+                self.key.push(ESCAPE);
+            }
+        }
+        self.key.push(END);
+        self
+    }
+
+    /// Appends an integer.
+    pub fn push_u64(mut self, n: u64) -> Tuple {
+        self.key.push(U64);
+        self.push_number(n);
+        self
+    }
+
+    /// Appends a point in time with nanosecond precision.
+    ///
+    /// Times before the Unix epoch are encoded as the epoch.
+    pub fn push_timestamp(mut self, time: SystemTime) -> Tuple {
+        let nanos = time
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_nanos()
+            .min(u128::from(u64::MAX)) as u64;
+        self.key.push(TIMESTAMP);
+        self.push_number(nanos);
+        self
+    }
+
+    /// Appends an element.
+    pub fn push(self, element: &Element) -> Tuple {
+        match element {
+            Element::Str(s) => self.push_str(s),
+            Element::U64(n) => self.push_u64(*n),
+            Element::Timestamp(time) => self.push_timestamp(*time),
+        }
+    }
+
+    /// Returns the encoded key.
+    pub fn as_str(&self) -> &str {
+        &self.key
+    }
+
+    /// Returns the encoded key.
+    pub fn into_key(self) -> String {
+        self.key
+    }
+
+    fn push_number(&mut self, n: u64) {
+        self.key.push_str(&format!("{:0width$}", n, width = DIGITS));
+    }
+}
+
+impl From<Tuple> for String {
+    fn from(tuple: Tuple) -> String {
+        tuple.into_key()
+    }
+}
+
+/// An element of a tuple.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Element {
+    /// A string.
+    Str(String),
+    /// An integer.
+    U64(u64),
+    /// A point in time.
+    Timestamp(SystemTime),
+}
+
+/// Decodes the elements of a key built with [`Tuple`].
+///
+/// # Errors
+///
+/// It returns `KvsError::InvalidTuple` if the key is not an encoded tuple.
+pub fn decode(key: &str) -> Result<Vec<Element>> {
+    let mut elements = Vec::new();
+    let mut chars = key.chars();
+    while let Some(tag) = chars.next() {
+        let element = match tag {
+            STR => {
+                let mut s = String::new();
+                loop {
+                    match chars.next() {
+                        Some(END) if chars.as_str().starts_with(ESCAPE) => {
+                            chars.next();
+                            s.push(END);
+                        }
+                        Some(END) => break,
+                        Some(c) => s.push(c),
+                        None => return Err(invalid(key)),
+                    }
+                }
+                Element::Str(s)
+            }
+            U64 => Element::U64(decode_number(&mut chars).ok_or_else(|| invalid(key))?),
+            TIMESTAMP => {
+                let nanos = decode_number(&mut chars).ok_or_else(|| invalid(key))?;
+                Element::Timestamp(UNIX_EPOCH + Duration::from_nanos(nanos))
+            }
+            _ => return Err(invalid(key)),
+        };
+        elements.push(element);
+    }
+    Ok(elements)
+}
+
+fn decode_number(chars: &mut std::str::Chars) -> Option<u64> {
+    let digits = chars.as_str().get(..DIGITS)?;
+    if !digits.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    let n = digits.parse().ok()?;
+    *chars = chars.as_str()[DIGITS..].chars();
+    Some(n)
+}
+
+fn invalid(key: &str) -> KvsError {
+    KvsError::InvalidTuple(key.to_owned())
+}
@@ -1,16 +1,62 @@
-use std::collections::{BTreeMap, HashMap};
+use std::borrow::Cow;
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::fs::{self, File, OpenOptions};
-use std::io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
-use std::ops::Range;
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::ops::{Bound, Range, RangeBounds};
 use std::path::{Path, PathBuf};
 
+use log::warn;
 use serde::{Deserialize, Serialize};
 use serde_json::Deserializer;
 
-use crate::{KvsError, Result};
+use crate::compaction::{CompactionMode, CompactionStats, LoadMeter, Never, RateLimiter};
+use crate::comparator::{self, Bytewise, Comparator};
+use crate::crc::Crc32;
+use crate::expiry::ExpiryIndex;
+use crate::hint::{self, Hint};
+use crate::index::Index;
+use crate::lru::Lru;
+use crate::manifest::{Manifest, Segment, StoreMeta};
+use crate::metrics::{Counter, Gauge, Operation};
+use crate::prefix::{self, PrefixConfig};
+use crate::prepared::{self, PreparedToken};
+use crate::random::{self, Rng};
+use crate::record::{self, Command, Record, SetValue};
+use crate::shm::{self, SharedIndex};
+use crate::trace::{Op, Recorder};
+use crate::warm;
+use crate::{
+    HotSet, KeyPattern, KvsError, Options, Recovery, Result, Stats, VectorClock, VerifyLevel,
+    WriteBatch,
+};
+use std::cmp::{Ordering, Reverse};
 use std::ffi::OsStr;
+use std::iter::Peekable;
+use std::mem;
+use std::panic;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
-const COMPACTION_THRESHOLD: u64 = 1024 * 1024;
+/// Directory of corrupt data skipped with `Recovery::Quarantine`.
+const QUARANTINE_DIR: &str = "quarantine";
+const LOCK: &str = "LOCK";
+// the log of stores written by versions before log files were numbered.
+const LEGACY_LOG: &str = "kv.log";
+// written once a legacy log is migrated, so that the migration isn't repeated.
+const MIGRATED: &str = "MIGRATED";
+// how often a `KvReader` refresh is retried when racing with a compaction.
+const REFRESH_ATTEMPTS: usize = 10;
+// records read in log order after which a log reader reads ahead.
+const SEQUENTIAL_READS: u32 = 4;
+// buffer sizes of log readers, without and with read-ahead.
+const BUFFER: usize = 8 * 1024;
+const READ_AHEAD: usize = 256 * 1024;
+// bytes read between checks of the deadline of `get_with_deadline`.
+const DEADLINE_CHUNK: u64 = 64 * 1024;
+// the delay of writes just below the stop limit of `Options::write_stall`.
+const MAX_STALL: Duration = Duration::from_millis(10);
 
 /// The `KvStore` stores string key/value pairs.
 ///
@@ -37,74 +83,3620 @@ pub struct KvStore {
     // writer of the current log.
     writer: BufWriterWithPos<File>,
     current_gen: u64,
-    index: BTreeMap<String, CommandPos>,
-    // the number of bytes representing "stale" commands that could be
-    // deleted during a compaction.
-    uncompacted: u64,
+    index: Index<CommandPos>,
+    // expiration times of keys with a TTL.
+    expiry: ExpiryIndex,
+    // map generation number to the number of bytes representing "stale" commands
+    // that could be deleted during a compaction.
+    stale: BTreeMap<u64, StaleBytes>,
+    // the total number of bytes of all log files.
+    total: u64,
+    last_compaction: Instant,
+    // the rate of reads and writes seen by compaction policies.
+    load: LoadMeter,
+    // lengths and checksums of the log files.
+    manifest: Manifest,
+    options: Options,
+    stats: Stats,
+    // length of the current log before a failed write, while the store is read-only.
+    read_only: Option<u64>,
+    // the lock file held with `Options::exclusive`.
+    _lock: Option<File>,
+    // the index published to reader processes with `Options::shared_index`.
+    shared: Option<SharedIndex>,
+    // the recorder of `Options::record_trace`.
+    trace: Option<Recorder>,
+    // the recency of keys with `Options::cache_capacity`.
+    lru: Option<Lru>,
+    // batches prepared with `prepare` that are not committed or rolled back yet, by id.
+    prepared: BTreeMap<String, WriteBatch>,
+    // shared with the live snapshots, which compactions must not reclaim data of.
+    snapshots: Arc<()>,
+    // the reads of keys counted for `HotSet::Frequent`.
+    reads: Option<HashMap<String, u64>>,
 }
 
 impl KvStore {
     /// Opens a `KvStore` with the given path.
     ///
-    /// This will create a new directory if the given one does not exist.
+    /// This will create a new directory if the given one does not exist. The single
+    /// `kv.log` of a store written by an older version is migrated to a numbered log
+    /// file the first time it is opened.
     ///
     /// # Errors
     ///
     /// It propagates I/O or deserialization errors during the log replay.
     pub fn open(path: impl Into<PathBuf>) -> Result<KvStore> {
+        KvStore::open_with_options(path, Options::default())
+    }
+
+    /// Opens a `KvStore` with the given path and options.
+    ///
+    /// This will create a new directory if the given one does not exist.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::CorruptedLog` if a log file does not match the manifest.
+    ///
+    /// It propagates I/O or deserialization errors during the log replay.
+    pub fn open_with_options(path: impl Into<PathBuf>, options: Options) -> Result<KvStore> {
+        KvStore::open_and_report(path.into(), options).map(|(store, _)| store)
+    }
+
+    /// Rebuilds the store in the given directory from whatever can be read of it.
+    ///
+    /// Every readable record of every log file is replayed, skipping corrupt data as
+    /// with `Recovery::Quarantine` and ignoring the manifest. The salvaged entries are
+    /// then compacted into a fresh log.
+    ///
+    /// Returns a report of the data that could not be salvaged.
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O errors during reading and rewriting the log.
+    pub fn repair(path: impl Into<PathBuf>) -> Result<RepairReport> {
+        let options = Options::new()
+            .verify(VerifyLevel::None)
+            .recovery(Recovery::Quarantine);
+        let (mut store, lost) = KvStore::open_and_report(path.into(), options)?;
+        store.compact()?;
+        Ok(RepairReport {
+            lost,
+            keys: store.index.len(),
+        })
+    }
+
+    /// Copies the store to the given directory as a backup, which
+    /// [`restore`](KvStore::restore) recreates the store from.
+    ///
+    /// The log files are copied as of the call, together with the prepared batches and
+    /// hint files for the log files without stale commands, so that the restored store
+    /// only reads the log files a compaction would rewrite. The manifest is copied
+    /// last, so that `restore` rejects a backup cut short. The directory is created if
+    /// it does not exist.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::DirectoryNotEmpty` if the directory has files.
+    ///
+    /// It propagates I/O errors during writing out buffered writes and copying.
+    pub fn backup(&mut self, dest: impl AsRef<Path>) -> Result<()> {
+        let dest = dest.as_ref();
+        create_empty_dir(dest)?;
+        self.writer.flush()?;
+        let mut manifest = self.manifest.clone();
+        manifest.insert(self.current_gen, self.writer.segment());
+        let mut hints: BTreeMap<u64, Vec<Hint>> = BTreeMap::new();
+        for (key, cmd_pos) in self.index.iter() {
+            hints.entry(cmd_pos.gen).or_default().push(Hint {
+                key: key.to_owned(),
+                pos: cmd_pos.pos,
+                len: cmd_pos.len,
+                expires_at: self.expiry.get(key),
+            });
+        }
+        for &gen in self.readers.keys() {
+            let src = log_path(&self.path, gen);
+            let len = match manifest.get(gen) {
+                Some(segment) => segment.len,
+                None => fs::metadata(&src)?.len(),
+            };
+            copy_file(&src, &log_path(dest, gen), len)?;
+            let hints = hints.remove(&gen).unwrap_or_default();
+            // a log file with stale commands is read on open instead, so that they are
+            // counted towards the compaction debt of the restored store.
+            let stale = self.stale.get(&gen).copied().unwrap_or_default();
+            if stale.overwritten + stale.tombstones == 0 {
+                hint::write(dest, gen, len, &hints)?;
+            }
+        }
+        for (id, batch) in &self.prepared {
+            prepared::save(dest, id, batch)?;
+        }
+        manifest.save(dest)
+    }
+
+    /// Recreates a store in the given directory from a backup made by
+    /// [`backup`](KvStore::backup), to be opened like any other store.
+    ///
+    /// The log files of the backup are checked against its manifest before they are
+    /// copied. The directory is created if it does not exist.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::InvalidBackup` if the backup has no manifest,
+    /// `KvsError::CorruptedLog` if a log file of the backup is missing or was modified,
+    /// and `KvsError::DirectoryNotEmpty` if the directory has files.
+    ///
+    /// It propagates I/O errors during copying.
+    pub fn restore(src: impl AsRef<Path>, dest: impl AsRef<Path>) -> Result<()> {
+        let (src, dest) = (src.as_ref(), dest.as_ref());
+        if !Manifest::exists(src) {
+            return Err(KvsError::InvalidBackup(src.display().to_string()));
+        }
+        verify(src, &sorted_gen_list(src)?, VerifyLevel::Full)?;
+        create_empty_dir(dest)?;
+        copy_dir(src, dest)
+    }
+
+    /// Opens a `KvStore`, also returning the corrupt data skipped in quarantine mode.
+    fn open_and_report(path: PathBuf, options: Options) -> Result<(KvStore, Vec<LostData>)> {
+        create_store_dir(&path)?;
+        let lock = lock(&path, options.exclusive)?;
+        migrate_legacy_log(&path)?;
+
+        let mut gen_list = sorted_gen_list(&path)?;
+        let garbage = collect_garbage(&path, &mut gen_list, options.verify)?;
+        truncate_torn_tail(&path, &gen_list)?;
+        let mut manifest = verify(&path, &gen_list, options.verify)?;
+        let comparator = store_comparator(&path, &gen_list, &mut manifest, &options)?;
+        let normalized = store_normalization(&path, &gen_list, &mut manifest, &options)?;
+        if manifest.identify(gen_list.is_empty()) {
+            manifest.save(&path)?;
+        }
+        let quarantine = quarantine_dir(&path, options.recovery);
+        let Replayed {
+            mut readers,
+            mut index,
+            expiry,
+            stale,
+            total,
+            lost,
+            committed,
+        } = replay(&path, &gen_list, quarantine.as_deref(), comparator)?;
+        index.set_normalized(normalized);
+        let prepared = prepared::recover(&path, &committed)?;
+
+        let current_gen = gen_list.last().unwrap_or(&0) + 1;
+        let writer = new_current_log(&path, current_gen, &mut readers, &options)?;
+        let trace = options
+            .trace
+            .as_deref()
+            .map(|path| Recorder::create(path, options.redactor.clone()))
+            .transpose()?;
+
+        let mut store = KvStore {
+            path,
+            readers,
+            writer,
+            current_gen,
+            index,
+            expiry,
+            stale,
+            total,
+            last_compaction: Instant::now(),
+            load: LoadMeter::new(),
+            manifest,
+            options,
+            stats: Stats::default(),
+            read_only: None,
+            _lock: lock,
+            shared: None,
+            trace,
+            lru: None,
+            prepared,
+            snapshots: Arc::new(()),
+            reads: None,
+        };
+        store.count(Counter::Corrupt, lost.iter().map(|data| data.len).sum());
+        store.count(Counter::Reclaimed, garbage);
+        store.reset_lru();
+        store.warm()?;
+        store.share_index()?;
+        Ok((store, lost))
+    }
+
+    /// Opens a `KvStore` without waiting for its index to be rebuilt.
+    ///
+    /// The existing log files are replayed by a background thread. Meanwhile the
+    /// returned [`LazyKvStore`] serves writes, and reads of keys written since it was
+    /// opened. Compaction is deferred until the index is rebuilt.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::CorruptedLog` if a log file does not match the manifest.
+    ///
+    /// It propagates I/O errors during creating the current log file.
+    pub fn open_lazy(path: impl Into<PathBuf>, options: Options) -> Result<LazyKvStore> {
         let path = path.into();
-        fs::create_dir_all(&path)?;
+        create_store_dir(&path)?;
+        let lock = lock(&path, options.exclusive)?;
+        migrate_legacy_log(&path)?;
+
+        let mut gen_list = sorted_gen_list(&path)?;
+        let garbage = collect_garbage(&path, &mut gen_list, options.verify)?;
+        truncate_torn_tail(&path, &gen_list)?;
+        let mut manifest = verify(&path, &gen_list, options.verify)?;
+        let comparator = store_comparator(&path, &gen_list, &mut manifest, &options)?;
+        let normalized = store_normalization(&path, &gen_list, &mut manifest, &options)?;
+        if manifest.identify(gen_list.is_empty()) {
+            manifest.save(&path)?;
+        }
+        let current_gen = gen_list.last().unwrap_or(&0) + 1;
+        let mut readers = HashMap::new();
+        let writer = new_current_log(&path, current_gen, &mut readers, &options)?;
+        let trace = options
+            .trace
+            .as_deref()
+            .map(|path| Recorder::create(path, options.redactor.clone()))
+            .transpose()?;
+
+        // the shared index is created once the index is rebuilt.
+        SharedIndex::discard(&path);
+        let replay_path = path.clone();
+        let quarantine = quarantine_dir(&path, options.recovery);
+        let mut index = Index::new(comparator.clone());
+        index.set_normalized(normalized);
+        let handle = thread::spawn(move || {
+            replay(&replay_path, &gen_list, quarantine.as_deref(), comparator)
+        });
+
+        let mut store = KvStore {
+            path,
+            readers,
+            writer,
+            current_gen,
+            index,
+            expiry: ExpiryIndex::default(),
+            stale: BTreeMap::new(),
+            total: 0,
+            last_compaction: Instant::now(),
+            load: LoadMeter::new(),
+            manifest,
+            options: options.clone().compaction_policy(Never),
+            stats: Stats::default(),
+            read_only: None,
+            _lock: lock,
+            shared: None,
+            trace,
+            lru: None,
+            // read once the log is replayed, which tells which of them were committed.
+            prepared: BTreeMap::new(),
+            snapshots: Arc::new(()),
+            reads: None,
+        };
+        store.count(Counter::Reclaimed, garbage);
+        Ok(LazyKvStore {
+            store,
+            options,
+            replay: Some(handle),
+            failed: false,
+        })
+    }
+
+    /// Merges the entries replayed from the existing log files with the entries
+    /// written since opening, which take precedence.
+    fn merge_replayed(&mut self, replayed: Replayed) -> Result<()> {
+        let Replayed {
+            readers,
+            mut index,
+            mut expiry,
+            mut stale,
+            total,
+            lost,
+            committed,
+        } = replayed;
+        self.count(Counter::Corrupt, lost.iter().map(|data| data.len).sum());
+        index.set_normalized(self.index.is_normalized());
+        let written = mem::replace(&mut self.index, Index::new(None));
+        for (key, cmd_pos) in written.into_iter() {
+            let expires_at = self.expiry.get(&key);
+            // the key may have been written with another spelling before opening.
+            let key = index.resolve(key);
+            expiry.set(&key, expires_at);
+            if let Some(old_cmd) = index.insert(key, cmd_pos) {
+                stale.entry(old_cmd.gen).or_default().overwritten += old_cmd.len;
+            }
+        }
+        for (gen, bytes) in mem::take(&mut self.stale) {
+            let stale = stale.entry(gen).or_default();
+            stale.overwritten += bytes.overwritten;
+            stale.tombstones += bytes.tombstones;
+        }
+        self.readers.extend(readers);
+        self.index = index;
+        self.expiry = expiry;
+        self.stale = stale;
+        self.total += total;
+        self.prepared = prepared::recover(&self.path, &committed)?;
+        self.reset_lru();
+        self.warm()?;
+        self.share_index()
+    }
+
+    /// Sets the value of a string key to a string.
+    ///
+    /// If the key already exists, the previous value will be overwritten.
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O or serialization errors during writing the log.
+    pub fn set(&mut self, key: String, value: String) -> Result<()> {
+        if let Some(trace) = &self.trace {
+            trace.record(Op::Set {
+                key: key.clone(),
+                value: value.clone(),
+            });
+        }
+        self.set_with_clock(key, value, VectorClock::new())
+    }
+
+    /// Sets the value of a string key along with the vector clock of the update.
+    ///
+    /// This is used when applying replicated writes so that the causal history of the
+    /// value can be inspected later with [`get_with_meta`](KvStore::get_with_meta).
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O or serialization errors during writing the log.
+    pub fn set_with_clock(&mut self, key: String, value: String, clock: VectorClock) -> Result<()> {
+        self.write_set(key, value, clock, None)
+    }
+
+    /// Sets the value of a string key that expires after the given TTL.
+    ///
+    /// Expired keys are treated as non-existent and are dropped by the next compaction.
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O or serialization errors during writing the log.
+    pub fn set_with_ttl(&mut self, key: String, value: String, ttl: Duration) -> Result<()> {
+        let expires_at = now_millis() + ttl.as_millis() as u64;
+        self.write_set(key, value, VectorClock::new(), Some(expires_at))
+    }
+
+    fn write_set(
+        &mut self,
+        key: String,
+        value: String,
+        clock: VectorClock,
+        expires_at: Option<u64>,
+    ) -> Result<()> {
+        let start = Instant::now();
+        self.stall()?;
+        let cmd = self.set_command(key, value, clock, expires_at)?;
+        let range = self.append(&cmd)?;
+        self.index_set(cmd, range)?;
+        self.evict()?;
+        self.maybe_compact()?;
+        self.observe(Operation::Set, start);
+        Ok(())
+    }
+
+    /// Builds the "set" command of a key, applying the limits and default TTL of its
+    /// prefix.
+    fn set_command(
+        &self,
+        key: String,
+        value: String,
+        clock: VectorClock,
+        mut expires_at: Option<u64>,
+    ) -> Result<Command> {
+        let key = self.index.resolve(key);
+        if let Some(config) = prefix::lookup(self.manifest.prefixes(), &key) {
+            if let Some(limit) = config.value_limit() {
+                if value.len() > limit {
+                    return Err(KvsError::ValueTooLarge { key, limit });
+                }
+            }
+            if let (None, Some(ttl)) = (expires_at, config.default_ttl()) {
+                expires_at = Some(now_millis() + ttl.as_millis() as u64);
+            }
+        }
+        Ok(Command::Set {
+            key,
+            value,
+            clock,
+            ts: Some(now_millis()),
+            expires_at,
+        })
+    }
+
+    /// Points the index at a "set" command written at the given range of the current
+    /// log.
+    fn index_set(&mut self, cmd: Command, range: Range<u64>) -> Result<()> {
+        if let Command::Set {
+            key, expires_at, ..
+        } = cmd
+        {
+            let cmd_pos = (self.current_gen, range).into();
+            self.expiry.set(&key, expires_at);
+            self.publish(&key, Some(cmd_pos))?;
+            if let Some(lru) = &mut self.lru {
+                lru.touch(&key);
+            }
+            if let Some(old_cmd) = self.index.insert(key, cmd_pos) {
+                self.stale.entry(old_cmd.gen).or_default().overwritten += old_cmd.len;
+            }
+        }
+        Ok(())
+    }
+
+    /// Starts a transaction, which is written to the log as a single record when it
+    /// commits.
+    pub fn begin(&mut self) -> Transaction<'_> {
+        Transaction {
+            store: self,
+            batch: WriteBatch::new(),
+        }
+    }
+
+    /// Applies the writes of a batch atomically.
+    ///
+    /// The writes are appended to the log as a single checksummed record and synced to
+    /// disk before `write` returns, which guarantees that:
+    ///
+    /// - once `write` returns, all of the writes survive a crash;
+    /// - after a crash during `write`, either all or none of them are read back. A
+    ///   batch cut short at the end of the log is dropped when the store is opened,
+    ///   and a corrupt one is skipped as a whole with `Recovery::Quarantine`;
+    /// - compactions, including those triggered by the batch, only copy the current
+    ///   values of keys, so a crash during a compaction never exposes part of a batch.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::KeyNotFound` if the batch removes a key that doesn't
+    /// exist when the removal is applied, and `KvsError::ValueTooLarge` if a value is
+    /// longer than the limit of its prefix. Nothing is written then.
+    ///
+    /// It propagates I/O or serialization errors during writing the log.
+    pub fn write(&mut self, batch: WriteBatch) -> Result<()> {
+        self.write_batch(batch, None)
+    }
+
+    /// Validates a batch and persists it without applying it, for an external
+    /// transaction coordinator to [`commit`](KvStore::commit) or
+    /// [`rollback`](KvStore::rollback) it later.
+    ///
+    /// Prepared batches survive restarts until they are committed or rolled back, and
+    /// the tokens of those pending after a restart are listed by
+    /// [`prepared`](KvStore::prepared). They don't lock their keys: writes in the
+    /// meantime are overwritten by the commit as if the batch was written then.
+    ///
+    /// # Errors
+    ///
+    /// It fails like [`KvStore::write`] if the batch couldn't be written now, in which
+    /// case nothing is prepared.
+    ///
+    /// It propagates I/O or serialization errors during writing the prepared batch.
+    pub fn prepare(&mut self, batch: WriteBatch) -> Result<PreparedToken> {
+        self.check_writable()?;
+        self.batch_commands(batch.writes.clone(), false)?;
+        let id = random::uuid();
+        prepared::save(&self.path, &id, &batch)?;
+        self.prepared.insert(id.clone(), batch);
+        Ok(PreparedToken::from_id(id))
+    }
+
+    /// Applies the writes of a prepared batch atomically, like [`KvStore::write`].
+    ///
+    /// Removals of keys that no longer exist are skipped, so that the commit doesn't
+    /// depend on writes since the batch was prepared. After a crash during `commit`,
+    /// the batch is either applied or still pending, never both.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::NotPrepared` if the batch is not pending, because it was
+    /// committed or rolled back already.
+    ///
+    /// It fails like [`KvStore::write`] otherwise, in which case the batch stays
+    /// pending.
+    pub fn commit(&mut self, token: &PreparedToken) -> Result<()> {
+        let batch = match self.prepared.get(token.id()) {
+            Some(batch) => batch.clone(),
+            None => return Err(KvsError::NotPrepared(token.id().to_owned())),
+        };
+        self.write_batch(batch, Some(token.id()))?;
+        self.prepared.remove(token.id());
+        prepared::remove(&self.path, token.id())?;
+        // the batch record tells whether it was committed until the file is removed.
+        self.maybe_compact()
+    }
+
+    /// Discards a prepared batch.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::NotPrepared` if the batch is not pending, because it was
+    /// committed or rolled back already.
+    ///
+    /// It propagates I/O errors during removing the prepared batch.
+    pub fn rollback(&mut self, token: &PreparedToken) -> Result<()> {
+        if !self.prepared.contains_key(token.id()) {
+            return Err(KvsError::NotPrepared(token.id().to_owned()));
+        }
+        prepared::remove(&self.path, token.id())?;
+        self.prepared.remove(token.id());
+        Ok(())
+    }
+
+    /// Returns the tokens of the prepared batches that are not committed or rolled back
+    /// yet, ordered by id.
+    pub fn prepared(&self) -> Vec<PreparedToken> {
+        self.prepared
+            .keys()
+            .map(|id| PreparedToken::from_id(id.clone()))
+            .collect()
+    }
+
+    /// Writes a batch, committing the prepared batch with the given id if any.
+    fn write_batch(&mut self, batch: WriteBatch, prepared: Option<&str>) -> Result<()> {
+        if let Some(trace) = &self.trace {
+            for (key, value) in &batch.writes {
+                trace.record(match value {
+                    Some(value) => Op::Set {
+                        key: key.clone(),
+                        value: value.clone(),
+                    },
+                    None => Op::Remove { key: key.clone() },
+                });
+            }
+        }
+        if batch.is_empty() {
+            return Ok(());
+        }
+        let start = Instant::now();
+        self.stall()?;
+        let cmds = self.batch_commands(batch.writes, prepared.is_some())?;
+        let meta = prepared.map(prepared::meta).unwrap_or_default();
+        let (buf, ranges) = record::encode_batch(&cmds, &meta)?;
+        let range = self.append_bytes(&buf)?;
+        self.writer.flush()?;
+        self.writer.writer.get_ref().sync_data()?;
+        let mut len = 0;
+        for (cmd, cmd_range) in cmds.into_iter().zip(ranges) {
+            len += cmd_range.end - cmd_range.start;
+            let cmd_range = range.start + cmd_range.start..range.start + cmd_range.end;
+            match cmd {
+                Command::Remove { key } => {
+                    let old_cmd = self.index.remove(&key).expect("key not found");
+                    self.expiry.remove(&key);
+                    if let Some(lru) = &mut self.lru {
+                        lru.remove(&key);
+                    }
+                    self.publish(&key, None)?;
+                    self.stale.entry(old_cmd.gen).or_default().overwritten += old_cmd.len;
+                    self.stale.entry(self.current_gen).or_default().tombstones +=
+                        cmd_range.end - cmd_range.start;
+                }
+                cmd => self.index_set(cmd, cmd_range)?,
+            }
+        }
+        // the header of the batch can be deleted in the next compaction.
+        self.stale.entry(self.current_gen).or_default().tombstones += range.end - range.start - len;
+        self.evict()?;
+        // a committed batch is compacted once its prepared batch is removed.
+        if prepared.is_none() {
+            self.maybe_compact()?;
+        }
+        self.observe(Operation::Set, start);
+        Ok(())
+    }
+
+    /// Returns the commands of the writes of a batch.
+    ///
+    /// Removals of keys that don't exist fail with `KvsError::KeyNotFound`, or are
+    /// skipped with `skip_missing`.
+    fn batch_commands(
+        &self,
+        writes: Vec<(String, Option<String>)>,
+        skip_missing: bool,
+    ) -> Result<Vec<Command>> {
+        // whether the keys exist after the writes of the batch so far.
+        let mut exists = HashMap::new();
+        let mut cmds = Vec::with_capacity(writes.len());
+        for (key, value) in writes {
+            let key = self.index.resolve(key);
+            let cmd = match value {
+                Some(value) => self.set_command(key, value, VectorClock::new(), None)?,
+                None => {
+                    if !exists
+                        .get(&key)
+                        .copied()
+                        .unwrap_or_else(|| self.contains(&key))
+                    {
+                        if skip_missing {
+                            continue;
+                        }
+                        return Err(KvsError::KeyNotFound);
+                    }
+                    Command::remove(key)
+                }
+            };
+            if let Command::Set { key, .. } | Command::Remove { key } = &cmd {
+                exists.insert(key.clone(), matches!(cmd, Command::Set { .. }));
+            }
+            cmds.push(cmd);
+        }
+        Ok(cmds)
+    }
+
+    /// Gets the string value of a given string key.
+    ///
+    /// Returns `None` if the given key does not exist.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::UnexpectedCommandType` if the given command type unexpected.
+    pub fn get(&mut self, key: String) -> Result<Option<String>> {
+        if let Some(trace) = &self.trace {
+            trace.record(Op::Get { key: key.clone() });
+        }
+        Ok(self.get_with_meta(key)?.map(|(value, _)| value))
+    }
+
+    /// Gets the string value of a given string key together with its metadata.
+    ///
+    /// Returns `None` if the given key does not exist.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::UnexpectedCommandType` if the given command type unexpected.
+    ///
+    /// It returns `KvsError::StaleIndex` if the index entry points into a log file
+    /// that has been compacted away.
+    pub fn get_with_meta(&mut self, key: String) -> Result<Option<(String, EntryMeta)>> {
+        let start = Instant::now();
+        let result = self.read(&key);
+        self.observe(Operation::Get, start);
+        result
+    }
+
+    fn read(&mut self, key: &str) -> Result<Option<(String, EntryMeta)>> {
+        let resolved = self.index.resolve_str(key);
+        let key: &str = &resolved;
+        if self.expiry.is_expired(key, now_millis()) {
+            return Ok(None);
+        }
+        if let Some(cmd_pos) = self.index.get(key) {
+            if let Some(lru) = &mut self.lru {
+                lru.touch(key);
+            }
+            if let Some(reads) = &mut self.reads {
+                match reads.get_mut(key) {
+                    Some(count) => *count += 1,
+                    None => {
+                        reads.insert(key.to_owned(), 1);
+                    }
+                }
+            }
+            let record = match self.writer.buffered(cmd_pos.pos, cmd_pos.len) {
+                Some(buf) if cmd_pos.gen == self.current_gen => decode(buf, cmd_pos)?,
+                _ => read_record(&mut self.readers, cmd_pos)?,
+            };
+            if let Command::Set { value, clock, .. } = record.cmd {
+                let expires_at = self.expiry.get(key).map(system_time);
+                Ok(Some((value, EntryMeta { clock, expires_at })))
+            } else {
+                Err(KvsError::UnexpectedCommandType)
+            }
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// Gets the string value of a given string key, giving up once `deadline` passes.
+    ///
+    /// The log record is read in chunks and the deadline is checked before each of
+    /// them, so a large value read from a slow disk is abandoned part way.
+    ///
+    /// Returns `None` if the given key does not exist.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::DeadlineExceeded` if the deadline passes before the value
+    /// is read.
+    pub fn get_with_deadline(&mut self, key: String, deadline: Instant) -> Result<Option<String>> {
+        if let Some(trace) = &self.trace {
+            trace.record(Op::Get { key: key.clone() });
+        }
+        let start = Instant::now();
+        let result = self.read_before(&key, deadline);
+        self.observe(Operation::Get, start);
+        result
+    }
+
+    fn read_before(&mut self, key: &str, deadline: Instant) -> Result<Option<String>> {
+        if Instant::now() >= deadline {
+            return Err(KvsError::DeadlineExceeded);
+        }
+        let resolved = self.index.resolve_str(key);
+        let key: &str = &resolved;
+        if self.expiry.is_expired(key, now_millis()) {
+            return Ok(None);
+        }
+        let cmd_pos = match self.index.get(key) {
+            Some(cmd_pos) => cmd_pos,
+            None => return Ok(None),
+        };
+        if let Some(lru) = &mut self.lru {
+            lru.touch(key);
+        }
+        let mut buf = Vec::with_capacity(cmd_pos.len as usize);
+        match self.writer.buffered(cmd_pos.pos, cmd_pos.len) {
+            Some(buffered) if cmd_pos.gen == self.current_gen => buf.extend_from_slice(buffered),
+            _ => {
+                let reader = self
+                    .readers
+                    .get_mut(&cmd_pos.gen)
+                    .ok_or(KvsError::StaleIndex(cmd_pos.gen))?;
+                reader.seek_record(cmd_pos.pos)?;
+                while (buf.len() as u64) < cmd_pos.len {
+                    if Instant::now() >= deadline {
+                        return Err(KvsError::DeadlineExceeded);
+                    }
+                    let chunk = DEADLINE_CHUNK.min(cmd_pos.len - buf.len() as u64);
+                    if reader.take(chunk).read_to_end(&mut buf)? == 0 {
+                        return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+                    }
+                }
+            }
+        }
+        match decode(&buf, cmd_pos)?.cmd {
+            Command::Set { value, .. } => Ok(Some(value)),
+            _ => Err(KvsError::UnexpectedCommandType),
+        }
+    }
+
+    /// Looks a key up for a read outside of the store, as by `SharedKvStore`.
+    ///
+    /// Returns the value itself if its record is still buffered.
+    fn locate(&mut self, key: &str) -> Result<Option<Located>> {
+        if let Some(trace) = &self.trace {
+            trace.record(Op::Get {
+                key: key.to_owned(),
+            });
+        }
+        let resolved = self.index.resolve_str(key);
+        let key: &str = &resolved;
+        if self.expiry.is_expired(key, now_millis()) {
+            return Ok(None);
+        }
+        let cmd_pos = match self.index.get(key) {
+            Some(cmd_pos) => *cmd_pos,
+            None => return Ok(None),
+        };
+        if let Some(lru) = &mut self.lru {
+            lru.touch(key);
+        }
+        match self.writer.buffered(cmd_pos.pos, cmd_pos.len) {
+            Some(buf) if cmd_pos.gen == self.current_gen => match decode(buf, &cmd_pos)?.cmd {
+                Command::Set { value, .. } => Ok(Some(Located::Value(value))),
+                _ => Err(KvsError::UnexpectedCommandType),
+            },
+            _ => Ok(Some(Located::At(cmd_pos))),
+        }
+    }
+
+    /// Gets the string value of a given string key without allocating a `String`.
+    ///
+    /// The log record is read into `buf`, which can be reused across calls, and the
+    /// returned value borrows from it unless it contains escaped characters.
+    ///
+    /// Returns `None` if the given key does not exist.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::UnexpectedCommandType` if the given command type unexpected.
+    pub fn get_in<'a>(&mut self, key: &str, buf: &'a mut Vec<u8>) -> Result<Option<Cow<'a, str>>> {
+        let start = Instant::now();
+        let result = self.read_in(key, buf);
+        self.observe(Operation::Get, start);
+        result
+    }
+
+    fn read_in<'a>(&mut self, key: &str, buf: &'a mut Vec<u8>) -> Result<Option<Cow<'a, str>>> {
+        let resolved = self.index.resolve_str(key);
+        let key: &str = &resolved;
+        if self.expiry.is_expired(key, now_millis()) {
+            return Ok(None);
+        }
+        let cmd_pos = match self.index.get(key) {
+            Some(cmd_pos) => cmd_pos,
+            None => return Ok(None),
+        };
+        if let Some(lru) = &mut self.lru {
+            lru.touch(key);
+        }
+        buf.clear();
+        match self.writer.buffered(cmd_pos.pos, cmd_pos.len) {
+            Some(buffered) if cmd_pos.gen == self.current_gen => buf.extend_from_slice(buffered),
+            _ => {
+                let reader = self
+                    .readers
+                    .get_mut(&cmd_pos.gen)
+                    .ok_or(KvsError::StaleIndex(cmd_pos.gen))?;
+                reader.seek_record(cmd_pos.pos)?;
+                reader.take(cmd_pos.len).read_to_end(buf)?;
+            }
+        }
+        let buf: &'a [u8] = trim_start(buf);
+        if record::is_binary(buf) {
+            return match record::set_value(buf).map_err(|reason| corruption(cmd_pos, reason))? {
+                Some(value) => Ok(Some(Cow::Borrowed(value))),
+                None => Err(KvsError::UnexpectedCommandType),
+            };
+        }
+        check(buf, cmd_pos)?;
+        match serde_json::from_slice(buf)? {
+            SetValue { ty, value } if ty == "Set" => Ok(Some(value)),
+            // untagged records of older versions.
+            _ => match serde_json::from_slice::<Record>(buf)?.cmd {
+                Command::Set { value, .. } => Ok(Some(Cow::Owned(value))),
+                _ => Err(KvsError::UnexpectedCommandType),
+            },
+        }
+    }
+
+    /// Removes a given key.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::KeyNotFound` if the given key is not found.
+    ///
+    /// It propagates I/O or serialization errors during writing the log.
+    pub fn remove(&mut self, key: String) -> Result<()> {
+        if let Some(trace) = &self.trace {
+            trace.record(Op::Remove { key: key.clone() });
+        }
+        if self.contains(&key) {
+            let start = Instant::now();
+            self.stall()?;
+            self.remove_existing(key)?;
+            self.maybe_compact()?;
+            self.observe(Operation::Remove, start);
+            Ok(())
+        } else {
+            Err(KvsError::KeyNotFound)
+        }
+    }
+
+    /// Sets the value of a string key and returns its previous value.
+    ///
+    /// Like [`set`](KvStore::set), this clears any TTL of the key.
+    ///
+    /// This is a [`get`](KvStore::get) followed by a `set`, not one logged operation:
+    /// only the new value is written to the log, and a trace records the two steps
+    /// separately. Nothing else writes the store in between, but a crash after the
+    /// call can lose the new value while the caller holds the old one.
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O or serialization errors during writing the log.
+    pub fn get_and_set(&mut self, key: String, value: String) -> Result<Option<String>> {
+        let old_value = self.get(key.clone())?;
+        self.set(key, value)?;
+        Ok(old_value)
+    }
+
+    /// Removes a given key and returns its value.
+    ///
+    /// Returns `None` without writing anything if the given key does not exist.
+    ///
+    /// Like [`get_and_set`](KvStore::get_and_set), this is a read followed by a removal
+    /// rather than one logged operation, with the same guarantees.
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O or serialization errors during writing the log.
+    pub fn get_and_remove(&mut self, key: String) -> Result<Option<String>> {
+        let value = self.get(key.clone())?;
+        if value.is_some() {
+            if let Some(trace) = &self.trace {
+                trace.record(Op::Remove { key: key.clone() });
+            }
+            self.stall()?;
+            self.remove_existing(key)?;
+            self.maybe_compact()?;
+        }
+        Ok(value)
+    }
+
+    /// Sets the value of a key to `new`, or removes it if `new` is `None`, only if
+    /// its current value is `expected`, where `None` means the key does not exist.
+    ///
+    /// Returns `Err` with the current value without writing anything if it is not
+    /// `expected`. Like [`set`](KvStore::set), a write clears any TTL of the key.
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O or serialization errors during writing the log.
+    pub fn compare_and_swap(
+        &mut self,
+        key: String,
+        expected: Option<String>,
+        new: Option<String>,
+    ) -> Result<std::result::Result<(), Option<String>>> {
+        let current = self.get(key.clone())?;
+        if current != expected {
+            return Ok(Err(current));
+        }
+        match new {
+            Some(value) => self.set(key, value)?,
+            None if current.is_some() => {
+                if let Some(trace) = &self.trace {
+                    trace.record(Op::Remove { key: key.clone() });
+                }
+                self.stall()?;
+                self.remove_existing(key)?;
+                self.maybe_compact()?;
+            }
+            None => {}
+        }
+        Ok(Ok(()))
+    }
+
+    /// Adds `delta` to the integer value of a key and returns the new value.
+    ///
+    /// A missing key is created with the value `delta`, as if it was 0. Unlike
+    /// [`set`](KvStore::set), the key keeps its TTL.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::NotAnInteger` if the value is not a decimal integer, and
+    /// `KvsError::Overflow` if the new value doesn't fit in an `i64`. Nothing is
+    /// written then.
+    ///
+    /// It propagates I/O or serialization errors during writing the log.
+    pub fn incr(&mut self, key: String, delta: i64) -> Result<i64> {
+        let key = self.index.resolve(key);
+        let (value, expires_at) = match self.get(key.clone())? {
+            Some(value) => match value.parse::<i64>() {
+                Ok(value) => (value, self.expiry.get(&key)),
+                Err(_) => return Err(KvsError::NotAnInteger(key)),
+            },
+            None => (0, None),
+        };
+        let value = value
+            .checked_add(delta)
+            .ok_or_else(|| KvsError::Overflow(key.clone()))?;
+        if let Some(trace) = &self.trace {
+            trace.record(Op::Set {
+                key: key.clone(),
+                value: value.to_string(),
+            });
+        }
+        self.write_set(key, value.to_string(), VectorClock::new(), expires_at)?;
+        Ok(value)
+    }
+
+    /// Renames a given key, overwriting the value of `new_key` if it exists.
+    ///
+    /// The value keeps its TTL and vector clock. The rename is written to the log as
+    /// a single command.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::KeyNotFound` if the given key is not found.
+    ///
+    /// It propagates I/O or serialization errors during writing the log.
+    pub fn rename(&mut self, key: String, new_key: String) -> Result<()> {
+        let key = self.index.resolve(key);
+        let new_key = self.index.resolve(new_key);
+        if !self.contains(&key) {
+            return Err(KvsError::KeyNotFound);
+        }
+        if key != new_key {
+            self.write_rename(key, new_key)?;
+        }
+        Ok(())
+    }
+
+    /// Renames a given key only if `new_key` does not exist.
+    ///
+    /// Returns `false` without writing anything if `new_key` exists.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::KeyNotFound` if the given key is not found.
+    ///
+    /// It propagates I/O or serialization errors during writing the log.
+    pub fn rename_if_absent(&mut self, key: String, new_key: String) -> Result<bool> {
+        let key = self.index.resolve(key);
+        let new_key = self.index.resolve(new_key);
+        if !self.contains(&key) {
+            return Err(KvsError::KeyNotFound);
+        }
+        if self.contains(&new_key) {
+            return Ok(false);
+        }
+        self.write_rename(key, new_key)?;
+        Ok(true)
+    }
+
+    /// Copies the value of a given key to `dst`, together with its TTL and vector clock.
+    ///
+    /// If `dst` exists, it is overwritten only if `overwrite` is `true`. Returns
+    /// whether the value was copied.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::KeyNotFound` if the given key is not found.
+    ///
+    /// It propagates I/O or serialization errors during writing the log.
+    pub fn copy(&mut self, key: String, dst: String, overwrite: bool) -> Result<bool> {
+        let key = self.index.resolve(key);
+        let dst = self.index.resolve(dst);
+        let (value, meta) = self
+            .get_with_meta(key.clone())?
+            .ok_or(KvsError::KeyNotFound)?;
+        if !overwrite && self.contains(&dst) {
+            return Ok(false);
+        }
+        if key != dst {
+            let expires_at = self.expiry.get(&key);
+            self.write_set(dst, value, meta.clock, expires_at)?;
+        }
+        Ok(true)
+    }
+
+    fn write_rename(&mut self, key: String, new_key: String) -> Result<()> {
+        self.stall()?;
+        let cmd = Command::Rename { key, new_key };
+        let range = self.append(&cmd)?;
+        if let Command::Rename { key, new_key } = cmd {
+            let cmd_pos = self.index.remove(&key).expect("key not found");
+            let expires_at = self.expiry.get(&key);
+            self.expiry.remove(&key);
+            self.expiry.set(&new_key, expires_at);
+            if let Some(lru) = &mut self.lru {
+                lru.remove(&key);
+                lru.touch(&new_key);
+            }
+            self.publish(&key, None)?;
+            self.publish(&new_key, Some(cmd_pos))?;
+            if let Some(old_cmd) = self.index.insert(new_key, cmd_pos) {
+                self.stale.entry(old_cmd.gen).or_default().overwritten += old_cmd.len;
+            }
+        }
+        // the "rename" command can be deleted once the "set" command is compacted.
+        self.stale.entry(self.current_gen).or_default().tombstones += range.end - range.start;
+        self.maybe_compact()
+    }
+
+    /// Returns the latency histograms of the operations and the bytes written since
+    /// opening or the last [`reset_stats`](KvStore::reset_stats), together with the
+    /// current compaction debt.
+    pub fn stats(&self) -> Stats {
+        let mut stats = self.stats.clone();
+        stats.compaction_debt = self.compaction_debt();
+        stats
+    }
+
+    /// Applies the given settings to the keys starting with `prefix`, replacing any
+    /// previous settings of the prefix.
+    ///
+    /// The settings are recorded in the store and apply to later writes. A key is
+    /// governed by the longest configured prefix it starts with.
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O errors during recording the settings.
+    pub fn configure_prefix(&mut self, prefix: &str, config: PrefixConfig) -> Result<()> {
+        self.manifest
+            .prefixes_mut()
+            .insert(prefix.to_owned(), config);
+        self.manifest.save(&self.path)
+    }
+
+    /// Removes the settings of a prefix, returning them.
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O errors during recording the settings.
+    pub fn remove_prefix_config(&mut self, prefix: &str) -> Result<Option<PrefixConfig>> {
+        let config = self.manifest.prefixes_mut().remove(prefix);
+        if config.is_some() {
+            self.manifest.save(&self.path)?;
+        }
+        Ok(config)
+    }
+
+    /// Returns the settings applying to a key, if any.
+    pub fn prefix_config(&self, key: &str) -> Option<&PrefixConfig> {
+        prefix::lookup(self.manifest.prefixes(), key)
+    }
+
+    /// Creates an independent copy of the store in `dest`, which must not contain a
+    /// store yet.
+    ///
+    /// The current log is closed first, so that each store continues with a log of
+    /// its own. Closed log files are never modified in place, so they are hard-linked
+    /// into `dest` where the file system allows it, which makes forking cheap even for
+    /// large stores. The copy gets a new UUID.
+    ///
+    /// # Errors
+    ///
+    /// It returns an I/O error of kind `AlreadyExists` if `dest` contains log files.
+    ///
+    /// It propagates I/O errors during linking or copying the log files.
+    pub fn fork(&mut self, dest: impl AsRef<Path>) -> Result<()> {
+        let dest = dest.as_ref();
+        create_store_dir(dest)?;
+        if !sorted_gen_list(dest)?.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("{} already contains a store", dest.display()),
+            )
+            .into());
+        }
+        self.start_log(self.current_gen + 1)?;
+        for gen in self.manifest.gens() {
+            let (src, dst) = (log_path(&self.path, gen), log_path(dest, gen));
+            if fs::hard_link(&src, &dst).is_err() {
+                fs::copy(&src, &dst)?;
+            }
+        }
+        self.manifest.fork().save(dest)
+    }
+
+    /// Returns an iterator over the differences between this store and `other`, in key
+    /// order.
+    ///
+    /// Keys only in `other` are `Change::Added`, keys only in this store are
+    /// `Change::Removed`. Only values are compared, not TTLs or vector clocks.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::ComparatorMismatch` if the stores order keys differently.
+    pub fn diff<'a>(&'a mut self, other: &'a mut KvStore) -> Result<Diff<'a>> {
+        let comparator = other.meta().comparator;
+        if self.meta().comparator != comparator {
+            return Err(KvsError::ComparatorMismatch(comparator));
+        }
+        let flushed = self.writer.flush();
+        let left = Scan {
+            entries: Box::new(self.index.iter()),
+            readers: &mut self.readers,
+            expiry: &self.expiry,
+            now: now_millis(),
+            error: flushed.err().map(KvsError::from),
+        };
+        Ok(Diff {
+            index: &self.index,
+            left: left.peekable(),
+            right: other.scan().peekable(),
+        })
+    }
+
+    /// Returns the identity of the store: its UUID, creation time and comparator.
+    pub fn meta(&self) -> StoreMeta {
+        self.manifest.meta()
+    }
+
+    /// Clears the latency histograms and byte counts.
+    pub fn reset_stats(&mut self) {
+        self.stats = Stats::default();
+    }
+
+    /// Writes the writes buffered with [`Options::write_buffer`] to the log.
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O errors during writing the log.
+    pub fn flush(&mut self) -> Result<()> {
+        Ok(self.writer.flush()?)
+    }
+
+    /// Returns whether the store is read-only because writing to the log failed.
+    ///
+    /// Once a write fails, for example because the disk is full, writes and
+    /// compactions fail with `KvsError::ReadOnly` instead of appending to a log that
+    /// may end with a partial record. Reads are still served.
+    pub fn is_read_only(&self) -> bool {
+        self.read_only.is_some()
+    }
+
+    /// Allows writes again after the cause of a failed write has been fixed.
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O errors during reopening the current log and removing the
+    /// partial record from it, in which case the store stays read-only.
+    pub fn clear_read_only(&mut self) -> Result<()> {
+        if let Some(len) = self.read_only {
+            let path = log_path(&self.path, self.current_gen);
+            self.writer.truncate(&path, len)?;
+            self.readers
+                .insert(self.current_gen, BufReaderWithPos::new(File::open(&path)?)?);
+            self.read_only = None;
+        }
+        Ok(())
+    }
+
+    /// Fails with `KvsError::ReadOnly` if writes are disabled.
+    fn check_writable(&self) -> Result<()> {
+        match self.read_only {
+            Some(_) => Err(KvsError::ReadOnly),
+            None => Ok(()),
+        }
+    }
+
+    /// Fails if live snapshots keep compactions from reclaiming the log.
+    fn check_unpinned(&self) -> Result<()> {
+        match self.snapshot_count() {
+            0 => Ok(()),
+            count => Err(KvsError::Pinned(count)),
+        }
+    }
+
+    /// Slows a write down or rejects it while the compaction debt exceeds the limits
+    /// of `Options::write_stall`.
+    fn stall(&mut self) -> Result<()> {
+        let (slowdown, stop) = match self.options.write_stall {
+            Some(limits) => limits,
+            None => return Ok(()),
+        };
+        let debt = self.compaction_debt();
+        if debt >= stop {
+            self.stats.rejected_writes += 1;
+            return Err(KvsError::Busy);
+        }
+        if debt >= slowdown {
+            // the delay grows linearly from nothing at the slowdown limit.
+            let excess = (debt - slowdown) as f64 / (stop - slowdown) as f64;
+            let delay = MAX_STALL.mul_f64(excess);
+            thread::sleep(delay);
+            self.stats.stalled_writes += 1;
+            self.stats.stall_time += delay;
+        }
+        Ok(())
+    }
+
+    /// Returns the remaining time to live of a given key.
+    ///
+    /// Returns `None` if the key does not expire.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::KeyNotFound` if the given key is not found.
+    pub fn ttl(&self, key: String) -> Result<Option<Duration>> {
+        let key = self.index.resolve(key);
+        if !self.contains(&key) {
+            return Err(KvsError::KeyNotFound);
+        }
+        let now = now_millis();
+        Ok(self
+            .expiry
+            .get(&key)
+            .map(|expires_at| Duration::from_millis(expires_at.saturating_sub(now))))
+    }
+
+    /// Removes all expired keys from the index and writes "remove" commands for them.
+    ///
+    /// Expired keys are found through an index ordered by expiration time, so the cost
+    /// is proportional to the number of expired keys rather than all keys. The work
+    /// done by a call is bounded by [`Options::max_purge`], and keys are only purged
+    /// after the [`Options::purge_jitter`] derived from them has passed.
+    ///
+    /// Returns the number of removed keys.
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O or serialization errors during writing the log.
+    pub fn purge_expired(&mut self) -> Result<usize> {
+        let jitter = self.options.purge_jitter.as_millis() as u64;
+        let expired = self
+            .expiry
+            .expired(now_millis(), jitter, self.options.max_purge);
+        let count = expired.len();
+        for key in expired {
+            self.stats.purged_keys += 1;
+            self.stats.purged_bytes += self.index.get(&key).map_or(0, |cmd_pos| cmd_pos.len);
+            self.remove_existing(key)?;
+        }
+        self.maybe_compact()?;
+        Ok(count)
+    }
+
+    /// Sets the TTL of an existing key, replacing any previous TTL.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::KeyNotFound` if the given key is not found.
+    ///
+    /// It propagates I/O or serialization errors during writing the log.
+    pub fn expire(&mut self, key: String, ttl: Duration) -> Result<()> {
+        let expires_at = now_millis() + ttl.as_millis() as u64;
+        self.write_expire(key, Some(expires_at))
+    }
+
+    /// Removes the TTL of a given key so that it never expires.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::KeyNotFound` if the given key is not found.
+    ///
+    /// It propagates I/O or serialization errors during writing the log.
+    pub fn persist(&mut self, key: String) -> Result<()> {
+        self.write_expire(key, None)
+    }
+
+    fn write_expire(&mut self, key: String, expires_at: Option<u64>) -> Result<()> {
+        let key = self.index.resolve(key);
+        if !self.contains(&key) {
+            return Err(KvsError::KeyNotFound);
+        }
+        if self.expiry.get(&key) == expires_at {
+            return Ok(());
+        }
+        self.stall()?;
+        let cmd = Command::Expire { key, expires_at };
+        let range = self.append(&cmd)?;
+        if let Command::Expire { key, .. } = cmd {
+            self.expiry.set(&key, expires_at);
+            let cmd_pos = self.index.get(&key).cloned();
+            self.publish(&key, cmd_pos)?;
+        }
+        // the "expire" command can be deleted once the "set" command is compacted.
+        self.stale.entry(self.current_gen).or_default().tombstones += range.end - range.start;
+        self.maybe_compact()
+    }
+
+    /// Returns the number of keys starting with the given prefix.
+    ///
+    /// This is answered from the ordered index without reading any values.
+    pub fn count_prefix(&self, prefix: &str) -> usize {
+        self.prefix_keys(prefix, None).count()
+    }
+
+    /// Returns the live keys matching the given pattern in order.
+    ///
+    /// Only the keys starting with the literal prefix of the pattern are visited.
+    pub fn keys_matching(&self, pattern: &KeyPattern) -> Vec<String> {
+        self.matching_keys(pattern, None)
+            .map(str::to_owned)
+            .collect()
+    }
+
+    /// Returns a page of up to `limit` live keys matching the given pattern that come
+    /// after `after` in key order, or from the first key if it is `None`.
+    ///
+    /// The next page starts after the last key of this one. Each page seeks to its
+    /// first key in the ordered index, so its cost doesn't grow with the number of
+    /// pages before it.
+    pub fn keys_page(
+        &self,
+        pattern: &KeyPattern,
+        after: Option<&str>,
+        limit: usize,
+    ) -> Vec<String> {
+        self.matching_keys(pattern, after)
+            .take(limit)
+            .map(str::to_owned)
+            .collect()
+    }
+
+    /// Returns the number of live keys matching the given pattern.
+    pub fn count_matching(&self, pattern: &KeyPattern) -> usize {
+        self.matching_keys(pattern, None).count()
+    }
+
+    /// Removes the keys matching the given pattern and returns how many were removed.
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O or serialization errors during writing the log.
+    pub fn remove_matching(&mut self, pattern: &KeyPattern) -> Result<usize> {
+        let keys = self.keys_matching(pattern);
+        let count = keys.len();
+        if count > 0 {
+            self.stall()?;
+        }
+        for key in keys {
+            let start = Instant::now();
+            self.remove_existing(key)?;
+            self.observe(Operation::Remove, start);
+        }
+        self.maybe_compact()?;
+        Ok(count)
+    }
+
+    fn matching_keys<'a>(
+        &'a self,
+        pattern: &'a KeyPattern,
+        after: Option<&'a str>,
+    ) -> impl Iterator<Item = &'a str> + 'a {
+        self.prefix_keys(pattern.prefix(), after)
+            .filter(move |key| pattern.matches(key))
+    }
+
+    /// Returns the live keys starting with the given prefix in order, after the given
+    /// key if any.
+    ///
+    /// Only bytewise ordered keys starting with a prefix are next to each other, so with
+    /// another comparator the whole index after the given key is scanned.
+    fn prefix_keys<'a>(
+        &'a self,
+        prefix: &'a str,
+        after: Option<&'a str>,
+    ) -> Box<dyn Iterator<Item = &'a str> + 'a> {
+        let now = now_millis();
+        let keys: Box<dyn Iterator<Item = &'a str>> = if self.index.is_bytewise() {
+            let start = match after {
+                Some(after) if after >= prefix => Bound::Excluded(after),
+                _ => Bound::Included(prefix),
+            };
+            Box::new(
+                self.index
+                    .range(start, Bound::Unbounded)
+                    .map(|(key, _)| key)
+                    .take_while(move |key| key.starts_with(prefix)),
+            )
+        } else {
+            let start = after.map_or(Bound::Unbounded, Bound::Excluded);
+            Box::new(
+                self.index
+                    .range(start, Bound::Unbounded)
+                    .map(|(key, _)| key)
+                    .filter(move |key| key.starts_with(prefix)),
+            )
+        };
+        Box::new(keys.filter(move |key| !self.expiry.is_expired(key, now)))
+    }
+
+    /// Aggregates the live keys by their prefixes of up to `depth` components
+    /// separated by `sep`, like `du` does for directories.
+    ///
+    /// Every key counts towards each of its prefixes, and a key with fewer components
+    /// is its own prefix at the deeper levels. The prefixes are returned in order.
+    ///
+    /// This is answered from the index without reading any values.
+    pub fn usage(&self, sep: &str, depth: usize) -> Vec<PrefixUsage> {
+        let now = now_millis();
+        let mut usage: BTreeMap<&str, PrefixUsage> = BTreeMap::new();
+        for (key, cmd_pos) in self.index.iter() {
+            if self.expiry.is_expired(key, now) {
+                continue;
+            }
+            let ends = key
+                .match_indices(sep)
+                .map(|(i, _)| i)
+                .filter(|_| !sep.is_empty())
+                .chain(Some(key.len()))
+                .take(depth);
+            for end in ends {
+                let prefix = usage.entry(&key[..end]).or_insert_with(|| PrefixUsage {
+                    prefix: key[..end].to_owned(),
+                    keys: 0,
+                    bytes: 0,
+                });
+                prefix.keys += 1;
+                prefix.bytes += cmd_pos.len;
+            }
+        }
+        usage.into_values().collect()
+    }
+
+    /// Returns a key chosen uniformly at random, or `None` if the store is empty.
+    pub fn random_key(&self) -> Option<String> {
+        self.sample(1).pop()
+    }
+
+    /// Returns up to `n` distinct keys chosen uniformly at random.
+    ///
+    /// This walks the whole index once, without reading any values.
+    pub fn sample(&self, n: usize) -> Vec<String> {
+        let mut rng = Rng::new();
+        let now = now_millis();
+        let mut sample = Vec::with_capacity(n);
+        let live_keys = self
+            .index
+            .keys()
+            .filter(|key| !self.expiry.is_expired(key, now));
+        // reservoir sampling.
+        for (i, key) in live_keys.enumerate() {
+            if i < n {
+                sample.push(key.to_owned());
+            } else {
+                let j = rng.below(i as u64 + 1) as usize;
+                if j < n {
+                    sample[j] = key.to_owned();
+                }
+            }
+        }
+        sample
+    }
+
+    /// Returns an iterator over the live entries in key order.
+    ///
+    /// Values are read from the log as the iterator advances, and it can be reversed to
+    /// start from the last key.
+    pub fn scan(&mut self) -> Scan<'_> {
+        self.range(..)
+    }
+
+    /// Returns an iterator over the live entries with keys in the given range, in key
+    /// order.
+    ///
+    /// Like [`scan`](KvStore::scan), it can be reversed, so the latest entries of
+    /// timestamp-ordered keys are returned by `range(start..end).rev().take(n)`. A range
+    /// whose start is after its end is empty.
+    pub fn range(&mut self, range: impl RangeBounds<String>) -> Scan<'_> {
+        // the entries are read from the log files.
+        let flushed = self.writer.flush();
+        Scan {
+            entries: range_entries(&self.index, range),
+            readers: &mut self.readers,
+            expiry: &self.expiry,
+            now: now_millis(),
+            error: flushed.err().map(KvsError::from),
+        }
+    }
+
+    /// Returns an iterator over the live entries from the given key on, in key order.
+    ///
+    /// `scan_from(start).take(n)` returns a page of entries; the next page is returned
+    /// by `range((Bound::Excluded(last), Bound::Unbounded))` with the last key of it.
+    pub fn scan_from(&mut self, start: &str) -> Scan<'_> {
+        self.range(start.to_owned()..)
+    }
+
+    /// Returns an iterator over the live entries with keys starting with the given
+    /// prefix, in key order.
+    ///
+    /// Only bytewise ordered keys starting with a prefix are next to each other, so with
+    /// another comparator the whole index is scanned.
+    pub fn scan_prefix(&mut self, prefix: &str) -> Scan<'_> {
+        let flushed = self.writer.flush();
+        Scan {
+            entries: prefix_entries(&self.index, prefix),
+            readers: &mut self.readers,
+            expiry: &self.expiry,
+            now: now_millis(),
+            error: flushed.err().map(KvsError::from),
+        }
+    }
+
+    /// Returns a read-only view of the store as of now, which writes made afterwards
+    /// don't change.
+    ///
+    /// The snapshot has its own copy of the index and handles of the log files, so it
+    /// can be read while the store is written. Keys expire in it as of the time it was
+    /// taken. Compaction is deferred while snapshots are live, so the log records they
+    /// point to are never reclaimed.
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O errors during writing out buffered writes and opening the log
+    /// files.
+    pub fn snapshot(&mut self) -> Result<Snapshot> {
+        Ok(Snapshot {
+            readers: self.open_readers()?,
+            index: self.index.clone(),
+            expiry: self.expiry.clone(),
+            now: now_millis(),
+            _pin: Arc::clone(&self.snapshots),
+        })
+    }
+
+    /// Returns the number of live snapshots taken with [`snapshot`](KvStore::snapshot).
+    pub fn snapshot_count(&self) -> usize {
+        Arc::strong_count(&self.snapshots) - 1
+    }
+
+    /// Returns a reader of the store as of now, which can be moved to another thread to
+    /// read without going through the store.
+    ///
+    /// The reader has a copy of the index and its own handles of the log files, and
+    /// sees later writes once [`refresh`](KvReader::refresh)ed, like a reader opened
+    /// with [`KvReader::open`]. More readers sharing the copy are returned by
+    /// [`try_clone`](KvReader::try_clone), which is cheaper than calling `reader` again.
+    ///
+    /// Unlike a [`Snapshot`], a reader doesn't defer compactions. It rebuilds its index
+    /// on the next refresh after a compaction.
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O errors during writing out buffered writes and opening the log
+    /// files.
+    pub fn reader(&mut self) -> Result<KvReader> {
+        let readers = self.open_readers()?;
+        let mut scanned = BTreeMap::new();
+        for (&gen, reader) in &readers {
+            let pos = if gen == self.current_gen {
+                self.writer.pos
+            } else {
+                reader.reader.get_ref().metadata()?.len()
+            };
+            scanned.insert(gen, pos);
+        }
+        Ok(KvReader {
+            path: self.path.clone(),
+            readers,
+            scanned,
+            index: Arc::new(self.index.clone()),
+            expiry: Arc::new(self.expiry.clone()),
+            shared: None,
+        })
+    }
+
+    /// Opens new handles of the log files, writing out buffered writes for them first.
+    fn open_readers(&mut self) -> Result<HashMap<u64, BufReaderWithPos<File>>> {
+        self.writer.flush()?;
+        let mut readers = HashMap::new();
+        for &gen in self.readers.keys() {
+            let reader = BufReaderWithPos::new(File::open(log_path(&self.path, gen))?)?;
+            readers.insert(gen, reader);
+        }
+        Ok(readers)
+    }
+
+    /// Returns the first live key in key order, or `None` if the store is empty.
+    pub fn first_key(&self) -> Option<String> {
+        let now = now_millis();
+        self.index
+            .keys()
+            .find(|key| !self.expiry.is_expired(key, now))
+            .map(str::to_owned)
+    }
+
+    /// Returns the last live key in key order, or `None` if the store is empty.
+    pub fn last_key(&self) -> Option<String> {
+        let now = now_millis();
+        self.index
+            .keys()
+            .rev()
+            .find(|key| !self.expiry.is_expired(key, now))
+            .map(str::to_owned)
+    }
+
+    /// Returns `true` if the key exists and has not expired.
+    fn contains(&self, key: &str) -> bool {
+        let resolved = self.index.resolve_str(key);
+        let key: &str = &resolved;
+        self.index.contains_key(key) && !self.expiry.is_expired(key, now_millis())
+    }
+
+    /// Writes a "remove" command for a key in the index and removes it from the index.
+    fn remove_existing(&mut self, key: String) -> Result<()> {
+        let key = self.index.resolve(key);
+        let cmd = Command::remove(key);
+        let range = self.append(&cmd)?;
+        if let Command::Remove { key } = cmd {
+            let old_cmd = self.index.remove(&key).expect("key not found");
+            self.expiry.remove(&key);
+            if let Some(lru) = &mut self.lru {
+                lru.remove(&key);
+            }
+            self.publish(&key, None)?;
+            self.stale.entry(old_cmd.gen).or_default().overwritten += old_cmd.len;
+            // the "remove" command itself can be deleted in the next compaction.
+            self.stale.entry(self.current_gen).or_default().tombstones += range.end - range.start;
+        }
+        Ok(())
+    }
+
+    /// Appends a command to the current log.
+    ///
+    /// Returns the position range of the serialized command.
+    fn append(&mut self, cmd: &Command) -> Result<Range<u64>> {
+        let buf = record::encode(cmd, &BTreeMap::new())?;
+        self.append_bytes(&buf)
+    }
+
+    /// Appends serialized records to the current log.
+    ///
+    /// Returns the position range of the records.
+    fn append_bytes(&mut self, buf: &[u8]) -> Result<Range<u64>> {
+        self.check_writable()?;
+        if let Some(max_log_size) = self.options.max_log_size {
+            if self.writer.pos >= max_log_size && self.writer.pos > 0 {
+                self.start_log(self.current_gen + 1)?;
+            }
+        }
+        let pos = self.writer.pos;
+        let flushed = pos - self.writer.writer.buffer().len() as u64;
+        let buffer = self.options.write_buffer;
+        let written = self.writer.write_all(buf).and_then(|()| {
+            if self.writer.writer.buffer().len() >= buffer {
+                self.writer.flush()?;
+            }
+            Ok(())
+        });
+        if let Err(e) = written {
+            warn!(
+                "switching to read-only after failing to write the log: {}",
+                e
+            );
+            // buffered records are lost with the failed one. Any part of them is
+            // removed again when writes are allowed.
+            self.read_only = Some(flushed);
+            let _ = self
+                .writer
+                .truncate(&log_path(&self.path, self.current_gen), flushed);
+            return Err(e.into());
+        }
+        self.total += self.writer.pos - pos;
+        self.count(Counter::Written, self.writer.pos - pos);
+        Ok(pos..self.writer.pos)
+    }
+
+    /// Clears stale entries in the log.
+    ///
+    /// Compaction is triggered automatically according to the configured
+    /// [`CompactionPolicy`](crate::compaction::CompactionPolicy).
+    pub fn compact(&mut self) -> Result<()> {
+        self.check_writable()?;
+        self.check_unpinned()?;
+        let start = Instant::now();
+        let old_total = self.total;
+        // increase current gen by 2. current_gen + 1 is for the compaction file.
+        let compaction_gen = self.current_gen + 1;
+        self.start_log(self.current_gen + 2)?;
+
+        let mut compaction_writer = self.new_log_file(compaction_gen)?;
+        let mut limiter = RateLimiter::new(self.options.compaction_rate_limit);
+        let now = now_millis();
+
+        let mut new_pos = 0; // pos in the new log file.
+        let mut expired = Vec::new();
+        let mut hints = Vec::new();
+        for (key, cmd_pos) in self.index.iter_mut() {
+            let reader = self
+                .readers
+                .get_mut(&cmd_pos.gen)
+                .expect("Cannot find log reader");
+            if self.expiry.is_expired(key, now) {
+                expired.push(key.to_owned());
+                continue;
+            }
+
+            let (entry, ts) = read_entry(reader, key, cmd_pos, self.expiry.get(key))?;
+            limiter.consume(cmd_pos.len);
+            if let Some(retention) = &self.options.retention {
+                if retention.expired(key, ts, now) {
+                    expired.push(key.to_owned());
+                    continue;
+                }
+            }
+
+            compaction_writer.write_all(&entry)?;
+            let len = entry.len() as u64;
+            *cmd_pos = (compaction_gen, new_pos..new_pos + len).into();
+            hints.push(Hint {
+                key: key.to_owned(),
+                pos: new_pos,
+                len,
+                expires_at: self.expiry.get(key),
+            });
+            new_pos += len;
+        }
+        compaction_writer.flush()?;
+        hint::write(&self.path, compaction_gen, new_pos, &hints)?;
+        self.manifest
+            .insert(compaction_gen, compaction_writer.segment());
+        for key in expired {
+            self.index.remove(&key);
+            self.expiry.remove(&key);
+        }
+        // readers have to find the entries in the compaction file before the stale
+        // log files are removed.
+        self.share_index()?;
+
+        // remove stale log files.
+        let stale_gens: Vec<_> = self
+            .readers
+            .keys()
+            .filter(|&&gen| gen < compaction_gen)
+            .cloned()
+            .collect();
+        for &stale_gen in &stale_gens {
+            self.manifest.remove(stale_gen);
+        }
+        self.manifest.save(&self.path)?;
+        for stale_gen in stale_gens {
+            self.readers.remove(&stale_gen);
+            hint::remove(&self.path, stale_gen)?;
+            fs::remove_file(log_path(&self.path, stale_gen))?;
+        }
+        self.stale.clear();
+        self.total = new_pos;
+        self.count(Counter::Compacted, new_pos);
+        self.count(Counter::Reclaimed, old_total.saturating_sub(new_pos));
+        self.last_compaction = Instant::now();
+        self.observe(Operation::Compaction, start);
+        self.report_debt();
+
+        Ok(())
+    }
+
+    /// Compacts a single log file instead of the whole log.
+    ///
+    /// The log file is chosen according to the configured
+    /// [`CompactionMode`](crate::compaction::CompactionMode), falling back to the oldest
+    /// one with `CompactionMode::Full`. Only log files with stale commands are chosen,
+    /// unless a retention policy is configured.
+    /// If the current log is the only one with stale commands, a new log is started so
+    /// that it can be compacted.
+    ///
+    /// Live entries are moved to the current log, so the work done and the extra disk
+    /// space used are bounded by the size of the chosen log file.
+    ///
+    /// With `CompactionMode::SizeTiered` and `CompactionMode::Leveled`, the log files
+    /// the layout calls for are merged into a new log file instead, if any.
+    pub fn compact_step(&mut self) -> Result<()> {
+        self.check_writable()?;
+        self.check_unpinned()?;
+        let merge = match self.options.compaction_mode {
+            CompactionMode::SizeTiered { min_files } => Some(self.size_tiered(min_files)),
+            CompactionMode::Leveled { base, multiplier } => Some(self.leveled(base, multiplier)),
+            _ => None,
+        };
+        let start = Instant::now();
+        if let Some(gens) = merge {
+            if !gens.is_empty() {
+                self.merge(&gens)?;
+                self.last_compaction = Instant::now();
+                self.observe(Operation::Compaction, start);
+            }
+            return Ok(());
+        }
+
+        // with a retention policy, any log file may contain expired entries.
+        let retention = self.options.retention.is_some();
+        let candidates = self
+            .readers
+            .keys()
+            .filter(|&&gen| gen != self.current_gen && (retention || self.reclaimable(gen) > 0));
+        let gen = match self.options.compaction_mode {
+            CompactionMode::MostStaleSegment => candidates
+                .max_by_key(|&&gen| self.reclaimable(gen))
+                .cloned(),
+            _ => candidates.min().cloned(),
+        };
+        let gen = match gen {
+            Some(gen) => gen,
+            None if self.reclaimable(self.current_gen) > 0 => {
+                let gen = self.current_gen;
+                self.start_log(gen + 1)?;
+                gen
+            }
+            None => return Ok(()),
+        };
+        self.compact_segment(gen)?;
+        self.last_compaction = Instant::now();
+        self.observe(Operation::Compaction, start);
+        Ok(())
+    }
+
+    /// Returns the log files of the smallest size tier with at least `min_files` of them.
+    fn size_tiered(&self, min_files: usize) -> Vec<u64> {
+        let mut tiers: BTreeMap<u32, Vec<u64>> = BTreeMap::new();
+        for (gen, len) in self.sealed_logs() {
+            // log files with the same number of significant bits differ in size by less
+            // than a factor of two.
+            tiers.entry(64 - len.leading_zeros()).or_default().push(gen);
+        }
+        tiers
+            .into_values()
+            .find(|gens| gens.len() >= min_files.max(2))
+            .unwrap_or_default()
+    }
+
+    /// Returns the log files of the lowest level exceeding its target size, together
+    /// with the log files of the next level.
+    fn leveled(&self, base: u64, multiplier: u64) -> Vec<u64> {
+        let multiplier = multiplier.max(2);
+        let target = |level: u32| base.saturating_mul(multiplier.saturating_pow(level));
+        // map levels to their total size and log files.
+        let mut levels: BTreeMap<u32, (u64, Vec<u64>)> = BTreeMap::new();
+        for (gen, len) in self.sealed_logs() {
+            let mut level = 0;
+            while len > target(level) {
+                level += 1;
+            }
+            let (size, gens) = levels.entry(level).or_default();
+            *size += len;
+            gens.push(gen);
+        }
+        let level = levels
+            .iter()
+            .find(|(&level, (size, _))| *size > target(level))
+            .map(|(&level, _)| level);
+        match level {
+            Some(level) => {
+                let mut gens = levels.remove(&level).unwrap_or_default().1;
+                gens.extend(levels.remove(&(level + 1)).unwrap_or_default().1);
+                gens.sort_unstable();
+                gens
+            }
+            None => Vec::new(),
+        }
+    }
+
+    /// Returns the generations and lengths of the log files other than the current one.
+    fn sealed_logs(&self) -> Vec<(u64, u64)> {
+        let mut logs: Vec<_> = self
+            .readers
+            .keys()
+            .filter(|&&gen| gen != self.current_gen)
+            .map(|&gen| (gen, self.manifest.get(gen).map_or(0, |segment| segment.len)))
+            .collect();
+        logs.sort_unstable();
+        logs
+    }
+
+    /// Merges the live entries of the given log files into a new log file.
+    fn merge(&mut self, gens: &[u64]) -> Result<()> {
+        // the live entries are moved to the current log, so a log started for them
+        // ends up holding exactly the merged entries.
+        self.start_log(self.current_gen + 1)?;
+        for &gen in gens {
+            self.compact_segment(gen)?;
+        }
+        self.start_log(self.current_gen + 1)
+    }
+
+    /// Compacts the log file of the given generation.
+    ///
+    /// Live "set" commands are appended to the current log. Other commands are kept
+    /// in place unless the log file is the oldest one, because they may still affect
+    /// commands in older log files.
+    fn compact_segment(&mut self, gen: u64) -> Result<()> {
+        let oldest = self.readers.keys().min() == Some(&gen);
+        let mut limiter = RateLimiter::new(self.options.compaction_rate_limit);
+        let now = now_millis();
+        // map positions of live "set" commands in the log file to their keys, which
+        // differ from the keys in the commands for renamed entries.
+        let mut live_at: HashMap<u64, String> = self
+            .index
+            .iter()
+            .filter(|(_, cmd_pos)| cmd_pos.gen == gen)
+            .map(|(key, cmd_pos)| (cmd_pos.pos, key.to_owned()))
+            .collect();
+        let reader = self.readers.get_mut(&gen).expect("Cannot find log reader");
+
+        let quarantine = quarantine_dir(&self.path, self.options.recovery);
+        let retention = &self.options.retention;
+        let expiry = &self.expiry;
+        let mut live = Vec::new();
+        let mut expired = Vec::new();
+        let mut tombstones = Vec::new();
+        // corrupt data was quarantined when the store was opened and is dropped here.
+        scan(gen, reader, quarantine.as_deref(), |record, range| {
+            match record.cmd {
+                Command::Set { ts, .. } => {
+                    if let Some(key) = live_at.remove(&range.start) {
+                        let retained = match retention {
+                            Some(retention) => !retention.expired(&key, ts, now),
+                            None => true,
+                        };
+                        if retained && !expiry.is_expired(&key, now) {
+                            live.push(key);
+                        } else {
+                            expired.push(key);
+                        }
+                    }
+                }
+                _ if !oldest => tombstones.push(range),
+                _ => {}
+            }
+            Ok(())
+        })?;
+        let old_len = reader.pos;
+        limiter.consume(old_len);
+
+        let mut relocated = 0;
+        let mut moved = Vec::with_capacity(live.len());
+        for key in live {
+            let cmd_pos = self.index.get_mut(&key).expect("key not found");
+            let (entry, _) = read_entry(reader, &key, cmd_pos, self.expiry.get(&key))?;
+            let new_pos = self.writer.pos;
+            self.writer.write_all(&entry)?;
+            *cmd_pos = (self.current_gen, new_pos..self.writer.pos).into();
+            self.total += cmd_pos.len;
+            relocated += cmd_pos.len;
+            limiter.consume(cmd_pos.len);
+            moved.push(key);
+        }
+        self.writer.flush()?;
+        for key in moved {
+            let cmd_pos = self.index.get(&key).cloned();
+            self.publish(&key, cmd_pos)?;
+        }
+        // older log files may still contain values of expired keys.
+        for key in expired {
+            if oldest {
+                self.index.remove(&key);
+                self.expiry.remove(&key);
+                self.publish(&key, None)?;
+            } else {
+                self.remove_existing(key)?;
+            }
+        }
+
+        self.readers.remove(&gen);
+        self.stale.remove(&gen);
+        self.total -= old_len;
+        hint::remove(&self.path, gen)?;
+        if tombstones.is_empty() {
+            self.manifest.remove(gen);
+            self.manifest.save(&self.path)?;
+            fs::remove_file(log_path(&self.path, gen))?;
+            self.count(Counter::Compacted, relocated);
+            self.count(Counter::Reclaimed, old_len.saturating_sub(relocated));
+            return Ok(());
+        }
+
+        // rewrite the kept commands to a temporary file which replaces the log file.
+        let mut reader = BufReaderWithPos::new(File::open(log_path(&self.path, gen))?)?;
+        let tmp_path = log_path(&self.path, gen).with_extension("log.tmp");
+        let mut tmp_writer = BufWriterWithPos::new(File::create(&tmp_path)?)?;
+        let mut new_len = 0;
+        for range in tombstones {
+            reader.seek(SeekFrom::Start(range.start))?;
+            let len = io::copy(
+                &mut (&mut reader).take(range.end - range.start),
+                &mut tmp_writer,
+            )?;
+            new_len += len;
+            limiter.consume(len);
+        }
+        tmp_writer.flush()?;
+        fs::rename(&tmp_path, log_path(&self.path, gen))?;
+        self.manifest.insert(gen, tmp_writer.segment());
+        self.manifest.save(&self.path)?;
+
+        self.readers.insert(
+            gen,
+            BufReaderWithPos::new(File::open(log_path(&self.path, gen))?)?,
+        );
+        self.stale.insert(
+            gen,
+            StaleBytes {
+                overwritten: 0,
+                tombstones: new_len,
+            },
+        );
+        self.total += new_len;
+        self.count(Counter::Compacted, relocated + new_len);
+        self.count(
+            Counter::Reclaimed,
+            old_len.saturating_sub(relocated + new_len),
+        );
+        Ok(())
+    }
+
+    /// Returns the number of bytes a compaction of the given log file would reclaim.
+    fn reclaimable(&self, gen: u64) -> u64 {
+        let stale = self.stale.get(&gen).cloned().unwrap_or_default();
+        if self.readers.keys().min() == Some(&gen) {
+            stale.overwritten + stale.tombstones
+        } else {
+            stale.overwritten
+        }
+    }
+
+    /// Consults the compaction policy without a write.
+    ///
+    /// The policy is otherwise only consulted after writes, so a compaction postponed
+    /// by `compaction::Adaptive` while the store was busy runs on the first call
+    /// after the store becomes idle.
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O or serialization errors during the compaction.
+    pub fn poll_compaction(&mut self) -> Result<()> {
+        self.check_writable()?;
+        self.maybe_compact()
+    }
+
+    /// Compacts the log if the compaction policy asks for it.
+    fn maybe_compact(&mut self) -> Result<()> {
+        let stats = CompactionStats {
+            uncompacted: self.compaction_debt(),
+            total: self.total,
+            last_compaction: self.last_compaction,
+            ops_per_sec: self.load.rate(),
+        };
+        // the compaction is deferred until the live snapshots are dropped.
+        if self.snapshot_count() == 0 && self.options.compaction_policy.should_compact(&stats) {
+            match self.options.compaction_mode {
+                CompactionMode::Full => self.compact()?,
+                _ => self.compact_step()?,
+            }
+        }
+        self.report_debt();
+        Ok(())
+    }
+
+    /// Returns the bytes of stale commands that a full compaction would reclaim.
+    fn compaction_debt(&self) -> u64 {
+        self.stale
+            .values()
+            .map(|stale| stale.overwritten + stale.tombstones)
+            .sum()
+    }
+
+    /// Rebuilds the recency of keys with `Options::cache_capacity`, taking the keys
+    /// written last as the most recently used.
+    fn reset_lru(&mut self) {
+        if self.options.cache_capacity.is_none() {
+            return;
+        }
+        let lru = self.lru.get_or_insert_with(Lru::default);
+        lru.clear();
+        let mut entries: Vec<_> = self.index.iter().collect();
+        entries.sort_by_key(|(_, cmd_pos)| (cmd_pos.gen, cmd_pos.pos));
+        for (key, _) in entries {
+            lru.touch(key);
+        }
+    }
+
+    /// Reads the log records of the hot set of `Options::warm`, so that the OS caches
+    /// them.
+    fn warm(&mut self) -> Result<()> {
+        let (hot_set, budget) = match self.options.warm {
+            Some(warm) => warm,
+            None => return Ok(()),
+        };
+        let saved = match hot_set {
+            HotSet::Frequent => {
+                self.reads.get_or_insert_with(HashMap::new);
+                warm::load(&self.path)?
+            }
+            HotSet::Recent => None,
+        };
+        let hot: Vec<CommandPos> = match saved {
+            Some(keys) => keys
+                .iter()
+                .filter_map(|key| self.index.get(&self.index.resolve_str(key)).cloned())
+                .collect(),
+            None => {
+                let mut entries: Vec<_> = self.index.iter().map(|(_, &cmd_pos)| cmd_pos).collect();
+                entries.sort_by_key(|cmd_pos| Reverse((cmd_pos.gen, cmd_pos.pos)));
+                entries
+            }
+        };
+        let mut total = 0;
+        let mut hot: Vec<_> = hot
+            .into_iter()
+            // the current log was just written; it may still be buffered.
+            .filter(|cmd_pos| cmd_pos.gen != self.current_gen)
+            .take_while(|cmd_pos| {
+                total += cmd_pos.len;
+                total <= budget
+            })
+            .collect();
+        // the records are read in log order, which lets the readers read ahead.
+        hot.sort_by_key(|cmd_pos| (cmd_pos.gen, cmd_pos.pos));
+        for cmd_pos in hot {
+            let reader = self
+                .readers
+                .get_mut(&cmd_pos.gen)
+                .expect("Cannot find log reader");
+            reader.seek_record(cmd_pos.pos)?;
+            let len = io::copy(&mut reader.take(cmd_pos.len), &mut io::sink())?;
+            self.stats.warmed_bytes += len;
+        }
+        Ok(())
+    }
+
+    /// Saves the keys read most often for `HotSet::Frequent`, as many as `Options::warm`
+    /// reads when the store is opened again.
+    fn save_hot_set(&self) -> Result<()> {
+        let (reads, budget) = match (&self.reads, self.options.warm) {
+            // the keys saved before are kept if none were read since.
+            (Some(reads), Some((_, budget))) if !reads.is_empty() => (reads, budget),
+            _ => return Ok(()),
+        };
+        let mut keys: Vec<_> = reads
+            .iter()
+            .filter_map(|(key, &count)| Some((count, key.as_str(), self.index.get(key)?.len)))
+            .collect();
+        keys.sort_by(|a, b| b.0.cmp(&a.0).then_with(|| a.1.cmp(b.1)));
+        let mut total = 0;
+        let keys: Vec<_> = keys
+            .into_iter()
+            .take_while(|&(_, _, len)| {
+                total += len;
+                total <= budget
+            })
+            .map(|(_, key, _)| key)
+            .collect();
+        warm::save(&self.path, &keys)
+    }
+
+    /// Removes the least recently used keys while the live entries exceed
+    /// `Options::cache_capacity`, sparing the most recently used key.
+    fn evict(&mut self) -> Result<()> {
+        let capacity = match self.options.cache_capacity {
+            Some(capacity) => capacity,
+            None => return Ok(()),
+        };
+        while self.total.saturating_sub(self.compaction_debt()) > capacity {
+            let prefixes = self.manifest.prefixes();
+            let evictable =
+                |key: &str| !prefix::lookup(prefixes, key).is_some_and(PrefixConfig::is_pinned);
+            let key = match self.lru.as_ref().and_then(|lru| lru.oldest(evictable)) {
+                Some(key) => key.to_owned(),
+                None => break,
+            };
+            match self.index.get(&key) {
+                Some(cmd_pos) => {
+                    self.stats.evicted_keys += 1;
+                    self.stats.evicted_bytes += cmd_pos.len;
+                    self.remove_existing(key)?;
+                }
+                // dropped from the index by a compaction.
+                None => {
+                    if let Some(lru) = &mut self.lru {
+                        lru.remove(&key);
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Reports the compaction debt and the size of the log to the metrics sink, if any.
+    fn report_debt(&self) {
+        if let Some(sink) = &self.options.metrics {
+            sink.set(Gauge::CompactionDebt, self.compaction_debt());
+            sink.set(Gauge::LogBytes, self.total);
+        }
+    }
+
+    /// Switches the current log to a new log file, recording the previous one in the
+    /// manifest.
+    fn start_log(&mut self, gen: u64) -> Result<()> {
+        self.writer.flush()?;
+        let writer = new_current_log(&self.path, gen, &mut self.readers, &self.options)?;
+        self.manifest
+            .insert(self.current_gen, self.writer.segment());
+        self.current_gen = gen;
+        self.writer = writer;
+        Ok(())
+    }
+
+    /// Creates the shared index with the whole index if `Options::shared_index` is
+    /// set, replacing any previous one. Otherwise a shared index left behind by an
+    /// earlier writer is removed.
+    fn share_index(&mut self) -> Result<()> {
+        if !self.options.shared_index || !self.index.is_bytewise() {
+            SharedIndex::discard(&self.path);
+            return Ok(());
+        }
+        let entries = self
+            .index
+            .iter()
+            .map(|(key, cmd_pos)| (key, cmd_pos.shared(self.expiry.get(key))));
+        self.shared = Some(SharedIndex::create(&self.path, self.index.len(), entries)?);
+        Ok(())
+    }
+
+    /// Publishes the new index entry of a key to the shared index, if any.
+    ///
+    /// It must be called before the log file of the previous entry is removed or
+    /// rewritten, because readers may still be reading it.
+    fn publish(&mut self, key: &str, cmd_pos: Option<CommandPos>) -> Result<()> {
+        let grow = match &self.shared {
+            Some(shared) => cmd_pos.is_some() && shared.is_full(),
+            None => return Ok(()),
+        };
+        // readers read the entry from the log file.
+        self.writer.flush()?;
+        if grow {
+            self.share_index()?;
+        }
+        let entry = cmd_pos.map(|cmd_pos| cmd_pos.shared(self.expiry.get(key)));
+        if let Some(shared) = &mut self.shared {
+            shared.update(key, entry);
+        }
+        Ok(())
+    }
+
+    /// Records the latency of an operation started at `start`.
+    fn observe(&mut self, op: Operation, start: Instant) {
+        let latency = start.elapsed();
+        if op != Operation::Compaction {
+            self.load.record();
+        }
+        self.stats.record(op, latency);
+        if let Some(sink) = &self.options.metrics {
+            sink.observe(op, latency);
+        }
+    }
+
+    /// Reports bytes to the metrics sink, if any.
+    fn count(&mut self, counter: Counter, bytes: u64) {
+        match counter {
+            Counter::Written => self.stats.written_bytes += bytes,
+            Counter::Compacted => self.stats.compacted_bytes += bytes,
+            _ => {}
+        }
+        if bytes > 0 {
+            if let Some(sink) = &self.options.metrics {
+                sink.add(counter, bytes);
+            }
+        }
+    }
+
+    /// Create a new log file with given generation number and add the reader to the readers map.
+    ///
+    /// Returns the writer to the log.
+    fn new_log_file(&mut self, gen: u64) -> Result<BufWriterWithPos<File>> {
+        new_log_file(&self.path, gen, &mut self.readers)
+    }
+}
+
+/// Creates the directory of a store unless it exists.
+///
+/// It fails with `KvsError::NotADirectory` if the path is a file, rather than with the
+/// I/O error of creating log files in it.
+fn create_store_dir(path: &Path) -> Result<()> {
+    if path.exists() && !path.is_dir() {
+        return Err(KvsError::NotADirectory(path.display().to_string()));
+    }
+    fs::create_dir_all(path)?;
+    Ok(())
+}
+
+/// Creates a directory to copy a store to, which must not have any files.
+fn create_empty_dir(path: &Path) -> Result<()> {
+    create_store_dir(path)?;
+    if fs::read_dir(path)?.next().is_some() {
+        return Err(KvsError::DirectoryNotEmpty(path.display().to_string()));
+    }
+    Ok(())
+}
+
+/// Copies the first `len` bytes of a file, syncing the copy to disk.
+fn copy_file(src: &Path, dest: &Path, len: u64) -> Result<()> {
+    let mut file = File::create(dest)?;
+    io::copy(&mut File::open(src)?.take(len), &mut file)?;
+    file.sync_all()?;
+    Ok(())
+}
+
+/// Copies the files of a directory and its subdirectories.
+fn copy_dir(src: &Path, dest: &Path) -> Result<()> {
+    for entry in fs::read_dir(src)? {
+        let entry = entry?;
+        let (src, dest) = (entry.path(), dest.join(entry.file_name()));
+        if entry.file_type()?.is_dir() {
+            fs::create_dir_all(&dest)?;
+            copy_dir(&src, &dest)?;
+        } else {
+            copy_file(&src, &dest, u64::MAX)?;
+        }
+    }
+    Ok(())
+}
+
+/// Create a new log file with given generation number and add the reader to the readers map.
+///
+/// Returns the writer to the log.
+fn new_log_file(
+    path: &Path,
+    gen: u64,
+    readers: &mut HashMap<u64, BufReaderWithPos<File>>,
+) -> Result<BufWriterWithPos<File>> {
+    let path = log_path(path, gen);
+    let writer = BufWriterWithPos::new(OpenOptions::new().create(true).append(true).open(&path)?)?;
+    readers.insert(gen, BufReaderWithPos::new(File::open(&path)?)?);
+    Ok(writer)
+}
+
+/// Creates a new log file to append commands to, reserving `Options::preallocate` bytes
+/// of disk space for it and buffering up to `Options::write_buffer` bytes of writes.
+fn new_current_log(
+    path: &Path,
+    gen: u64,
+    readers: &mut HashMap<u64, BufReaderWithPos<File>>,
+    options: &Options,
+) -> Result<BufWriterWithPos<File>> {
+    let mut writer = new_log_file(path, gen, readers)?;
+    if options.write_buffer > writer.writer.capacity() {
+        let file = writer.writer.into_inner().map_err(|e| e.into_error())?;
+        writer.writer = BufWriter::with_capacity(options.write_buffer, file);
+    }
+    if let Some(len) = options.preallocate {
+        if let Err(e) = reserve(writer.writer.get_ref(), len) {
+            readers.remove(&gen);
+            fs::remove_file(log_path(path, gen))?;
+            return Err(e);
+        }
+    }
+    Ok(writer)
+}
+
+/// Allocates disk space for the first `len` bytes of a file without changing its size,
+/// so that appends still start at the end of the data.
+#[cfg(target_os = "linux")]
+fn reserve(file: &File, len: u64) -> Result<()> {
+    use std::os::unix::io::AsRawFd;
+
+    let len = len.min(libc::off_t::MAX as u64) as libc::off_t;
+    // SAFETY: the file descriptor is valid for the lifetime of `file`.
+    let ret = unsafe { libc::fallocate(file.as_raw_fd(), libc::FALLOC_FL_KEEP_SIZE, 0, len) };
+    if ret == 0 {
+        return Ok(());
+    }
+    let err = io::Error::last_os_error();
+    match err.raw_os_error() {
+        Some(libc::ENOSPC) | Some(libc::EFBIG) => Err(KvsError::NoSpace(len as u64)),
+        // the file system can't preallocate; the log grows on demand instead.
+        Some(libc::EOPNOTSUPP) => Ok(()),
+        _ => Err(err.into()),
+    }
+}
+
+/// Preallocation is only supported on Linux; elsewhere the log grows on demand.
+#[cfg(not(target_os = "linux"))]
+fn reserve(_file: &File, _len: u64) -> Result<()> {
+    Ok(())
+}
+
+/// Tells the OS whether a file is read sequentially, so that it reads ahead of it.
+#[cfg(target_os = "linux")]
+fn advise(file: &File, sequential: bool) {
+    use std::os::unix::io::AsRawFd;
+
+    let advice = if sequential {
+        libc::POSIX_FADV_SEQUENTIAL
+    } else {
+        libc::POSIX_FADV_NORMAL
+    };
+    // SAFETY: the file descriptor is valid for the lifetime of `file`. The advice is
+    // only a hint, so failures are ignored.
+    unsafe { libc::posix_fadvise(file.as_raw_fd(), 0, 0, advice) };
+}
+
+/// Read-ahead advice is only given on Linux; elsewhere the larger buffer does the work.
+#[cfg(not(target_os = "linux"))]
+fn advise(_file: &File, _sequential: bool) {}
+
+/// Takes the lock of the given store directory if `exclusive` is set.
+///
+/// The lock is released when the returned file is closed.
+#[cfg(target_os = "linux")]
+fn lock(path: &Path, exclusive: bool) -> Result<Option<File>> {
+    use std::os::unix::io::AsRawFd;
+
+    if !exclusive {
+        return Ok(None);
+    }
+    let file = OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(path.join(LOCK))?;
+    // SAFETY: the file descriptor is valid for the lifetime of `file`.
+    if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } != 0 {
+        let err = io::Error::last_os_error();
+        return match err.raw_os_error() {
+            Some(libc::EWOULDBLOCK) => Err(KvsError::Locked),
+            _ => Err(err.into()),
+        };
+    }
+    Ok(Some(file))
+}
+
+/// Locking is only supported on Linux; elsewhere `Options::exclusive` is ignored.
+#[cfg(not(target_os = "linux"))]
+fn lock(_path: &Path, _exclusive: bool) -> Result<Option<File>> {
+    Ok(None)
+}
+
+impl Drop for KvStore {
+    fn drop(&mut self) {
+        // errors can't be reported here. Log files missing from the manifest are
+        // accepted when the store is opened again.
+        if self.shared.is_some() {
+            SharedIndex::discard(&self.path);
+        }
+        if self.clear_read_only().is_ok() && self.writer.flush().is_ok() {
+            self.manifest
+                .insert(self.current_gen, self.writer.segment());
+            let _ = self.manifest.save(&self.path);
+        }
+        let _ = self.save_hot_set();
+    }
+}
+
+/// Checks the log files in the given directory against the manifest.
+///
+/// Returns the manifest with the lengths and checksums of all log files.
+fn verify(path: &Path, gen_list: &[u64], level: VerifyLevel) -> Result<Manifest> {
+    let recorded = Manifest::load(path)?;
+    if level != VerifyLevel::None {
+        if let Some(gen) = recorded
+            .gens()
+            .find(|gen| gen_list.binary_search(gen).is_err())
+        {
+            return Err(KvsError::CorruptedLog {
+                gen,
+                reason: "missing",
+            });
+        }
+    }
+
+    let mut manifest = Manifest::default();
+    for &gen in gen_list {
+        let log_path = log_path(path, gen);
+        let len = fs::metadata(&log_path)?.len();
+        let segment = match recorded.get(gen) {
+            Some(segment) if level != VerifyLevel::None => {
+                let reason = if len < segment.len {
+                    Some("truncated")
+                } else if len > segment.len
+                    || (level == VerifyLevel::Full && Segment::read(&log_path)? != segment)
+                {
+                    Some("modified")
+                } else {
+                    None
+                };
+                if let Some(reason) = reason {
+                    return Err(KvsError::CorruptedLog { gen, reason });
+                }
+                segment
+            }
+            Some(segment) if segment.len == len => segment,
+            // log files written after the manifest was last saved.
+            _ => Segment::read(&log_path)?,
+        };
+        manifest.insert(gen, segment);
+    }
+    manifest.copy_meta(&recorded);
+    Ok(manifest)
+}
+
+/// Returns whether the keys of the store are normalized.
+///
+/// The normalization of `Options::normalize_keys` is recorded in the manifest of a new
+/// store, and applies whenever it is opened.
+///
+/// # Errors
+///
+/// It returns `KvsError::NotNormalized` if the option is set for an existing store
+/// created without it.
+fn store_normalization(
+    path: &Path,
+    gen_list: &[u64],
+    manifest: &mut Manifest,
+    options: &Options,
+) -> Result<bool> {
+    if manifest.is_normalized() || !options.normalize_keys {
+        return Ok(manifest.is_normalized());
+    }
+    if !gen_list.is_empty() {
+        return Err(KvsError::NotNormalized);
+    }
+    manifest.set_normalized();
+    manifest.save(path)?;
+    Ok(true)
+}
+
+/// Returns the comparator of the store, or `None` if keys are ordered by bytes.
+///
+/// The comparator of `Options::comparator` is recorded in the manifest of a new store.
+/// Stores without a recorded comparator order keys by bytes.
+///
+/// # Errors
+///
+/// It returns `KvsError::ComparatorMismatch` if the configured comparator is not the
+/// recorded one, or if no comparator is configured for a custom recorded one.
+fn store_comparator(
+    path: &Path,
+    gen_list: &[u64],
+    manifest: &mut Manifest,
+    options: &Options,
+) -> Result<Option<Arc<dyn Comparator>>> {
+    let recorded = match manifest.comparator() {
+        Some(name) => Some(name.to_owned()),
+        None if !gen_list.is_empty() => Some(Bytewise.name().to_owned()),
+        None => None,
+    };
+    let comparator = match (recorded, &options.comparator) {
+        (Some(name), Some(comparator)) if comparator.name() != name => {
+            return Err(KvsError::ComparatorMismatch(name));
+        }
+        (Some(_), Some(comparator)) => comparator.clone(),
+        (Some(name), None) => {
+            comparator::builtin(&name).ok_or(KvsError::ComparatorMismatch(name))?
+        }
+        (None, Some(comparator)) => {
+            if comparator.name() != Bytewise.name() {
+                manifest.set_comparator(Some(comparator.name().to_owned()));
+                manifest.save(path)?;
+            }
+            comparator.clone()
+        }
+        (None, None) => return Ok(None),
+    };
+    Ok(Some(comparator).filter(|comparator| comparator.name() != Bytewise.name()))
+}
+
+/// A command of a legacy log, such as `{"Set":["key1","value1"]}`.
+#[derive(Deserialize)]
+enum LegacyCommand {
+    Set(String, String),
+    #[serde(alias = "Rm")]
+    Remove(String),
+}
+
+/// Migrates the single `kv.log` of a store written by an older version to a numbered
+/// log file and a manifest.
+///
+/// The live entries are written to a temporary log file first, and the `MIGRATED`
+/// marker is written once it is complete. Only then is the log file moved into
+/// place, recorded in the manifest and the legacy log renamed to `kv.log.migrated`,
+/// steps that are repeated if a crash interrupts them. A `kv.log` next to numbered log
+/// files without the marker is not a legacy store and is left alone.
+fn migrate_legacy_log(path: &Path) -> Result<()> {
+    let legacy_path = path.join(LEGACY_LOG);
+    let marker_path = path.join(MIGRATED);
+    if !legacy_path.is_file() {
+        return Ok(());
+    }
+    let gen = 1;
+    let log_path = log_path(path, gen);
+    let tmp_path = log_path.with_extension("log.tmp");
+    if !marker_path.exists() {
+        if !sorted_gen_list(path)?.is_empty() {
+            return Ok(());
+        }
+        let mut entries = BTreeMap::new();
+        let reader = BufReader::new(File::open(&legacy_path)?);
+        for cmd in Deserializer::from_reader(reader).into_iter::<LegacyCommand>() {
+            match cmd {
+                Ok(LegacyCommand::Set(key, value)) => {
+                    entries.insert(key, value);
+                }
+                Ok(LegacyCommand::Remove(key)) => {
+                    entries.remove(&key);
+                }
+                // a command cut short by a crash was never acknowledged.
+                Err(e) if e.is_eof() => {
+                    warn!("dropped a command cut short at the end of {}", LEGACY_LOG);
+                    break;
+                }
+                Err(e) => return Err(e.into()),
+            }
+        }
+        let mut writer = BufWriter::new(File::create(&tmp_path)?);
+        for (key, value) in entries {
+            let cmd = Command::Set {
+                key,
+                value,
+                clock: VectorClock::new(),
+                ts: None,
+                expires_at: None,
+            };
+            writer.write_all(&record::encode(&cmd, &BTreeMap::new())?)?;
+        }
+        writer.flush()?;
+        writer.get_ref().sync_all()?;
+        let marker_tmp = marker_path.with_extension("tmp");
+        fs::write(&marker_tmp, LEGACY_LOG)?;
+        File::open(&marker_tmp)?.sync_all()?;
+        fs::rename(&marker_tmp, &marker_path)?;
+    }
+    if tmp_path.exists() {
+        fs::rename(&tmp_path, &log_path)?;
+    }
+    let mut manifest = Manifest::load(path)?;
+    if manifest.get(gen).is_none() {
+        manifest.insert(gen, Segment::read(&log_path)?);
+        manifest.save(path)?;
+    }
+    fs::rename(&legacy_path, legacy_path.with_extension("log.migrated"))?;
+    warn!("migrated {} to log file {}", LEGACY_LOG, gen);
+    Ok(())
+}
+
+/// Truncates a record cut short by a crash at the end of the newest log file, so
+/// that it is dropped as a whole, like the writes of an incomplete batch.
+///
+/// A cleanly closed store records its newest log file in the manifest, so only a
+/// log file missing from the manifest is scanned; any other change to a log file is
+/// left to `verify`. A record cut short is only torn if no valid record follows it,
+/// such as a record whose length was damaged; that is left to the recovery mode too.
+fn truncate_torn_tail(path: &Path, gen_list: &[u64]) -> Result<()> {
+    let gen = match gen_list.last() {
+        Some(&gen) => gen,
+        None => return Ok(()),
+    };
+    if Manifest::load(path)?.get(gen).is_some() {
+        return Ok(());
+    }
+    let log_path = log_path(path, gen);
+    let mut reader = BufReaderWithPos::new(File::open(&log_path)?)?;
+    let mut pos = 0;
+    loop {
+        match read_next(&mut reader)? {
+            Next::Record(_, len) => pos += len,
+            Next::Partial => break,
+            // corrupt records are left to the recovery mode.
+            Next::End | Next::Corrupt(_) => return Ok(()),
+        }
+    }
+    let mut rest = Vec::new();
+    reader.seek(SeekFrom::Start(pos))?;
+    reader.read_to_end(&mut rest)?;
+    if (1..rest.len()).any(|i| record::starts_record(&rest[i..])) {
+        return Ok(());
+    }
+    let file = OpenOptions::new().write(true).open(&log_path)?;
+    warn!(
+        "dropped {} bytes of a record cut short at {} of log file {}",
+        file.metadata()?.len() - pos,
+        pos,
+        gen
+    );
+    file.set_len(pos)?;
+    file.sync_all()?;
+    Ok(())
+}
+
+/// Clears the files a crash can leave behind in the given directory: temporary files
+/// and log files a compaction has replaced but not removed yet.
+///
+/// A log file is only cleared if it is older than a log file recorded in the manifest
+/// without being recorded itself, because every log file other than the current one
+/// is recorded before the manifest is saved. Such log files are moved to the
+/// quarantine directory rather than removed, as nothing checks that their records made
+/// it into the compacted log; their hint files are removed. Log files are kept with
+/// `VerifyLevel::None`, which doesn't check that the manifest matches the log.
+///
+/// Returns the number of bytes cleared from the directory.
+fn collect_garbage(path: &Path, gen_list: &mut Vec<u64>, level: VerifyLevel) -> Result<u64> {
+    let mut removed = 0;
+    for entry in fs::read_dir(path)? {
+        let file = entry?.path();
+        if file.is_file() && file.extension() == Some("tmp".as_ref()) {
+            let len = fs::metadata(&file)?.len();
+            fs::remove_file(&file)?;
+            warn!("removed leftover file {} of {} bytes", file.display(), len);
+            removed += len;
+        }
+    }
+    if level != VerifyLevel::None {
+        let recorded = Manifest::load(path)?;
+        if let Some(newest) = recorded.gens().max() {
+            let mut orphaned = Vec::new();
+            gen_list.retain(|&gen| {
+                let orphan = gen < newest && recorded.get(gen).is_none();
+                if orphan {
+                    orphaned.push(gen);
+                }
+                !orphan
+            });
+            for gen in orphaned {
+                hint::remove(path, gen)?;
+                let file = log_path(path, gen);
+                let len = fs::metadata(&file)?.len();
+                let dir = path.join(QUARANTINE_DIR);
+                fs::create_dir_all(&dir)?;
+                let dest = dir.join(format!("{}.log", gen));
+                fs::rename(&file, &dest)?;
+                warn!(
+                    "quarantined leftover log file {} of {} bytes to {}",
+                    gen,
+                    len,
+                    dest.display()
+                );
+                removed += len;
+            }
+        }
+    }
+    Ok(removed)
+}
+
+/// Returns sorted generation numbers in the given directory.
+fn sorted_gen_list(path: &Path) -> Result<Vec<u64>> {
+    let mut gen_list: Vec<u64> = fs::read_dir(path)?
+        .flat_map(|res| -> Result<_> { Ok(res?.path()) })
+        .filter(|path| path.is_file() && path.extension() == Some("log".as_ref()))
+        .flat_map(|path| {
+            path.file_name()
+                .and_then(OsStr::to_str)
+                .map(|s| s.trim_end_matches(".log"))
+                .map(str::parse::<u64>)
+        })
+        .flatten()
+        .collect();
+    gen_list.sort_unstable();
+    Ok(gen_list)
+}
+
+/// The state replayed from existing log files.
+struct Replayed {
+    readers: HashMap<u64, BufReaderWithPos<File>>,
+    index: Index<CommandPos>,
+    expiry: ExpiryIndex,
+    stale: BTreeMap<u64, StaleBytes>,
+    total: u64,
+    lost: Vec<LostData>,
+    // the ids of the prepared batches committed by batch records.
+    committed: HashSet<String>,
+}
+
+/// Replays the log files of the given generations, oldest first.
+fn replay(
+    path: &Path,
+    gen_list: &[u64],
+    quarantine: Option<&Path>,
+    comparator: Option<Arc<dyn Comparator>>,
+) -> Result<Replayed> {
+    let mut replayed = Replayed {
+        readers: HashMap::new(),
+        index: Index::new(comparator),
+        expiry: ExpiryIndex::default(),
+        stale: BTreeMap::new(),
+        total: 0,
+        lost: Vec::new(),
+        committed: HashSet::new(),
+    };
+    for &gen in gen_list {
+        let mut reader = BufReaderWithPos::new(File::open(log_path(path, gen))?)?;
+        let len = reader.seek(SeekFrom::End(0))?;
+        if let Some(hints) = hint::read(path, gen, len)? {
+            load_hints(
+                gen,
+                hints,
+                &mut replayed.index,
+                &mut replayed.expiry,
+                &mut replayed.stale,
+            );
+            replayed.total += len;
+            replayed.readers.insert(gen, reader);
+            continue;
+        }
+        let corrupt = load(
+            gen,
+            &mut reader,
+            &mut replayed.index,
+            &mut replayed.expiry,
+            &mut replayed.stale,
+            &mut replayed.committed,
+            quarantine,
+        )?;
+        replayed
+            .lost
+            .extend(corrupt.into_iter().map(|range| LostData {
+                gen,
+                offset: range.start,
+                len: range.end - range.start,
+            }));
+        replayed.total += reader.pos;
+        replayed.readers.insert(gen, reader);
+    }
+    Ok(replayed)
+}
+
+/// Returns the directory corrupt data is quarantined to, if any.
+fn quarantine_dir(path: &Path, recovery: Recovery) -> Option<PathBuf> {
+    match recovery {
+        Recovery::Strict => None,
+        Recovery::Quarantine => Some(path.join(QUARANTINE_DIR)),
+    }
+}
+
+/// Load the whole log file and store value locations in the index map.
+///
+/// Records how many bytes of each log file can be saved after a compaction in `stale`,
+/// and the ids of committed prepared batches in `committed`.
+///
+/// Corrupt data is quarantined to the given directory instead of failing the load.
+///
+/// Returns the position ranges of the skipped corrupt data.
+fn load(
+    gen: u64,
+    reader: &mut BufReaderWithPos<File>,
+    index: &mut Index<CommandPos>,
+    expiry: &mut ExpiryIndex,
+    stale: &mut BTreeMap<u64, StaleBytes>,
+    committed: &mut HashSet<String>,
+    quarantine: Option<&Path>,
+) -> Result<Vec<Range<u64>>> {
+    let corrupt = scan(gen, reader, quarantine, |record, range| {
+        if let Some(id) = prepared::committed(&record.meta) {
+            committed.insert(id.to_owned());
+        }
+        apply(gen, record, range, index, expiry, stale);
+        Ok(())
+    })?;
+    stale.entry(gen).or_default().overwritten += corrupt
+        .iter()
+        .map(|range| range.end - range.start)
+        .sum::<u64>();
+    Ok(corrupt)
+}
+
+/// Stores the value locations listed in the hint file of a log file in the index.
+fn load_hints(
+    gen: u64,
+    hints: Vec<Hint>,
+    index: &mut Index<CommandPos>,
+    expiry: &mut ExpiryIndex,
+    stale: &mut BTreeMap<u64, StaleBytes>,
+) {
+    for Hint {
+        key,
+        pos,
+        len,
+        expires_at,
+    } in hints
+    {
+        let key = index.resolve(key);
+        expiry.set(&key, expires_at);
+        if let Some(old_cmd) = index.insert(key, (gen, pos..pos + len).into()) {
+            stale.entry(old_cmd.gen).or_default().overwritten += old_cmd.len;
+        }
+    }
+}
+
+/// Applies a record read from the log file of the given generation to the index.
+fn apply(
+    gen: u64,
+    record: Record,
+    Range {
+        start: pos,
+        end: new_pos,
+    }: Range<u64>,
+    index: &mut Index<CommandPos>,
+    expiry: &mut ExpiryIndex,
+    stale: &mut BTreeMap<u64, StaleBytes>,
+) {
+    match record.cmd {
+        Command::Set {
+            key, expires_at, ..
+        } => {
+            let key = index.resolve(key);
+            expiry.set(&key, expires_at);
+            if let Some(old_cmd) = index.insert(key, (gen, pos..new_pos).into()) {
+                stale.entry(old_cmd.gen).or_default().overwritten += old_cmd.len;
+            }
+        }
+        Command::Remove { key } => {
+            let key = index.resolve(key);
+            expiry.remove(&key);
+            if let Some(old_cmd) = index.remove(&key) {
+                stale.entry(old_cmd.gen).or_default().overwritten += old_cmd.len;
+            }
+            // the "remove" command itself can be deleted in the next compaction.
+            stale.entry(gen).or_default().tombstones += new_pos - pos;
+        }
+        Command::Expire { key, expires_at } => {
+            let key = index.resolve(key);
+            if index.contains_key(&key) {
+                expiry.set(&key, expires_at);
+            }
+            stale.entry(gen).or_default().tombstones += new_pos - pos;
+        }
+        Command::Rename { key, new_key } => {
+            // the key is missing if its "set" command has been moved by a compaction
+            // after the rename.
+            let key = index.resolve(key);
+            let new_key = index.resolve(new_key);
+            if let Some(cmd_pos) = index.remove(&key) {
+                let expires_at = expiry.get(&key);
+                expiry.remove(&key);
+                expiry.set(&new_key, expires_at);
+                if let Some(old_cmd) = index.insert(new_key, cmd_pos) {
+                    stale.entry(old_cmd.gen).or_default().overwritten += old_cmd.len;
+                }
+            }
+            stale.entry(gen).or_default().tombstones += new_pos - pos;
+        }
+        Command::Batch(records) => {
+            // the last record ends the batch, which may follow whitespace of older
+            // versions.
+            let start = new_pos - records.last().map_or(0, |(_, range)| range.end);
+            let mut len = 0;
+            for (record, range) in records {
+                len += range.end - range.start;
+                let range = start + range.start..start + range.end;
+                apply(gen, record, range, index, expiry, stale);
+            }
+            // the header of the batch can be deleted in the next compaction.
+            stale.entry(gen).or_default().tombstones += new_pos - pos - len;
+        }
+        Command::Unknown => stale.entry(gen).or_default().tombstones += new_pos - pos,
+    }
+}
+
+/// Reads the records of a log file from `pos` on, calling `f` with every record and its
+/// position.
+///
+/// Returns the position after the last complete record. A partial record at the end
+/// is still being written, and is read by a later call.
+fn scan_tail(
+    gen: u64,
+    reader: &mut BufReaderWithPos<File>,
+    mut pos: u64,
+    mut f: impl FnMut(Record, Range<u64>),
+) -> Result<u64> {
+    reader.seek(SeekFrom::Start(pos))?;
+    loop {
+        match read_next(reader)? {
+            Next::Record(record, len) => {
+                f(record, pos..pos + len);
+                pos += len;
+            }
+            Next::End | Next::Partial => return Ok(pos),
+            Next::Corrupt(reason) => return Err(KvsError::Corruption { gen, pos, reason }),
+        }
+    }
+}
+
+/// Reads all records of a log file, calling `f` with every record and its position.
+///
+/// Records that can't be parsed or don't match their checksum are corrupt. Without a
+/// quarantine directory, corrupt data fails the scan with `KvsError::Corruption`.
+/// Otherwise the data up to the next valid record is copied to a file in the directory
+/// and skipped.
+///
+/// Returns the position ranges of the skipped data.
+fn scan(
+    gen: u64,
+    reader: &mut BufReaderWithPos<File>,
+    quarantine: Option<&Path>,
+    mut f: impl FnMut(Record, Range<u64>) -> Result<()>,
+) -> Result<Vec<Range<u64>>> {
+    // To make sure we read from the beginning of the file.
+    let mut pos = reader.seek(SeekFrom::Start(0))?;
+    let mut skipped = Vec::new();
+    loop {
+        let reason = loop {
+            match read_next(reader)? {
+                Next::Record(record, len) => {
+                    f(record, pos..pos + len)?;
+                    pos += len;
+                }
+                Next::End => return Ok(skipped),
+                Next::Partial => break "truncated record".to_owned(),
+                Next::Corrupt(reason) => break reason,
+            }
+        };
+        let dir = match quarantine {
+            Some(dir) => dir,
+            None => return Err(KvsError::Corruption { gen, pos, reason }),
+        };
+
+        let mut rest = Vec::new();
+        reader.seek(SeekFrom::Start(pos))?;
+        reader.read_to_end(&mut rest)?;
+        let len = (1..rest.len())
+            .find(|&i| record::starts_record(&rest[i..]))
+            .unwrap_or(rest.len());
+        fs::create_dir_all(dir)?;
+        let path = dir.join(format!("{}-{}", gen, pos));
+        fs::write(&path, &rest[..len])?;
+        warn!(
+            "skipped {} corrupt bytes at {} of log file {}: {}; quarantined to {}",
+            len,
+            pos,
+            gen,
+            reason,
+            path.display()
+        );
+        skipped.push(pos..pos + len as u64);
+        pos += len as u64;
+        reader.seek(SeekFrom::Start(pos))?;
+    }
+}
+
+/// The outcome of reading the next record of a log file.
+enum Next {
+    /// A record, and its length including the whitespace before it.
+    Record(Record, u64),
+    /// The end of the file.
+    End,
+    /// A record cut short by the end of the file.
+    Partial,
+    /// Data that isn't a valid record, and why.
+    Corrupt(String),
+}
+
+/// Reads the record at the position of a reader, binary or JSON.
+fn read_next(reader: &mut BufReaderWithPos<File>) -> Result<Next> {
+    // JSON records of older versions may be separated by whitespace.
+    let mut len = 0;
+    let first = loop {
+        let buf = reader.reader.fill_buf()?;
+        if buf.is_empty() {
+            return Ok(Next::End);
+        }
+        let skip = buf
+            .iter()
+            .position(|b| !b.is_ascii_whitespace())
+            .unwrap_or(buf.len());
+        let first = buf.get(skip).copied();
+        reader.reader.consume(skip);
+        reader.pos += skip as u64;
+        len += skip as u64;
+        if let Some(first) = first {
+            break first;
+        }
+    };
+
+    if first == record::BINARY {
+        let mut header = [0; record::HEADER_LEN];
+        let mut read = Vec::with_capacity(record::HEADER_LEN);
+        (&mut *reader)
+            .take(record::HEADER_LEN as u64)
+            .read_to_end(&mut read)?;
+        if read.len() < record::HEADER_LEN {
+            return Ok(Next::Partial);
+        }
+        header.copy_from_slice(&read);
+        let (payload_len, crc) = record::header(&header);
+        let mut payload = Vec::new();
+        (&mut *reader).take(payload_len).read_to_end(&mut payload)?;
+        if (payload.len() as u64) < payload_len {
+            return Ok(Next::Partial);
+        }
+        len += record::HEADER_LEN as u64 + payload_len;
+        return Ok(match record::decode_payload(&payload, crc) {
+            Ok(record) => Next::Record(record, len),
+            Err(reason) => Next::Corrupt(reason),
+        });
+    }
+
+    let recorded = RefCell::new(Vec::new());
+    let mut stream =
+        Deserializer::from_reader(Recording::new(&mut *reader, &recorded)).into_iter::<Record>();
+    match stream.next() {
+        Some(Ok(record)) => {
+            len += stream.byte_offset() as u64;
+            if record::checksum_ok(&recorded.borrow()) {
+                Ok(Next::Record(record, len))
+            } else {
+                Ok(Next::Corrupt("checksum mismatch".to_owned()))
+            }
+        }
+        Some(Err(e)) if e.is_eof() => Ok(Next::Partial),
+        Some(Err(e)) if e.is_io() => Err(e.into()),
+        Some(Err(e)) => Ok(Next::Corrupt(e.to_string())),
+        None => Ok(Next::End),
+    }
+}
+
+/// Keeps the bytes read through it, so that a JSON record parsed from a stream can be
+/// checked against its checksum.
+struct Recording<'a, R: Read> {
+    inner: R,
+    recorded: &'a RefCell<Vec<u8>>,
+}
+
+impl<'a, R: Read> Recording<'a, R> {
+    fn new(inner: R, recorded: &'a RefCell<Vec<u8>>) -> Self {
+        Recording { inner, recorded }
+    }
+}
+
+impl<R: Read> Read for Recording<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = self.inner.read(buf)?;
+        self.recorded.borrow_mut().extend_from_slice(&buf[..len]);
+        Ok(len)
+    }
+}
+
+/// Reads the record an index entry points to.
+fn read_record(
+    readers: &mut HashMap<u64, BufReaderWithPos<File>>,
+    cmd_pos: &CommandPos,
+) -> Result<Record> {
+    // the entry is only valid for the generation it was written to; never read
+    // its offset from another log file.
+    let reader = readers
+        .get_mut(&cmd_pos.gen)
+        .ok_or(KvsError::StaleIndex(cmd_pos.gen))?;
+    reader.seek_record(cmd_pos.pos)?;
+    let mut buf = Vec::with_capacity(cmd_pos.len as usize);
+    reader.take(cmd_pos.len).read_to_end(&mut buf)?;
+    decode(&buf, cmd_pos)
+}
+
+/// Deserializes the record an index entry points to, checking its checksum.
+fn decode(buf: &[u8], cmd_pos: &CommandPos) -> Result<Record> {
+    let buf = trim_start(buf);
+    if record::is_binary(buf) {
+        return record::decode(buf).map_err(|reason| corruption(cmd_pos, reason));
+    }
+    check(buf, cmd_pos)?;
+    Ok(serde_json::from_slice(buf)?)
+}
+
+/// Checks the checksum of the JSON record an index entry points to.
+fn check(buf: &[u8], cmd_pos: &CommandPos) -> Result<()> {
+    if record::checksum_ok(buf) {
+        Ok(())
+    } else {
+        Err(corruption(cmd_pos, "checksum mismatch".to_owned()))
+    }
+}
+
+fn corruption(cmd_pos: &CommandPos, reason: String) -> KvsError {
+    KvsError::Corruption {
+        gen: cmd_pos.gen,
+        pos: cmd_pos.pos,
+        reason,
+    }
+}
+
+/// Skips the whitespace before a record, which only JSON records have.
+fn trim_start(buf: &[u8]) -> &[u8] {
+    let start = buf
+        .iter()
+        .position(|b| !b.is_ascii_whitespace())
+        .unwrap_or(buf.len());
+    &buf[start..]
+}
+
+/// Returns the milliseconds elapsed since the Unix epoch.
+fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+/// Reads the "set" command of an index entry.
+///
+/// The command is brought up to date with the key and expiration time in the index,
+/// so that "rename" and "expire" commands written after it can be dropped by a
+/// compaction.
+///
+/// Returns the serialized command and its timestamp.
+fn read_entry(
+    reader: &mut BufReaderWithPos<File>,
+    key: &str,
+    cmd_pos: &CommandPos,
+    expires_at: Option<u64>,
+) -> Result<(Vec<u8>, Option<u64>)> {
+    if reader.pos != cmd_pos.pos {
+        reader.seek(SeekFrom::Start(cmd_pos.pos))?;
+    }
+    let mut entry = Vec::with_capacity(cmd_pos.len as usize);
+    reader.take(cmd_pos.len).read_to_end(&mut entry)?;
+    let Record { cmd, meta } = decode(&entry, cmd_pos)?;
+    match cmd {
+        Command::Set {
+            key: old_key,
+            value,
+            clock,
+            ts,
+            expires_at: old_expires_at,
+        } => {
+            // records of older versions and of batches are rewritten as plain binary
+            // records.
+            if old_key != key || old_expires_at != expires_at || entry[0] != record::BINARY {
+                let cmd = Command::Set {
+                    key: key.to_owned(),
+                    value,
+                    clock,
+                    ts,
+                    expires_at,
+                };
+                entry = record::encode(&cmd, &meta)?;
+            }
+            Ok((entry, ts))
+        }
+        _ => Err(KvsError::UnexpectedCommandType),
+    }
+}
+
+/// Converts milliseconds since the Unix epoch to a `SystemTime`.
+fn system_time(millis: u64) -> SystemTime {
+    UNIX_EPOCH + Duration::from_millis(millis)
+}
+
+/// Index entries in key order, as iterated by a `Scan`.
+type Entries<'a> = Box<dyn DoubleEndedIterator<Item = (&'a str, &'a CommandPos)> + 'a>;
+
+/// Returns the entries of an index with keys in the given range.
+fn range_entries(index: &Index<CommandPos>, range: impl RangeBounds<String>) -> Entries<'_> {
+    // the bounds are normalized like keys, so that they order the same.
+    let start = range.start_bound().map(|key| index.resolve_str(key));
+    let end = range.end_bound().map(|key| index.resolve_str(key));
+    Box::new(index.range(
+        start.as_ref().map(|key| key.as_ref()),
+        end.as_ref().map(|key| key.as_ref()),
+    ))
+}
+
+/// Returns the entries of an index with keys starting with the given prefix.
+fn prefix_entries<'a>(index: &'a Index<CommandPos>, prefix: &str) -> Entries<'a> {
+    let prefix = index.normalize(prefix).into_owned();
+    if index.is_bytewise() {
+        let end = prefix_end(&prefix).map_or(Bound::Unbounded, Bound::Excluded);
+        return range_entries(index, (Bound::Included(prefix), end));
+    }
+    Box::new(
+        index
+            .iter()
+            .filter(move |(key, _)| key.starts_with(prefix.as_str())),
+    )
+}
+
+/// Returns the least string after all strings starting with the given prefix, or
+/// `None` if there is none.
+fn prefix_end(prefix: &str) -> Option<String> {
+    let mut end = prefix.to_owned();
+    while let Some(c) = end.pop() {
+        // the surrogates are skipped, and `char::MAX` has no successor.
+        let next = match c {
+            '\u{d7ff}' => Some('\u{e000}'),
+            c => char::from_u32(c as u32 + 1),
+        };
+        if let Some(next) = next {
+            end.push(next);
+            return Some(end);
+        }
+    }
+    None
+}
+
+fn log_path(dir: &Path, gen: u64) -> PathBuf {
+    dir.join(format!("{}.log", gen))
+}
+
+/// Metadata stored alongside a value.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct EntryMeta {
+    /// The vector clock of the update that wrote the value.
+    ///
+    /// It is empty for values written with plain [`set`](KvStore::set).
+    pub clock: VectorClock,
+    /// When the key expires, if it has a TTL.
+    pub expires_at: Option<SystemTime>,
+}
+
+/// A transaction of a `KvStore`, started with [`KvStore::begin`].
+///
+/// Writes are buffered until [`commit`](Transaction::commit) writes them as a single
+/// [`WriteBatch`], and reads see them. The transaction borrows the store mutably, so no
+/// other write interleaves with it. Dropping a transaction without committing it rolls
+/// it back.
+///
+/// ```rust
+/// # use kvs::{KvStore, Result};
+/// # fn try_main() -> Result<()> {
+/// # let dir = tempfile::TempDir::new()?;
+/// let mut store = KvStore::open(dir.path())?;
+/// let mut tx = store.begin();
+/// let count: u64 = tx.get("count".to_owned())?.map_or(0, |n| n.parse().unwrap());
+/// tx.set("count".to_owned(), (count + 1).to_string());
+/// tx.commit()?;
+/// # Ok(())
+/// # }
+/// ```
+pub struct Transaction<'a> {
+    store: &'a mut KvStore,
+    batch: WriteBatch,
+}
+
+impl Transaction<'_> {
+    /// Gets the string value of a given string key, as written by the transaction if
+    /// it was.
+    ///
+    /// Returns `None` if the given key does not exist.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::UnexpectedCommandType` if the given command type unexpected.
+    pub fn get(&mut self, key: String) -> Result<Option<String>> {
+        match self.pending(&key) {
+            Some(value) => Ok(value.cloned()),
+            None => self.store.get(key),
+        }
+    }
+
+    /// Sets the value of a string key to a string when the transaction commits.
+    pub fn set(&mut self, key: String, value: String) {
+        let key = self.store.index.resolve(key);
+        self.batch.set(key, value);
+    }
+
+    /// Removes a given key when the transaction commits.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::KeyNotFound` if the given key is not found.
+    pub fn remove(&mut self, key: String) -> Result<()> {
+        let exists = match self.pending(&key) {
+            Some(value) => value.is_some(),
+            None => self.store.contains(&key),
+        };
+        if !exists {
+            return Err(KvsError::KeyNotFound);
+        }
+        let key = self.store.index.resolve(key);
+        self.batch.remove(key);
+        Ok(())
+    }
+
+    /// Writes the writes of the transaction as a single record.
+    ///
+    /// # Errors
+    ///
+    /// It fails like [`KvStore::write`], in which case nothing is written.
+    pub fn commit(self) -> Result<()> {
+        self.store.write(self.batch)
+    }
+
+    /// Discards the writes of the transaction.
+    pub fn rollback(self) {}
+
+    /// Returns the last write of the transaction to a key, `None` in it for a removal.
+    fn pending(&self, key: &str) -> Option<Option<&String>> {
+        let key = self.store.index.resolve_str(key);
+        self.batch
+            .writes
+            .iter()
+            .rev()
+            .find(|(written, _)| self.store.index.compare(written, &key) == Ordering::Equal)
+            .map(|(_, value)| value.as_ref())
+    }
+}
+
+/// A `KvStore` whose index is still being rebuilt in the background.
+///
+/// It is returned by [`KvStore::open_lazy`]. Writes are served right away, and so are
+/// reads of keys written since opening. Other reads wait for the rebuild.
+pub struct LazyKvStore {
+    store: KvStore,
+    // the options to apply once the index is rebuilt.
+    options: Options,
+    replay: Option<JoinHandle<Result<Replayed>>>,
+    failed: bool,
+}
+
+impl LazyKvStore {
+    /// Sets the value of a string key to a string.
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O or serialization errors during writing the log.
+    pub fn set(&mut self, key: String, value: String) -> Result<()> {
+        if self.failed {
+            return Err(KvsError::RebuildFailed);
+        }
+        self.store.set(key, value)
+    }
+
+    /// Gets the string value of a given string key.
+    ///
+    /// Waits for the index to be rebuilt unless the key was written since opening.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::RebuildFailed` if rebuilding the index failed earlier.
+    ///
+    /// It propagates errors of rebuilding the index and of reading the log.
+    pub fn get(&mut self, key: String) -> Result<Option<String>> {
+        if self.failed || !self.store.index.contains_key(&key) {
+            self.wait()?;
+        }
+        self.store.get(key)
+    }
+
+    /// Returns `true` if the index has been rebuilt, so that no call will wait for it.
+    pub fn is_ready(&self) -> bool {
+        self.replay
+            .as_ref()
+            .is_none_or(|handle| handle.is_finished())
+    }
+
+    /// Waits for the index to be rebuilt and returns the store.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::RebuildFailed` if rebuilding the index failed earlier.
+    ///
+    /// It propagates errors of rebuilding the index.
+    pub fn wait(&mut self) -> Result<&mut KvStore> {
+        if self.failed {
+            return Err(KvsError::RebuildFailed);
+        }
+        if let Some(handle) = self.replay.take() {
+            let replayed = match handle.join() {
+                Ok(Ok(replayed)) => replayed,
+                Ok(Err(e)) => {
+                    self.failed = true;
+                    return Err(e);
+                }
+                Err(panic) => panic::resume_unwind(panic),
+            };
+            self.store.merge_replayed(replayed)?;
+            self.store.options = self.options.clone();
+        }
+        Ok(&mut self.store)
+    }
+
+    /// Waits for the index to be rebuilt and returns the store.
+    ///
+    /// # Errors
+    ///
+    /// It propagates errors of rebuilding the index.
+    pub fn into_store(mut self) -> Result<KvStore> {
+        self.wait()?;
+        Ok(self.store)
+    }
+}
 
-        let mut readers = HashMap::new();
-        let mut index = BTreeMap::new();
+/// A `KvStore` that can be cloned and used from multiple threads.
+///
+/// Writes are serialized by a lock around the store. Reads take the lock only to look
+/// the key up, and read the value with log files opened by the clone, so reads of
+/// different clones run in parallel with each other and with writes. Reads through the
+/// same clone share its log files and take turns reading them.
+///
+/// `KvStore` itself keeps its `&mut self` API: the rest of it, such as transactions,
+/// snapshots and scans, is reached through [`lock`](SharedKvStore::lock).
+///
+/// ```rust
+/// # use kvs::{KvStore, Result, SharedKvStore};
+/// # use std::thread;
+/// # fn try_main() -> Result<()> {
+/// let store = SharedKvStore::new(KvStore::open(std::env::current_dir()?)?);
+/// let reader = store.clone();
+/// thread::spawn(move || reader.get("key1".to_owned()));
+/// # Ok(())
+/// # }
+/// ```
+pub struct SharedKvStore {
+    store: Arc<Mutex<KvStore>>,
+    path: PathBuf,
+    // log files opened by this clone.
+    readers: Mutex<HashMap<u64, BufReaderWithPos<File>>>,
+}
 
-        let gen_list = sorted_gen_list(&path)?;
-        let mut uncompacted = 0;
+/// Where `KvStore::locate` found a value.
+enum Located {
+    Value(String),
+    At(CommandPos),
+}
 
-        for &gen in &gen_list {
-            let mut reader = BufReaderWithPos::new(File::open(log_path(&path, gen))?)?;
-            uncompacted += load(gen, &mut reader, &mut index)?;
-            readers.insert(gen, reader);
+impl SharedKvStore {
+    /// Moves a store behind a lock so that it can be shared.
+    pub fn new(store: KvStore) -> SharedKvStore {
+        SharedKvStore {
+            path: store.path.clone(),
+            store: Arc::new(Mutex::new(store)),
+            readers: Mutex::new(HashMap::new()),
         }
+    }
 
-        let current_gen = gen_list.last().unwrap_or(&0) + 1;
-        let writer = new_log_file(&path, current_gen, &mut readers)?;
-
-        Ok(KvStore {
-            path,
-            readers,
-            writer,
-            current_gen,
-            index,
-            uncompacted,
-        })
+    /// Locks the store for the rest of its API, blocking writes and lookups of other
+    /// clones until the guard is dropped.
+    pub fn lock(&self) -> MutexGuard<'_, KvStore> {
+        self.store.lock().expect("store lock poisoned")
     }
 
     /// Sets the value of a string key to a string.
     ///
-    /// If the key already exists, the previous value will be overwritten.
-    ///
     /// # Errors
     ///
     /// It propagates I/O or serialization errors during writing the log.
-    pub fn set(&mut self, key: String, value: String) -> Result<()> {
-        let cmd = Command::set(key, value);
-        let pos = self.writer.pos;
-        serde_json::to_writer(&mut self.writer, &cmd)?;
-        self.writer.flush()?;
-        if let Command::Set { key, .. } = cmd {
-            if let Some(old_cmd) = self
-                .index
-                .insert(key, (self.current_gen, pos..self.writer.pos).into())
-            {
-                self.uncompacted += old_cmd.len;
-            }
-        }
-
-        if self.uncompacted > COMPACTION_THRESHOLD {
-            self.compact()?;
-        }
-        Ok(())
+    pub fn set(&self, key: String, value: String) -> Result<()> {
+        self.lock().set(key, value)
     }
 
     /// Gets the string value of a given string key.
@@ -114,22 +3706,28 @@ impl KvStore {
     /// # Errors
     ///
     /// It returns `KvsError::UnexpectedCommandType` if the given command type unexpected.
-    pub fn get(&mut self, key: String) -> Result<Option<String>> {
-        if let Some(cmd_pos) = self.index.get(&key) {
-            let reader = self
-                .readers
-                .get_mut(&cmd_pos.gen)
-                .expect("Cannot find log reader");
-            reader.seek(SeekFrom::Start(cmd_pos.pos))?;
-            let cmd_reader = reader.take(cmd_pos.len);
-            if let Command::Set { value, .. } = serde_json::from_reader(cmd_reader)? {
-                Ok(Some(value))
-            } else {
-                Err(KvsError::UnexpectedCommandType)
+    pub fn get(&self, key: String) -> Result<Option<String>> {
+        let start = Instant::now();
+        let mut attempts = 1;
+        let result = loop {
+            let cmd_pos = match self.lock().locate(&key) {
+                Ok(Some(Located::At(cmd_pos))) => cmd_pos,
+                Ok(Some(Located::Value(value))) => break Ok(Some(value)),
+                Ok(None) => break Ok(None),
+                Err(e) => break Err(e),
+            };
+            match self.read(&cmd_pos) {
+                // a compaction removed the log file after the lookup.
+                Err(KvsError::Io(ref e))
+                    if e.kind() == io::ErrorKind::NotFound && attempts < REFRESH_ATTEMPTS =>
+                {
+                    attempts += 1;
+                }
+                result => break result.map(Some),
             }
-        } else {
-            Ok(None)
-        }
+        };
+        self.lock().observe(Operation::Get, start);
+        result
     }
 
     /// Removes a given key.
@@ -139,170 +3737,559 @@ impl KvStore {
     /// It returns `KvsError::KeyNotFound` if the given key is not found.
     ///
     /// It propagates I/O or serialization errors during writing the log.
-    pub fn remove(&mut self, key: String) -> Result<()> {
-        if self.index.contains_key(&key) {
-            let cmd = Command::remove(key);
-            serde_json::to_writer(&mut self.writer, &cmd)?;
-            self.writer.flush()?;
-            if let Command::Remove { key } = cmd {
-                let old_cmd = self.index.remove(&key).expect("key not found");
-                self.uncompacted += old_cmd.len;
-            }
-            Ok(())
-        } else {
-            Err(KvsError::KeyNotFound)
+    pub fn remove(&self, key: String) -> Result<()> {
+        self.lock().remove(key)
+    }
+
+    fn read(&self, cmd_pos: &CommandPos) -> Result<String> {
+        let mut readers = self.readers.lock().expect("readers lock poisoned");
+        if !readers.contains_key(&cmd_pos.gen) {
+            // a new log file; close the ones removed by compactions since.
+            let path = &self.path;
+            readers.retain(|&gen, _| log_path(path, gen).exists());
+            let file = File::open(log_path(&self.path, cmd_pos.gen))?;
+            readers.insert(cmd_pos.gen, BufReaderWithPos::new(file)?);
+        }
+        match read_record(&mut readers, cmd_pos)?.cmd {
+            Command::Set { value, .. } => Ok(value),
+            _ => Err(KvsError::UnexpectedCommandType),
         }
     }
+}
 
-    /// Clears stale entries in the log.
-    pub fn compact(&mut self) -> Result<()> {
-        // increase current gen by 2. current_gen + 1 is for the compaction file.
-        let compaction_gen = self.current_gen + 1;
-        self.current_gen += 2;
-        self.writer = self.new_log_file(self.current_gen)?;
+impl Clone for SharedKvStore {
+    fn clone(&self) -> SharedKvStore {
+        SharedKvStore {
+            store: Arc::clone(&self.store),
+            path: self.path.clone(),
+            readers: Mutex::new(HashMap::new()),
+        }
+    }
+}
 
-        let mut compaction_writer = self.new_log_file(compaction_gen)?;
+/// A read-only view of a store written by another process, or handed out by the
+/// writing `KvStore` itself with [`reader`](KvStore::reader).
+///
+/// It doesn't take the lock of [`Options::exclusive`], so it can be opened next to the
+/// `KvStore` of the writing process. Its index is only updated by
+/// [`refresh`](KvReader::refresh), which reads the records appended to the log since,
+/// or rebuilds the index if the log has been compacted.
+///
+/// If the writer publishes its index with [`Options::shared_index`], keys are looked
+/// up in the shared index instead, and reads see writes as soon as they are made.
+///
+/// ```rust
+/// # use kvs::{KvReader, Result};
+/// # fn try_main() -> Result<()> {
+/// use std::env::current_dir;
+/// let mut reader = KvReader::open(current_dir()?)?;
+/// reader.refresh()?;
+/// let val = reader.get("key".to_owned())?;
+/// # Ok(())
+/// # }
+/// ```
+pub struct KvReader {
+    path: PathBuf,
+    readers: HashMap<u64, BufReaderWithPos<File>>,
+    // map generation number to the position after the last record read from it.
+    scanned: BTreeMap<u64, u64>,
+    // shared with the readers cloned from this one until either is refreshed.
+    index: Arc<Index<CommandPos>>,
+    expiry: Arc<ExpiryIndex>,
+    // the index published by the writer, which replaces reading the log.
+    shared: Option<SharedIndex>,
+}
 
-        let mut new_pos = 0; // pos in the new log file.
-        for cmd_pos in &mut self.index.values_mut() {
-            let reader = self
-                .readers
-                .get_mut(&cmd_pos.gen)
-                .expect("Cannot find log reader");
-            if reader.pos != cmd_pos.pos {
-                reader.seek(SeekFrom::Start(cmd_pos.pos))?;
+impl KvReader {
+    /// Opens the store in the given directory for reading.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::ComparatorMismatch` if the store was created with a custom
+    /// comparator.
+    ///
+    /// It propagates I/O or deserialization errors during reading the log.
+    pub fn open(path: impl Into<PathBuf>) -> Result<KvReader> {
+        let path = path.into();
+        let manifest = Manifest::load(&path)?;
+        let comparator = match manifest.comparator() {
+            Some(name) => Some(
+                comparator::builtin(name)
+                    .ok_or_else(|| KvsError::ComparatorMismatch(name.to_owned()))?,
+            ),
+            None => None,
+        };
+        let mut index = Index::new(comparator);
+        index.set_normalized(manifest.is_normalized());
+        let mut reader = KvReader {
+            path,
+            readers: HashMap::new(),
+            scanned: BTreeMap::new(),
+            index: Arc::new(index),
+            expiry: Arc::default(),
+            shared: None,
+        };
+        reader.refresh()?;
+        Ok(reader)
+    }
+
+    /// Returns another reader of the same store, which shares the index of this one
+    /// until either is refreshed and has its own handles of the log files.
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O or deserialization errors during opening the log files, or
+    /// during rebuilding the index if they were compacted away.
+    pub fn try_clone(&self) -> Result<KvReader> {
+        let mut readers = HashMap::new();
+        let mut compacted = false;
+        for &gen in self.readers.keys() {
+            match File::open(log_path(&self.path, gen)) {
+                Ok(file) => {
+                    readers.insert(gen, BufReaderWithPos::new(file)?);
+                }
+                Err(e) if e.kind() == io::ErrorKind::NotFound => compacted = true,
+                Err(e) => return Err(e.into()),
+            }
+        }
+        let mut reader = KvReader {
+            path: self.path.clone(),
+            readers,
+            scanned: self.scanned.clone(),
+            index: Arc::clone(&self.index),
+            expiry: Arc::clone(&self.expiry),
+            shared: None,
+        };
+        if compacted || self.shared.is_some() {
+            reader.refresh()?;
+        }
+        Ok(reader)
+    }
+
+    /// Catches up with the writes made to the log since opening or the last refresh.
+    ///
+    /// With a shared index, it only switches to a shared index the writer replaced
+    /// and closes log files removed by compactions.
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O or deserialization errors during reading the log.
+    pub fn refresh(&mut self) -> Result<()> {
+        if self.shared.as_ref().is_none_or(SharedIndex::is_stale) {
+            self.shared = SharedIndex::open(&self.path, false).unwrap_or(None);
+            if self.shared.is_some() {
+                self.scanned.clear();
+                self.index = Arc::new(self.index.emptied());
+                self.expiry = Arc::default();
             }
+        }
+        if self.shared.is_some() {
+            let path = &self.path;
+            self.readers.retain(|&gen, _| log_path(path, gen).exists());
+            return Ok(());
+        }
+        self.refresh_log()
+    }
 
-            let mut entry_reader = reader.take(cmd_pos.len);
-            let len = io::copy(&mut entry_reader, &mut compaction_writer)?;
-            *cmd_pos = (compaction_gen, new_pos..new_pos + len).into();
-            new_pos += len;
+    /// Catches up with the log.
+    fn refresh_log(&mut self) -> Result<()> {
+        let mut attempts = 1;
+        loop {
+            match self.try_refresh() {
+                // a compaction removed a log file before it could be opened.
+                Err(KvsError::Io(ref e))
+                    if e.kind() == io::ErrorKind::NotFound && attempts < REFRESH_ATTEMPTS =>
+                {
+                    attempts += 1;
+                }
+                result => return result,
+            }
         }
-        compaction_writer.flush()?;
+    }
 
-        // remove stale log files.
-        let stale_gens: Vec<_> = self
-            .readers
-            .keys()
-            .filter(|&&gen| gen < compaction_gen)
-            .cloned()
-            .collect();
-        for stale_gen in stale_gens {
-            self.readers.remove(&stale_gen);
-            fs::remove_file(log_path(&self.path, stale_gen))?;
+    fn try_refresh(&mut self) -> Result<()> {
+        let gen_list = sorted_gen_list(&self.path)?;
+        // the writer only appends to its current log, which is the newest one read.
+        // Any other change is made by a compaction and requires a rebuild.
+        let newest = self.scanned.keys().next_back().cloned().unwrap_or(0);
+        let compacted = self.scanned.iter().any(|(&gen, &pos)| {
+            let len = fs::metadata(log_path(&self.path, gen)).map(|metadata| metadata.len());
+            match len {
+                Ok(len) => len < pos || (len != pos && gen != newest),
+                Err(_) => true,
+            }
+        }) || gen_list
+            .iter()
+            .any(|gen| *gen < newest && !self.scanned.contains_key(gen));
+        if compacted {
+            self.readers.clear();
+            self.scanned.clear();
+            self.index = Arc::new(self.index.emptied());
+            self.expiry = Arc::default();
         }
-        self.uncompacted = 0;
 
+        let mut stale = BTreeMap::new();
+        for gen in gen_list {
+            if !self.readers.contains_key(&gen) {
+                let reader = BufReaderWithPos::new(File::open(log_path(&self.path, gen))?)?;
+                self.readers.insert(gen, reader);
+            }
+            let reader = self.readers.get_mut(&gen).expect("Cannot find log reader");
+            let pos = self.scanned.get(&gen).cloned().unwrap_or(0);
+            let (index, expiry) = (&mut self.index, &mut self.expiry);
+            // the index is copied if other readers share it, and only once there are
+            // records to apply.
+            let pos = scan_tail(gen, reader, pos, |record, range| {
+                let (index, expiry) = (Arc::make_mut(index), Arc::make_mut(expiry));
+                apply(gen, record, range, index, expiry, &mut stale)
+            })?;
+            self.scanned.insert(gen, pos);
+        }
         Ok(())
     }
 
-    /// Create a new log file with given generation number and add the reader to the readers map.
+    /// Gets the string value of a given string key as of the last refresh, or the
+    /// latest value with a shared index.
     ///
-    /// Returns the writer to the log.
-    fn new_log_file(&mut self, gen: u64) -> Result<BufWriterWithPos<File>> {
-        new_log_file(&self.path, gen, &mut self.readers)
+    /// Returns `None` if the given key does not exist.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::UnexpectedCommandType` if the given command type unexpected.
+    pub fn get(&mut self, key: String) -> Result<Option<String>> {
+        let key = self.index.resolve(key);
+        for _ in 0..REFRESH_ATTEMPTS {
+            let shared = match &self.shared {
+                Some(shared) if shared.is_stale() => {
+                    self.refresh()?;
+                    continue;
+                }
+                Some(shared) => shared,
+                None => break,
+            };
+            let lookup = match shared.get(&key) {
+                Some(lookup) => lookup,
+                // the writer crashed while updating the shared index.
+                None => break,
+            };
+            let entry = match lookup.entry {
+                Some(entry) if entry.expires_at == 0 || entry.expires_at > now_millis() => entry,
+                _ => return Ok(None),
+            };
+            if !self.readers.contains_key(&entry.gen) {
+                match File::open(log_path(&self.path, entry.gen)) {
+                    Ok(file) => {
+                        self.readers.insert(entry.gen, BufReaderWithPos::new(file)?);
+                    }
+                    // a compaction removed the log file after the lookup.
+                    Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+                    Err(e) => return Err(e.into()),
+                }
+            }
+            let cmd_pos = CommandPos {
+                gen: entry.gen,
+                pos: entry.pos,
+                len: entry.len,
+            };
+            let record = read_record(&mut self.readers, &cmd_pos);
+            if !shared.is_current(&lookup) {
+                continue;
+            }
+            return match record?.cmd {
+                Command::Set { value, .. } => Ok(Some(value)),
+                _ => Err(KvsError::UnexpectedCommandType),
+            };
+        }
+        if self.shared.take().is_some() {
+            self.refresh_log()?;
+        }
+
+        let key = self.index.resolve(key);
+        if self.expiry.is_expired(&key, now_millis()) {
+            return Ok(None);
+        }
+        match self.index.get(&key) {
+            Some(cmd_pos) => match read_record(&mut self.readers, cmd_pos)?.cmd {
+                Command::Set { value, .. } => Ok(Some(value)),
+                _ => Err(KvsError::UnexpectedCommandType),
+            },
+            None => Ok(None),
+        }
     }
 }
 
-/// Create a new log file with given generation number and add the reader to the readers map.
+/// A difference between two stores, returned by [`diff`](KvStore::diff).
 ///
-/// Returns the writer to the log.
-fn new_log_file(
-    path: &Path,
-    gen: u64,
-    readers: &mut HashMap<u64, BufReaderWithPos<File>>,
-) -> Result<BufWriterWithPos<File>> {
-    let path = log_path(&path, gen);
-    let writer = BufWriterWithPos::new(
-        OpenOptions::new()
-            .create(true)
-            .write(true)
-            .append(true)
-            .open(&path)?,
-    )?;
-    readers.insert(gen, BufReaderWithPos::new(File::open(&path)?)?);
-    Ok(writer)
+/// It is serialized with its kind in a `change` field, such as
+/// `{"change":"added","key":"key1","value":"value1"}`, and is stable as described in
+/// [`schema`](crate::schema).
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+#[serde(tag = "change", rename_all = "lowercase")]
+pub enum Change {
+    /// A key only in the other store.
+    Added {
+        /// The key.
+        key: String,
+        /// Its value in the other store.
+        value: String,
+    },
+    /// A key only in this store.
+    Removed {
+        /// The key.
+        key: String,
+        /// Its value in this store.
+        value: String,
+    },
+    /// A key with different values in the stores.
+    Changed {
+        /// The key.
+        key: String,
+        /// Its value in this store.
+        old: String,
+        /// Its value in the other store.
+        new: String,
+    },
 }
 
-/// Returns sorted generation numbers in the given directory.
-fn sorted_gen_list(path: &Path) -> Result<Vec<u64>> {
-    let mut gen_list: Vec<u64> = fs::read_dir(&path)?
-        .flat_map(|res| -> Result<_> { Ok(res?.path()) })
-        .filter(|path| path.is_file() && path.extension() == Some("log".as_ref()))
-        .flat_map(|path| {
-            path.file_name()
-                .and_then(OsStr::to_str)
-                .map(|s| s.trim_end_matches(".log"))
-                .map(str::parse::<u64>)
-        })
-        .flatten()
-        .collect();
-    gen_list.sort_unstable();
-    Ok(gen_list)
+/// An iterator over the differences between two stores in key order, returned by
+/// [`diff`](KvStore::diff).
+///
+/// Each item is a difference, or the error of reading a value from the log.
+pub struct Diff<'a> {
+    // the index of this store, ordering the keys.
+    index: &'a Index<CommandPos>,
+    left: Peekable<Scan<'a>>,
+    right: Peekable<Scan<'a>>,
 }
 
-/// Load the whole log file and store value locations in the index map.
-///
-/// Returns how many bytes can be saved after a compaction.
-fn load(
-    gen: u64,
-    reader: &mut BufReaderWithPos<File>,
-    index: &mut BTreeMap<String, CommandPos>,
-) -> Result<u64> {
-    // To make sure we read from the beginning of the file.
-    let mut pos = reader.seek(SeekFrom::Start(0))?;
-    let mut stream = Deserializer::from_reader(reader).into_iter::<Command>();
-    let mut uncompacted = 0; // number of bytes that can be saved after a compaction.
-    while let Some(cmd) = stream.next() {
-        let new_pos = stream.byte_offset() as u64;
-        match cmd? {
-            Command::Set { key, .. } => {
-                if let Some(old_cmd) = index.insert(key, (gen, pos..new_pos).into()) {
-                    uncompacted += old_cmd.len;
-                }
-            }
-            Command::Remove { key } => {
-                if let Some(old_cmd) = index.remove(&key) {
-                    uncompacted += old_cmd.len;
+impl Iterator for Diff<'_> {
+    type Item = Result<Change>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let ordering = match (self.left.peek(), self.right.peek()) {
+                (None, None) => return None,
+                (Some(Err(_)), _) | (Some(_), None) => Ordering::Less,
+                (_, Some(Err(_))) | (None, Some(_)) => Ordering::Greater,
+                (Some(Ok((left, _))), Some(Ok((right, _)))) => self.index.compare(left, right),
+            };
+            let change = match ordering {
+                Ordering::Less => self
+                    .left
+                    .next()?
+                    .map(|(key, value)| Change::Removed { key, value }),
+                Ordering::Greater => self
+                    .right
+                    .next()?
+                    .map(|(key, value)| Change::Added { key, value }),
+                Ordering::Equal => {
+                    let (key, old) = self.left.next()?.ok()?;
+                    let (_, new) = self.right.next()?.ok()?;
+                    if old == new {
+                        continue;
+                    }
+                    Ok(Change::Changed { key, old, new })
                 }
-                // the "remove" command itself can be deleted in the next compaction.
-                // so we add its length to `uncompacted`.
-                uncompacted += new_pos - pos;
-            }
+            };
+            return Some(change);
         }
-        pos = new_pos;
     }
-    Ok(uncompacted)
 }
 
-fn log_path(dir: &Path, gen: u64) -> PathBuf {
-    dir.join(format!("{}.log", gen))
+/// A read-only view of a `KvStore` as of the time it was taken, returned by
+/// [`snapshot`](KvStore::snapshot).
+///
+/// The store doesn't compact its log until all of its snapshots are dropped, so
+/// long-lived snapshots let stale data accumulate.
+///
+/// ```rust
+/// # use kvs::{KvStore, Result};
+/// # fn try_main() -> Result<()> {
+/// # let dir = tempfile::TempDir::new()?;
+/// let mut store = KvStore::open(dir.path())?;
+/// store.set("key".to_owned(), "old".to_owned())?;
+/// let mut snapshot = store.snapshot()?;
+/// store.set("key".to_owned(), "new".to_owned())?;
+/// assert_eq!(snapshot.get("key".to_owned())?, Some("old".to_owned()));
+/// # Ok(())
+/// # }
+/// ```
+pub struct Snapshot {
+    readers: HashMap<u64, BufReaderWithPos<File>>,
+    index: Index<CommandPos>,
+    expiry: ExpiryIndex,
+    // the time the snapshot was taken, which expiration times are compared to.
+    now: u64,
+    // counted by the store while the snapshot is live.
+    _pin: Arc<()>,
 }
 
-/// Struct representing a command.
-#[derive(Serialize, Deserialize, Debug)]
-enum Command {
-    Set { key: String, value: String },
-    Remove { key: String },
+impl Snapshot {
+    /// Gets the string value of a given string key as of the snapshot.
+    ///
+    /// Returns `None` if the given key does not exist.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::UnexpectedCommandType` if the given command type unexpected.
+    pub fn get(&mut self, key: String) -> Result<Option<String>> {
+        let key = self.index.resolve(key);
+        if self.expiry.is_expired(&key, self.now) {
+            return Ok(None);
+        }
+        match self.index.get(&key) {
+            Some(cmd_pos) => match read_record(&mut self.readers, cmd_pos)?.cmd {
+                Command::Set { value, .. } => Ok(Some(value)),
+                _ => Err(KvsError::UnexpectedCommandType),
+            },
+            None => Ok(None),
+        }
+    }
+
+    /// Returns an iterator over the live entries of the snapshot in key order, like
+    /// [`KvStore::scan`].
+    pub fn scan(&mut self) -> Scan<'_> {
+        self.range(..)
+    }
+
+    /// Returns an iterator over the live entries of the snapshot with keys in the given
+    /// range, like [`KvStore::range`].
+    pub fn range(&mut self, range: impl RangeBounds<String>) -> Scan<'_> {
+        Scan {
+            entries: range_entries(&self.index, range),
+            readers: &mut self.readers,
+            expiry: &self.expiry,
+            now: self.now,
+            error: None,
+        }
+    }
+
+    /// Returns an iterator over the live entries of the snapshot with keys starting
+    /// with the given prefix, like [`KvStore::scan_prefix`].
+    pub fn scan_prefix(&mut self, prefix: &str) -> Scan<'_> {
+        Scan {
+            entries: prefix_entries(&self.index, prefix),
+            readers: &mut self.readers,
+            expiry: &self.expiry,
+            now: self.now,
+            error: None,
+        }
+    }
+}
+
+/// An iterator over the live entries of a `KvStore` in key order, returned by
+/// [`scan`](KvStore::scan), [`range`](KvStore::range) and
+/// [`scan_prefix`](KvStore::scan_prefix), or the same methods of a [`Snapshot`].
+///
+/// Each item is a key and its value, or the error of reading the value from the log.
+pub struct Scan<'a> {
+    entries: Entries<'a>,
+    readers: &'a mut HashMap<u64, BufReaderWithPos<File>>,
+    expiry: &'a ExpiryIndex,
+    now: u64,
+    // the error of writing out buffered writes, returned first.
+    error: Option<KvsError>,
+}
+
+impl Scan<'_> {
+    fn read(&mut self, key: &str, cmd_pos: &CommandPos) -> Result<(String, String)> {
+        match read_record(self.readers, cmd_pos)?.cmd {
+            Command::Set { value, .. } => Ok((key.to_owned(), value)),
+            _ => Err(KvsError::UnexpectedCommandType),
+        }
+    }
 }
 
-impl Command {
-    fn set(key: String, value: String) -> Command {
-        Command::Set { key, value }
+impl Iterator for Scan<'_> {
+    type Item = Result<(String, String)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Some(e) = self.error.take() {
+            return Some(Err(e));
+        }
+        while let Some((key, cmd_pos)) = self.entries.next() {
+            if !self.expiry.is_expired(key, self.now) {
+                return Some(self.read(key, cmd_pos));
+            }
+        }
+        None
     }
+}
 
-    fn remove(key: String) -> Command {
-        Command::Remove { key }
+impl DoubleEndedIterator for Scan<'_> {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        if let Some(e) = self.error.take() {
+            return Some(Err(e));
+        }
+        while let Some((key, cmd_pos)) = self.entries.next_back() {
+            if !self.expiry.is_expired(key, self.now) {
+                return Some(self.read(key, cmd_pos));
+            }
+        }
+        None
     }
 }
 
+/// The keys under a prefix, as returned by [`usage`](KvStore::usage).
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PrefixUsage {
+    /// The key prefix.
+    pub prefix: String,
+    /// The number of live keys starting with the prefix.
+    pub keys: usize,
+    /// The size of the log records of the live keys, including the keys themselves.
+    pub bytes: u64,
+}
+
+/// The data a [`repair`](KvStore::repair) could not salvage.
+#[derive(Clone, Debug, Default)]
+pub struct RepairReport {
+    /// Corrupt data skipped by the repair.
+    pub lost: Vec<LostData>,
+    /// The number of keys in the repaired store.
+    pub keys: usize,
+}
+
+/// Corrupt data of a log file.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct LostData {
+    /// The generation of the log file.
+    pub gen: u64,
+    /// The offset of the data in the log file.
+    pub offset: u64,
+    /// The length of the data.
+    pub len: u64,
+}
+
+/// Bytes of stale commands in a log file.
+#[derive(Clone, Copy, Default)]
+struct StaleBytes {
+    // "set" commands that were overwritten or removed.
+    overwritten: u64,
+    // commands other than "set", which can only be dropped from the oldest log file.
+    tombstones: u64,
+}
+
 /// Represents the position and length of a json-serialized command in the log.
+#[derive(Clone, Copy)]
 struct CommandPos {
     gen: u64,
     pos: u64,
     len: u64,
 }
 
+impl CommandPos {
+    /// Returns the entry of the shared index for a key with the given expiration time.
+    fn shared(self, expires_at: Option<u64>) -> shm::Entry {
+        shm::Entry {
+            gen: self.gen,
+            pos: self.pos,
+            len: self.len,
+            expires_at: expires_at.unwrap_or(0),
+        }
+    }
+}
+
 impl From<(u64, Range<u64>)> for CommandPos {
     fn from((gen, range): (u64, Range<u64>)) -> Self {
         CommandPos {
@@ -316,18 +4303,47 @@ impl From<(u64, Range<u64>)> for CommandPos {
 struct BufReaderWithPos<R: Read + Seek> {
     reader: BufReader<R>,
     pos: u64,
+    // the number of records read right after the previous one.
+    sequential: u32,
 }
 
 impl<R: Read + Seek> BufReaderWithPos<R> {
     fn new(mut inner: R) -> Result<Self> {
-        let pos = inner.seek(SeekFrom::Current(0))?;
+        let pos = inner.stream_position()?;
         Ok(BufReaderWithPos {
             reader: BufReader::new(inner),
             pos,
+            sequential: 0,
         })
     }
 }
 
+impl BufReaderWithPos<File> {
+    /// Seeks to the record at `pos`.
+    ///
+    /// Once records are read in log order, as by scans of a compacted log, the reader
+    /// switches to a larger buffer and asks the OS to read ahead, until a record is
+    /// read out of order again.
+    fn seek_record(&mut self, pos: u64) -> Result<()> {
+        if pos == self.pos {
+            self.sequential = self.sequential.saturating_add(1);
+        } else {
+            self.sequential = 0;
+        }
+        let read_ahead = self.sequential >= SEQUENTIAL_READS;
+        if read_ahead == (self.reader.capacity() >= READ_AHEAD) {
+            self.seek(SeekFrom::Start(pos))?;
+            return Ok(());
+        }
+        let file = self.reader.get_ref().try_clone()?;
+        advise(&file, read_ahead);
+        let capacity = if read_ahead { READ_AHEAD } else { BUFFER };
+        self.reader = BufReader::with_capacity(capacity, file);
+        self.pos = self.reader.seek(SeekFrom::Start(pos))?;
+        Ok(())
+    }
+}
+
 impl<R: Read + Seek> Read for BufReaderWithPos<R> {
     fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
         let len = self.reader.read(buf)?;
@@ -338,6 +4354,15 @@ impl<R: Read + Seek> Read for BufReaderWithPos<R> {
 
 impl<R: Read + Seek> Seek for BufReaderWithPos<R> {
     fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        // `BufReader::seek` discards the buffer even if it holds the target.
+        if let SeekFrom::Start(target) = pos {
+            let offset = target as i64 - self.pos as i64;
+            if offset >= 0 && offset as usize <= self.reader.buffer().len() {
+                self.reader.seek_relative(offset)?;
+                self.pos = target;
+                return Ok(self.pos);
+            }
+        }
         self.pos = self.reader.seek(pos)?;
         Ok(self.pos)
     }
@@ -346,22 +4371,59 @@ impl<R: Read + Seek> Seek for BufReaderWithPos<R> {
 struct BufWriterWithPos<W: Write + Seek> {
     writer: BufWriter<W>,
     pos: u64,
+    // checksum of the bytes written by this writer.
+    crc: Crc32,
 }
 
 impl<W: Write + Seek> BufWriterWithPos<W> {
     fn new(mut inner: W) -> Result<Self> {
-        let pos = inner.seek(SeekFrom::Current(0))?;
+        let pos = inner.stream_position()?;
         Ok(BufWriterWithPos {
             writer: BufWriter::new(inner),
             pos,
+            crc: Crc32::new(),
         })
     }
+
+    /// Returns the bytes of a record written at `pos` that are still buffered, or
+    /// `None` if it has been written out.
+    fn buffered(&self, pos: u64, len: u64) -> Option<&[u8]> {
+        let buffer = self.writer.buffer();
+        let start = pos.checked_sub(self.pos - buffer.len() as u64)? as usize;
+        buffer.get(start..start + len as usize)
+    }
+
+    /// Returns the length and checksum of a log file written from its start.
+    fn segment(&self) -> Segment {
+        Segment {
+            len: self.pos,
+            crc: self.crc.value(),
+        }
+    }
+}
+
+impl BufWriterWithPos<File> {
+    /// Reopens the log file at `path`, truncating it to `len` bytes and dropping the
+    /// buffered bytes.
+    fn truncate(&mut self, path: &Path, len: u64) -> Result<()> {
+        let file = OpenOptions::new().append(true).open(path)?;
+        file.set_len(len)?;
+        let segment = Segment::read(path)?;
+        // `into_parts` drops the bytes that failed to be written instead of flushing them.
+        let capacity = self.writer.capacity();
+        let _ =
+            mem::replace(&mut self.writer, BufWriter::with_capacity(capacity, file)).into_parts();
+        self.pos = segment.len;
+        self.crc = Crc32::resume(segment.crc);
+        Ok(())
+    }
 }
 
 impl<W: Write + Seek> Write for BufWriterWithPos<W> {
     fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
         let len = self.writer.write(buf)?;
         self.pos += len as u64;
+        self.crc.update(&buf[..len]);
         Ok(len)
     }
 
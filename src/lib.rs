@@ -1,8 +1,64 @@
 #![deny(missing_docs)]
 //! A simple key/value store.
 
+pub use batch::WriteBatch;
+#[cfg(feature = "server")]
+pub use client::KvsClient;
+pub use clock::VectorClock;
+pub use engines::KvsEngine;
 pub use error::{KvsError, Result};
-pub use kv::KvStore;
+pub use kv::{
+    Change, Diff, EntryMeta, KvReader, KvStore, LazyKvStore, LostData, PrefixUsage, RepairReport,
+    Scan, SharedKvStore, Snapshot, Transaction,
+};
+pub use manifest::StoreMeta;
+pub use options::{HotSet, Options, Recovery, VerifyLevel};
+pub use pattern::KeyPattern;
+pub use prefix::PrefixConfig;
+pub use prepared::PreparedToken;
+#[cfg(feature = "server")]
+pub use server::KvsServer;
+pub use stats::{Histogram, Stats};
 
+mod batch;
+#[cfg(feature = "server")]
+mod client;
+mod clock;
+pub mod compaction;
+pub mod comparator;
+mod crc;
+pub mod encoding;
+mod engines;
 mod error;
+mod expiry;
+pub mod export;
+pub mod genload;
+#[cfg(all(feature = "server", target_os = "linux"))]
+pub mod handoff;
+mod hint;
+mod index;
+pub mod keys;
 mod kv;
+mod lru;
+mod manifest;
+pub mod metrics;
+mod nfc;
+mod options;
+mod pattern;
+mod prefix;
+mod prepared;
+#[cfg(feature = "server")]
+pub mod protocol;
+mod random;
+mod record;
+pub mod redact;
+#[cfg(feature = "server")]
+pub mod resp;
+pub mod schema;
+#[cfg(feature = "server")]
+mod server;
+pub mod sessions;
+mod shm;
+mod stats;
+pub mod trace;
+mod warm;
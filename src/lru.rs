@@ -0,0 +1,51 @@
+use std::collections::{BTreeMap, HashMap};
+
+/// The order in which the keys of a store in cache mode were last used.
+#[derive(Default)]
+pub(crate) struct Lru {
+    // map key to the tick of its last use.
+    ticks: HashMap<String, u64>,
+    // map tick to the key last used at it.
+    keys: BTreeMap<u64, String>,
+    next_tick: u64,
+}
+
+impl Lru {
+    /// Marks a key as the most recently used one.
+    pub(crate) fn touch(&mut self, key: &str) {
+        let tick = self.next_tick;
+        self.next_tick += 1;
+        match self.ticks.get_mut(key) {
+            Some(old_tick) => {
+                let key = self.keys.remove(old_tick).expect("tick not found");
+                *old_tick = tick;
+                self.keys.insert(tick, key);
+            }
+            None => {
+                self.ticks.insert(key.to_owned(), tick);
+                self.keys.insert(tick, key.to_owned());
+            }
+        }
+    }
+
+    pub(crate) fn remove(&mut self, key: &str) {
+        if let Some(tick) = self.ticks.remove(key) {
+            self.keys.remove(&tick);
+        }
+    }
+
+    /// Returns the least recently used key accepted by `filter`, other than the most
+    /// recently used one.
+    pub(crate) fn oldest(&self, filter: impl Fn(&str) -> bool) -> Option<&str> {
+        self.keys
+            .values()
+            .take(self.keys.len().saturating_sub(1))
+            .map(String::as_str)
+            .find(|&key| filter(key))
+    }
+
+    pub(crate) fn clear(&mut self) {
+        self.ticks.clear();
+        self.keys.clear();
+    }
+}
@@ -0,0 +1,226 @@
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+
+use crate::comparator::{Bytewise, Comparator};
+use crate::crc::Crc32;
+use crate::prefix::PrefixConfig;
+use crate::random;
+use crate::Result;
+
+const MANIFEST: &str = "MANIFEST";
+const NFC: &str = "nfc";
+
+/// Records the length and checksum of log files that are no longer written to.
+///
+/// It is checked when the store is opened to detect truncated or externally modified
+/// log files. The current log file is only recorded when the store is dropped, so a
+/// crash never leaves the manifest out of date for it.
+#[derive(Serialize, Deserialize, Clone, Default)]
+pub(crate) struct Manifest {
+    segments: BTreeMap<u64, Segment>,
+    // the name of the comparator the store was created with, unless it is bytewise.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    comparator: Option<String>,
+    // "nfc" if keys are normalized.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    normalization: Option<String>,
+    // the UUID of the store, missing in manifests written by older versions.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    id: Option<String>,
+    // milliseconds since the Unix epoch when the store was created.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    created: Option<u64>,
+    // the version of kvs that created the store.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    version: Option<String>,
+    // settings of the keys starting with each prefix.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    prefixes: BTreeMap<String, PrefixConfig>,
+}
+
+/// The identity of a store, returned by [`KvStore::meta`](crate::KvStore::meta).
+///
+/// Its serialized form is stable as described in [`schema`](crate::schema).
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq, Eq)]
+#[serde(default)]
+pub struct StoreMeta {
+    /// A random UUID assigned when the store is first opened. Copies of the store
+    /// directory share it.
+    pub id: String,
+    /// When the store was created, or `None` if it was created by a version of kvs
+    /// that didn't record it.
+    pub created: Option<SystemTime>,
+    /// The version of kvs that created the store, if recorded.
+    pub version: Option<String>,
+    /// The name of the comparator ordering the keys.
+    pub comparator: String,
+    /// The Unicode normalization of the keys, `nfc` if they are normalized with
+    /// [`Options::normalize_keys`](crate::Options::normalize_keys).
+    pub normalization: Option<String>,
+}
+
+/// The length and CRC-32 checksum of a log file.
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+pub(crate) struct Segment {
+    pub(crate) len: u64,
+    pub(crate) crc: u32,
+}
+
+impl Segment {
+    /// Reads a log file to compute its length and checksum.
+    pub(crate) fn read(path: &Path) -> Result<Segment> {
+        let mut reader = BufReader::new(File::open(path)?);
+        let mut crc = Crc32::new();
+        let mut len = 0;
+        loop {
+            let buf = reader.fill_buf()?;
+            if buf.is_empty() {
+                break;
+            }
+            crc.update(buf);
+            let n = buf.len();
+            len += n as u64;
+            reader.consume(n);
+        }
+        Ok(Segment {
+            len,
+            crc: crc.value(),
+        })
+    }
+}
+
+impl Manifest {
+    /// Reads the manifest in the given directory.
+    ///
+    /// Returns an empty manifest if there is none, like in stores written by older
+    /// versions.
+    pub(crate) fn load(dir: &Path) -> Result<Manifest> {
+        match File::open(dir.join(MANIFEST)) {
+            Ok(file) => Ok(serde_json::from_reader(BufReader::new(file))?),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Manifest::default()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Returns whether there is a manifest in the given directory.
+    pub(crate) fn exists(dir: &Path) -> bool {
+        dir.join(MANIFEST).is_file()
+    }
+
+    /// Replaces the manifest in the given directory.
+    pub(crate) fn save(&self, dir: &Path) -> Result<()> {
+        let tmp_path = dir.join(MANIFEST).with_extension("tmp");
+        let mut writer = BufWriter::new(File::create(&tmp_path)?);
+        serde_json::to_writer(&mut writer, self)?;
+        writer.flush()?;
+        writer.get_ref().sync_all()?;
+        fs::rename(&tmp_path, dir.join(MANIFEST))?;
+        Ok(())
+    }
+
+    pub(crate) fn get(&self, gen: u64) -> Option<Segment> {
+        self.segments.get(&gen).cloned()
+    }
+
+    pub(crate) fn insert(&mut self, gen: u64, segment: Segment) {
+        self.segments.insert(gen, segment);
+    }
+
+    pub(crate) fn remove(&mut self, gen: u64) {
+        self.segments.remove(&gen);
+    }
+
+    pub(crate) fn comparator(&self) -> Option<&str> {
+        self.comparator.as_deref()
+    }
+
+    pub(crate) fn set_comparator(&mut self, name: Option<String>) {
+        self.comparator = name;
+    }
+
+    pub(crate) fn is_normalized(&self) -> bool {
+        self.normalization.as_deref() == Some(NFC)
+    }
+
+    pub(crate) fn set_normalized(&mut self) {
+        self.normalization = Some(NFC.to_owned());
+    }
+
+    /// Assigns the store a new UUID unless it has one, recording the creation time
+    /// and version if the store is `new`.
+    ///
+    /// Returns whether the manifest changed.
+    pub(crate) fn identify(&mut self, new: bool) -> bool {
+        if self.id.is_some() {
+            return false;
+        }
+        self.id = Some(random::uuid());
+        if new {
+            self.created = Some(
+                SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .map_or(0, |d| d.as_millis() as u64),
+            );
+            self.version = Some(env!("CARGO_PKG_VERSION").to_owned());
+        }
+        true
+    }
+
+    /// Returns the manifest of a copy of the store, which is a new store with the same
+    /// log files and settings.
+    pub(crate) fn fork(&self) -> Manifest {
+        let mut manifest = Manifest {
+            segments: self.segments.clone(),
+            ..Manifest::default()
+        };
+        manifest.copy_meta(self);
+        manifest.id = None;
+        manifest.identify(true);
+        manifest
+    }
+
+    /// Copies the identity, comparator, normalization and prefix settings of the store
+    /// from another manifest.
+    pub(crate) fn copy_meta(&mut self, other: &Manifest) {
+        self.comparator = other.comparator.clone();
+        self.normalization = other.normalization.clone();
+        self.id = other.id.clone();
+        self.created = other.created;
+        self.version = other.version.clone();
+        self.prefixes = other.prefixes.clone();
+    }
+
+    pub(crate) fn prefixes(&self) -> &BTreeMap<String, PrefixConfig> {
+        &self.prefixes
+    }
+
+    pub(crate) fn prefixes_mut(&mut self) -> &mut BTreeMap<String, PrefixConfig> {
+        &mut self.prefixes
+    }
+
+    /// Returns the identity of the store.
+    pub(crate) fn meta(&self) -> StoreMeta {
+        StoreMeta {
+            id: self.id.clone().unwrap_or_default(),
+            created: self
+                .created
+                .map(|millis| UNIX_EPOCH + Duration::from_millis(millis)),
+            version: self.version.clone(),
+            comparator: self
+                .comparator
+                .clone()
+                .unwrap_or_else(|| Bytewise.name().to_owned()),
+            normalization: self.normalization.clone(),
+        }
+    }
+
+    /// Returns the generations of all recorded log files.
+    pub(crate) fn gens(&self) -> impl Iterator<Item = u64> + '_ {
+        self.segments.keys().cloned()
+    }
+}
@@ -0,0 +1,260 @@
+//! Sinks receiving the metrics of a `KvStore`.
+//!
+//! The store reports operation latencies and byte counters to the
+//! [`MetricsSink`] configured with [`Options::metrics_sink`](crate::Options::metrics_sink),
+//! so it doesn't depend on any metrics library. [`LogSink`] and [`PrometheusSink`] are
+//! provided; other libraries can be adapted by implementing the trait.
+
+use std::fmt::Write;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use log::debug;
+
+use crate::Histogram;
+
+/// An operation whose latency is measured.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Operation {
+    /// Reads of a key.
+    Get,
+    /// Writes of a value.
+    Set,
+    /// Removals of a key.
+    Remove,
+    /// Compactions of the log.
+    Compaction,
+}
+
+impl Operation {
+    /// All operations.
+    pub const ALL: [Operation; 4] = [
+        Operation::Get,
+        Operation::Set,
+        Operation::Remove,
+        Operation::Compaction,
+    ];
+
+    /// Returns the lowercase name of the operation.
+    pub fn name(self) -> &'static str {
+        match self {
+            Operation::Get => "get",
+            Operation::Set => "set",
+            Operation::Remove => "remove",
+            Operation::Compaction => "compaction",
+        }
+    }
+}
+
+/// A counter of bytes.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Counter {
+    /// Bytes appended to the log by writes.
+    Written,
+    /// Bytes written by compactions.
+    Compacted,
+    /// Bytes of log files freed by compactions.
+    Reclaimed,
+    /// Bytes of corrupt data skipped when opening the store.
+    Corrupt,
+}
+
+impl Counter {
+    /// All counters.
+    pub const ALL: [Counter; 4] = [
+        Counter::Written,
+        Counter::Compacted,
+        Counter::Reclaimed,
+        Counter::Corrupt,
+    ];
+
+    /// Returns the lowercase name of the counter.
+    pub fn name(self) -> &'static str {
+        match self {
+            Counter::Written => "written",
+            Counter::Compacted => "compacted",
+            Counter::Reclaimed => "reclaimed",
+            Counter::Corrupt => "corrupt",
+        }
+    }
+}
+
+/// A gauge of bytes, reported after writes and compactions.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Gauge {
+    /// Bytes of stale commands a compaction would reclaim.
+    CompactionDebt,
+    /// Bytes of all log files.
+    LogBytes,
+}
+
+impl Gauge {
+    /// All gauges.
+    pub const ALL: [Gauge; 2] = [Gauge::CompactionDebt, Gauge::LogBytes];
+
+    /// Returns the lowercase name of the gauge.
+    pub fn name(self) -> &'static str {
+        match self {
+            Gauge::CompactionDebt => "compaction_debt",
+            Gauge::LogBytes => "log",
+        }
+    }
+}
+
+/// Receives the metrics of a `KvStore`.
+///
+/// Methods are called synchronously by store operations, so they should be cheap.
+pub trait MetricsSink: Send + Sync {
+    /// Records the latency of an operation.
+    fn observe(&self, op: Operation, latency: Duration);
+
+    /// Adds bytes to a counter. Ignored by default.
+    fn add(&self, counter: Counter, bytes: u64) {
+        let _ = (counter, bytes);
+    }
+
+    /// Sets a gauge to the given number of bytes. Ignored by default.
+    fn set(&self, gauge: Gauge, bytes: u64) {
+        let _ = (gauge, bytes);
+    }
+}
+
+impl<T: MetricsSink + ?Sized> MetricsSink for Arc<T> {
+    fn observe(&self, op: Operation, latency: Duration) {
+        (**self).observe(op, latency)
+    }
+
+    fn add(&self, counter: Counter, bytes: u64) {
+        (**self).add(counter, bytes)
+    }
+
+    fn set(&self, gauge: Gauge, bytes: u64) {
+        (**self).set(gauge, bytes)
+    }
+}
+
+/// Logs every metric at the debug level with the `log` crate.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct LogSink;
+
+impl MetricsSink for LogSink {
+    fn observe(&self, op: Operation, latency: Duration) {
+        debug!("{} took {:?}", op.name(), latency);
+    }
+
+    fn add(&self, counter: Counter, bytes: u64) {
+        debug!("{} {} bytes", counter.name(), bytes);
+    }
+
+    fn set(&self, gauge: Gauge, bytes: u64) {
+        debug!("{} is {} bytes", gauge.name(), bytes);
+    }
+}
+
+/// Aggregates metrics for Prometheus.
+///
+/// [`render`](PrometheusSink::render) formats them in the Prometheus text exposition
+/// format, to be served by the embedder's HTTP endpoint. Share the sink with the store
+/// through an `Arc`:
+///
+/// ```rust
+/// # use kvs::{KvStore, Options, Result};
+/// # use kvs::metrics::PrometheusSink;
+/// # fn try_main() -> Result<()> {
+/// use std::env::current_dir;
+/// use std::sync::Arc;
+/// let sink = Arc::new(PrometheusSink::new());
+/// let mut store = KvStore::open_with_options(current_dir()?, Options::new().metrics_sink(sink.clone()))?;
+/// store.set("key".to_owned(), "value".to_owned())?;
+/// println!("{}", sink.render());
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug, Default)]
+pub struct PrometheusSink {
+    metrics: Mutex<PrometheusMetrics>,
+}
+
+#[derive(Debug, Default)]
+struct PrometheusMetrics {
+    latencies: [Histogram; 4],
+    counters: [u64; 4],
+    gauges: [u64; 2],
+}
+
+impl PrometheusSink {
+    /// Creates a sink without any metrics.
+    pub fn new() -> PrometheusSink {
+        PrometheusSink::default()
+    }
+
+    /// Returns the metrics in the Prometheus text exposition format.
+    pub fn render(&self) -> String {
+        let metrics = self.metrics.lock().unwrap();
+        let mut out = String::new();
+        out.push_str("# HELP kvs_operation_duration_seconds Latency of store operations.\n");
+        out.push_str("# TYPE kvs_operation_duration_seconds summary\n");
+        for op in &Operation::ALL {
+            let histogram = &metrics.latencies[*op as usize];
+            for &quantile in &[0.5, 0.9, 0.99] {
+                writeln!(
+                    out,
+                    "kvs_operation_duration_seconds{{op=\"{}\",quantile=\"{}\"}} {}",
+                    op.name(),
+                    quantile,
+                    histogram.percentile(quantile * 100.0).as_secs_f64()
+                )
+                .unwrap();
+            }
+            writeln!(
+                out,
+                "kvs_operation_duration_seconds_sum{{op=\"{}\"}} {}",
+                op.name(),
+                histogram.sum().as_secs_f64()
+            )
+            .unwrap();
+            writeln!(
+                out,
+                "kvs_operation_duration_seconds_count{{op=\"{}\"}} {}",
+                op.name(),
+                histogram.count()
+            )
+            .unwrap();
+        }
+        for counter in &Counter::ALL {
+            writeln!(out, "# TYPE kvs_{}_bytes_total counter", counter.name()).unwrap();
+            writeln!(
+                out,
+                "kvs_{}_bytes_total {}",
+                counter.name(),
+                metrics.counters[*counter as usize]
+            )
+            .unwrap();
+        }
+        for gauge in &Gauge::ALL {
+            writeln!(out, "# TYPE kvs_{}_bytes gauge", gauge.name()).unwrap();
+            writeln!(
+                out,
+                "kvs_{}_bytes {}",
+                gauge.name(),
+                metrics.gauges[*gauge as usize]
+            )
+            .unwrap();
+        }
+        out
+    }
+}
+
+impl MetricsSink for PrometheusSink {
+    fn observe(&self, op: Operation, latency: Duration) {
+        self.metrics.lock().unwrap().latencies[op as usize].record(latency);
+    }
+
+    fn add(&self, counter: Counter, bytes: u64) {
+        self.metrics.lock().unwrap().counters[counter as usize] += bytes;
+    }
+
+    fn set(&self, gauge: Gauge, bytes: u64) {
+        self.metrics.lock().unwrap().gauges[gauge as usize] = bytes;
+    }
+}
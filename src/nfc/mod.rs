@@ -0,0 +1,134 @@
+//! Unicode normalization of keys to NFC, so that canonically equivalent spellings of a
+//! key, such as a composed and a decomposed "é", are the same key.
+
+use std::borrow::Cow;
+
+mod tables;
+
+use self::tables::{COMBINING_CLASSES, COMPOSITIONS, DECOMPOSITIONS};
+
+// Hangul syllables are composed and decomposed algorithmically.
+const S_BASE: u32 = 0xac00;
+const L_BASE: u32 = 0x1100;
+const V_BASE: u32 = 0x1161;
+const T_BASE: u32 = 0x11a7;
+const L_COUNT: u32 = 19;
+const V_COUNT: u32 = 21;
+const T_COUNT: u32 = 28;
+const N_COUNT: u32 = V_COUNT * T_COUNT;
+const S_COUNT: u32 = L_COUNT * N_COUNT;
+
+/// Returns the NFC form of a string, borrowing it if it is already in NFC.
+pub(crate) fn nfc(text: &str) -> Cow<'_, str> {
+    // characters below U+0300 are neither decomposed nor composed with their
+    // predecessors.
+    if text.chars().all(|c| c < '\u{300}') {
+        return Cow::Borrowed(text);
+    }
+    let composed = compose(decompose(text));
+    if composed == text {
+        Cow::Borrowed(text)
+    } else {
+        Cow::Owned(composed)
+    }
+}
+
+/// Returns the NFC form of a string, reusing it if it is already in NFC.
+pub(crate) fn nfc_owned(text: String) -> String {
+    match nfc(&text) {
+        Cow::Owned(normalized) => normalized,
+        Cow::Borrowed(_) => text,
+    }
+}
+
+/// Returns the canonical decomposition of a string in canonical order.
+fn decompose(text: &str) -> Vec<char> {
+    let mut chars = Vec::with_capacity(text.len());
+    for c in text.chars() {
+        let s = (c as u32).wrapping_sub(S_BASE);
+        if s < S_COUNT {
+            chars.push(jamo(L_BASE + s / N_COUNT));
+            chars.push(jamo(V_BASE + (s % N_COUNT) / T_COUNT));
+            if !s.is_multiple_of(T_COUNT) {
+                chars.push(jamo(T_BASE + s % T_COUNT));
+            }
+            continue;
+        }
+        match DECOMPOSITIONS.binary_search_by_key(&c, |&(c, _)| c) {
+            Ok(i) => chars.extend_from_slice(DECOMPOSITIONS[i].1),
+            Err(_) => chars.push(c),
+        }
+    }
+    // sorts every run of combining marks by combining class, keeping the order of
+    // marks of the same class.
+    let mut start = 0;
+    while start < chars.len() {
+        let len = chars[start..]
+            .iter()
+            .take_while(|&&c| combining_class(c) != 0)
+            .count();
+        chars[start..start + len].sort_by_key(|&c| combining_class(c));
+        start += len.max(1);
+    }
+    chars
+}
+
+/// Composes a canonically ordered decomposition.
+fn compose(chars: Vec<char>) -> String {
+    let mut composed: Vec<char> = Vec::with_capacity(chars.len());
+    // the index of the last starter, and the combining class of the last character
+    // after it.
+    let mut starter = None;
+    let mut last_class = 0;
+    for c in chars {
+        let class = combining_class(c);
+        if let Some(i) = starter {
+            let blocked = composed.len() > i + 1 && (last_class == 0 || last_class >= class);
+            if !blocked {
+                if let Some(primary) = compose_pair(composed[i], c) {
+                    composed[i] = primary;
+                    continue;
+                }
+            }
+        }
+        if class == 0 {
+            starter = Some(composed.len());
+        }
+        last_class = class;
+        composed.push(c);
+    }
+    composed.into_iter().collect()
+}
+
+/// Returns the primary composite of two characters, if any.
+fn compose_pair(a: char, b: char) -> Option<char> {
+    let (l, v) = (
+        (a as u32).wrapping_sub(L_BASE),
+        (b as u32).wrapping_sub(V_BASE),
+    );
+    if l < L_COUNT && v < V_COUNT {
+        return Some(jamo(S_BASE + (l * V_COUNT + v) * T_COUNT));
+    }
+    let (s, t) = (
+        (a as u32).wrapping_sub(S_BASE),
+        (b as u32).wrapping_sub(T_BASE),
+    );
+    if s < S_COUNT && s.is_multiple_of(T_COUNT) && t > 0 && t < T_COUNT {
+        return Some(jamo(a as u32 + t));
+    }
+    COMPOSITIONS
+        .binary_search_by_key(&(a, b), |&(pair, _)| pair)
+        .ok()
+        .map(|i| COMPOSITIONS[i].1)
+}
+
+fn combining_class(c: char) -> u8 {
+    match COMBINING_CLASSES.binary_search_by_key(&c, |&(c, _)| c) {
+        Ok(i) => COMBINING_CLASSES[i].1,
+        Err(_) => 0,
+    }
+}
+
+fn jamo(code: u32) -> char {
+    std::char::from_u32(code).expect("Hangul code points are characters")
+}
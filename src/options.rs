@@ -0,0 +1,329 @@
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::compaction::{CompactionMode, CompactionPolicy, Retention, SizeThreshold};
+use crate::comparator::Comparator;
+use crate::metrics::MetricsSink;
+use crate::redact::Redactor;
+
+const DEFAULT_COMPACTION_THRESHOLD: u64 = 1024 * 1024;
+
+/// Options for opening a `KvStore`.
+///
+/// ```rust
+/// # use kvs::{KvStore, Options, Result};
+/// # use kvs::compaction::StaleRatio;
+/// # fn try_main() -> Result<()> {
+/// use std::env::current_dir;
+/// let options = Options::new().compaction_policy(StaleRatio {
+///     ratio: 0.5,
+///     min_bytes: 4096,
+/// });
+/// let mut store = KvStore::open_with_options(current_dir()?, options)?;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone)]
+pub struct Options {
+    pub(crate) compaction_policy: Arc<dyn CompactionPolicy>,
+    pub(crate) compaction_mode: CompactionMode,
+    pub(crate) compaction_rate_limit: Option<u64>,
+    pub(crate) retention: Option<Retention>,
+    pub(crate) verify: VerifyLevel,
+    pub(crate) recovery: Recovery,
+    pub(crate) metrics: Option<Arc<dyn MetricsSink>>,
+    pub(crate) preallocate: Option<u64>,
+    pub(crate) purge_jitter: Duration,
+    pub(crate) max_purge: usize,
+    pub(crate) exclusive: bool,
+    pub(crate) shared_index: bool,
+    pub(crate) comparator: Option<Arc<dyn Comparator>>,
+    pub(crate) normalize_keys: bool,
+    pub(crate) trace: Option<PathBuf>,
+    pub(crate) redactor: Option<Arc<dyn Redactor>>,
+    pub(crate) cache_capacity: Option<u64>,
+    pub(crate) write_buffer: usize,
+    pub(crate) write_stall: Option<(u64, u64)>,
+    pub(crate) max_log_size: Option<u64>,
+    pub(crate) warm: Option<(HotSet, u64)>,
+}
+
+impl Options {
+    /// Creates the default options.
+    pub fn new() -> Options {
+        Options::default()
+    }
+
+    /// Sets the policy deciding when the log is compacted.
+    pub fn compaction_policy(mut self, policy: impl CompactionPolicy + 'static) -> Options {
+        self.compaction_policy = Arc::new(policy);
+        self
+    }
+
+    /// Sets whether a triggered compaction rewrites the whole log or a single log file.
+    ///
+    /// Defaults to `CompactionMode::Full`.
+    pub fn compaction_mode(mut self, mode: CompactionMode) -> Options {
+        self.compaction_mode = mode;
+        self
+    }
+
+    /// Limits the bandwidth used by compaction to the given number of bytes per second.
+    ///
+    /// Compaction copies live entries at full speed by default, which can starve
+    /// other readers and writers of the disk on slow devices.
+    pub fn compaction_rate_limit(mut self, bytes_per_sec: u64) -> Options {
+        self.compaction_rate_limit = Some(bytes_per_sec);
+        self
+    }
+
+    /// Drops entries older than the retention policy allows during compaction.
+    pub fn retention(mut self, retention: Retention) -> Options {
+        self.retention = Some(retention);
+        self
+    }
+
+    /// Sets how thoroughly log files are checked when the store is opened.
+    ///
+    /// Defaults to `VerifyLevel::Quick`.
+    pub fn verify(mut self, level: VerifyLevel) -> Options {
+        self.verify = level;
+        self
+    }
+
+    /// Sets how corrupt records in the log are handled.
+    ///
+    /// Defaults to `Recovery::Strict`.
+    pub fn recovery(mut self, recovery: Recovery) -> Options {
+        self.recovery = recovery;
+        self
+    }
+
+    /// Reserves disk space for the given number of bytes whenever a new log file is
+    /// started, as a hint of how large log files are expected to grow.
+    ///
+    /// Preallocated log files are less fragmented, and running out of disk space is
+    /// reported up front with `KvsError::NoSpace` rather than by a failing write. It
+    /// has no effect on platforms other than Linux.
+    pub fn preallocate(mut self, bytes: u64) -> Options {
+        self.preallocate = Some(bytes);
+        self
+    }
+
+    /// Delays purging each expired key by up to the given jitter, so that keys which
+    /// expire together aren't all purged by the same `KvStore::purge_expired` call.
+    ///
+    /// Expired keys are never returned by reads, whether purged or not.
+    pub fn purge_jitter(mut self, jitter: Duration) -> Options {
+        self.purge_jitter = jitter;
+        self
+    }
+
+    /// Limits the number of expired keys a `KvStore::purge_expired` call removes.
+    ///
+    /// The remaining expired keys are removed by later calls.
+    pub fn max_purge(mut self, keys: usize) -> Options {
+        self.max_purge = keys;
+        self
+    }
+
+    /// Locks the store directory while the store is open, so that no other process
+    /// opens it with this option.
+    ///
+    /// Other processes can still read the store with `KvReader`. It has no effect on
+    /// platforms other than Linux.
+    pub fn exclusive(mut self, exclusive: bool) -> Options {
+        self.exclusive = exclusive;
+        self
+    }
+
+    /// Publishes the index in a file mapped into the memory of `KvReader`s, so that
+    /// they see writes without reading the log themselves.
+    ///
+    /// Every write also updates the shared index, which takes 64 bytes per slot and
+    /// twice as many slots as keys. Opening fails on platforms other than Linux.
+    pub fn shared_index(mut self, shared_index: bool) -> Options {
+        self.shared_index = shared_index;
+        self
+    }
+
+    /// Sets the order and equality of keys of a new store.
+    ///
+    /// The comparator is recorded in the store when it is created. Without this option,
+    /// a store is opened with the provided comparator it was created with, and new
+    /// stores order keys by bytes. A store created with a custom comparator has to be
+    /// opened with a comparator of the same name.
+    ///
+    /// With a comparator other than `Bytewise`, prefix queries scan the whole index
+    /// and `shared_index` has no effect.
+    pub fn comparator(mut self, comparator: impl Comparator + 'static) -> Options {
+        self.comparator = Some(Arc::new(comparator));
+        self
+    }
+
+    /// Normalizes the keys of a new store to NFC, so that canonically equivalent
+    /// spellings of a key, such as a composed and a decomposed "é", are the same key.
+    ///
+    /// The normalization is recorded in the store when it is created, and applied to
+    /// the keys passed to reads and writes whenever the store is opened. Prefixes and
+    /// patterns are not normalized.
+    pub fn normalize_keys(mut self, normalize: bool) -> Options {
+        self.normalize_keys = normalize;
+        self
+    }
+
+    /// Buffers writes in memory until the given bytes of log records are pending, so
+    /// that bursts of small writes reach the log in a few large writes.
+    ///
+    /// Buffered entries are read from memory. They are written to the log by
+    /// [`KvStore::flush`](crate::KvStore::flush), by scans and compactions, and when
+    /// the store is dropped, but are lost if the process crashes first or writing the
+    /// log fails. The default of 0 writes every command to the log immediately.
+    pub fn write_buffer(mut self, bytes: usize) -> Options {
+        self.write_buffer = bytes;
+        self
+    }
+
+    /// Starts a new log file once the current one reaches the given bytes.
+    ///
+    /// Smaller log files let `CompactionMode::OldestSegment` and the other incremental
+    /// modes reclaim space in smaller steps. A log file exceeds the size by at most one
+    /// command; a full compaction still writes all live entries to one log file.
+    pub fn max_log_size(mut self, bytes: u64) -> Options {
+        self.max_log_size = Some(bytes);
+        self
+    }
+
+    /// Stalls writes while compaction falls behind, so that stale data can't grow
+    /// without bound.
+    ///
+    /// Once the bytes a compaction would reclaim reach `slowdown`, every write is
+    /// delayed, by up to 10 ms as they approach `stop`. From `stop` on, writes fail
+    /// with `KvsError::Busy` until a compaction brings the stale bytes down. Stalled
+    /// and rejected writes are counted in `Stats`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `slowdown` is greater than `stop`.
+    pub fn write_stall(mut self, slowdown: u64, stop: u64) -> Options {
+        assert!(slowdown <= stop, "slowdown limit above the stop limit");
+        self.write_stall = Some((slowdown, stop));
+        self
+    }
+
+    /// Turns the store into a persistent LRU cache holding at most the given bytes of
+    /// live log records.
+    ///
+    /// When a write exceeds the capacity, the least recently read or written keys are
+    /// removed, writing "remove" commands like `remove` does. The key just written is
+    /// never evicted. Recency is not persisted: on opening, keys written later count
+    /// as used more recently.
+    pub fn cache_capacity(mut self, bytes: u64) -> Options {
+        self.cache_capacity = Some(bytes);
+        self
+    }
+
+    /// Reads the log records of the given hot set of keys, up to the given bytes of
+    /// them, when the store is opened, so that the first reads after a restart find
+    /// them in the OS cache.
+    ///
+    /// With `HotSet::Frequent`, reads of keys are counted while the store is open, and
+    /// the keys read most often are saved when it is dropped. The warmed bytes are
+    /// counted in `Stats`.
+    pub fn warm(mut self, hot_set: HotSet, bytes: u64) -> Options {
+        self.warm = Some((hot_set, bytes));
+        self
+    }
+
+    /// Records the `get`, `set` and `remove` calls to a trace file, which is replaced
+    /// when the store is opened.
+    ///
+    /// The trace can be replayed with [`trace::replay`](crate::trace::replay) or
+    /// `kvs replay`.
+    pub fn record_trace(mut self, path: impl Into<PathBuf>) -> Options {
+        self.trace = Some(path.into());
+        self
+    }
+
+    /// Rewrites the values recorded by `record_trace` with the given
+    /// [`Redactor`](crate::redact::Redactor), so that traces can be shared safely.
+    ///
+    /// The values stored are not affected.
+    pub fn redactor(mut self, redactor: impl Redactor + 'static) -> Options {
+        self.redactor = Some(Arc::new(redactor));
+        self
+    }
+
+    /// Reports operation latencies and byte counters to the given sink, in addition to
+    /// the histograms returned by `KvStore::stats`.
+    pub fn metrics_sink(mut self, sink: impl MetricsSink + 'static) -> Options {
+        self.metrics = Some(Arc::new(sink));
+        self
+    }
+}
+
+impl Default for Options {
+    fn default() -> Options {
+        Options {
+            compaction_policy: Arc::new(SizeThreshold(DEFAULT_COMPACTION_THRESHOLD)),
+            compaction_mode: CompactionMode::Full,
+            compaction_rate_limit: None,
+            retention: None,
+            verify: VerifyLevel::Quick,
+            recovery: Recovery::Strict,
+            metrics: None,
+            preallocate: None,
+            purge_jitter: Duration::from_secs(0),
+            max_purge: usize::MAX,
+            exclusive: false,
+            shared_index: false,
+            comparator: None,
+            normalize_keys: false,
+            trace: None,
+            redactor: None,
+            cache_capacity: None,
+            write_buffer: 0,
+            write_stall: None,
+            max_log_size: None,
+            warm: None,
+        }
+    }
+}
+
+/// How thoroughly log files are checked against the manifest when a store is opened.
+///
+/// The manifest records the length and checksum of every log file that is no longer
+/// written to. Opening fails with `KvsError::CorruptedLog` if a log file is missing
+/// or differs from it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum VerifyLevel {
+    /// Compares the lengths and the checksums of the whole log files.
+    Full,
+    /// Compares the lengths of the log files.
+    Quick,
+    /// Skips the checks.
+    None,
+}
+
+/// How corrupt records in the log are handled.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Recovery {
+    /// Fails to open the store.
+    Strict,
+    /// Copies the corrupt data up to the next readable record to a file in the
+    /// `quarantine` directory of the store, logs a warning and skips it.
+    ///
+    /// Entries in the corrupt data are lost; the space is reclaimed by compaction.
+    Quarantine,
+}
+
+/// The keys whose log records `Options::warm` reads when a store is opened.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HotSet {
+    /// The keys written most recently.
+    Recent,
+    /// The keys read most often while the store was last open, or the keys written
+    /// most recently if it wasn't dropped cleanly with this option.
+    Frequent,
+}
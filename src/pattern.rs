@@ -0,0 +1,216 @@
+#[cfg(feature = "regex")]
+use crate::{KvsError, Result};
+
+/// A pattern selecting keys.
+///
+/// Keys are matched against the part of the ordered index starting with the literal
+/// prefix of the pattern, so patterns with a long prefix are cheap to evaluate.
+///
+/// ```rust
+/// # use kvs::KeyPattern;
+/// let pattern = KeyPattern::glob("user:*:email");
+/// assert!(pattern.matches("user:42:email"));
+/// assert!(!pattern.matches("user:42:name"));
+/// assert_eq!(pattern.prefix(), "user:");
+/// ```
+#[derive(Clone, Debug)]
+pub struct KeyPattern {
+    kind: Kind,
+    prefix: String,
+}
+
+#[derive(Clone, Debug)]
+enum Kind {
+    Glob(Vec<Token>),
+    #[cfg(feature = "regex")]
+    Regex(regex::Regex),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Token {
+    Char(char),
+    // `?`
+    Any,
+    // `*`
+    Star,
+    // `[...]`, with inclusive ranges and whether it is negated.
+    Class(Vec<(char, char)>, bool),
+}
+
+impl KeyPattern {
+    /// Creates a glob pattern.
+    ///
+    /// `*` matches any sequence of characters, `?` matches a single character, and
+    /// `[abc]`, `[a-z]` and `[^abc]` match a character of a set. A backslash matches
+    /// the following character literally.
+    pub fn glob(pattern: &str) -> KeyPattern {
+        let tokens = parse_glob(pattern);
+        let prefix = tokens
+            .iter()
+            .map_while(|token| match token {
+                Token::Char(c) => Some(*c),
+                _ => None,
+            })
+            .collect();
+        KeyPattern {
+            kind: Kind::Glob(tokens),
+            prefix,
+        }
+    }
+
+    /// Creates a regular expression pattern, which matches keys containing a match.
+    ///
+    /// Only patterns anchored with `^` have a literal prefix.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::InvalidPattern` if the regular expression is invalid.
+    #[cfg(feature = "regex")]
+    pub fn regex(pattern: &str) -> Result<KeyPattern> {
+        let regex =
+            regex::Regex::new(pattern).map_err(|e| KvsError::InvalidPattern(e.to_string()))?;
+        Ok(KeyPattern {
+            kind: Kind::Regex(regex),
+            prefix: regex_prefix(pattern),
+        })
+    }
+
+    /// Returns whether a key matches the pattern.
+    pub fn matches(&self, key: &str) -> bool {
+        match &self.kind {
+            Kind::Glob(tokens) => {
+                let key: Vec<char> = key.chars().collect();
+                glob_matches(tokens, &key)
+            }
+            #[cfg(feature = "regex")]
+            Kind::Regex(regex) => regex.is_match(key),
+        }
+    }
+
+    /// Returns the prefix all matching keys start with.
+    pub fn prefix(&self) -> &str {
+        &self.prefix
+    }
+}
+
+fn parse_glob(pattern: &str) -> Vec<Token> {
+    let chars: Vec<char> = pattern.chars().collect();
+    let mut tokens = Vec::new();
+    let mut i = 0;
+    while i < chars.len() {
+        let token = match chars[i] {
+            '*' => Token::Star,
+            '?' => Token::Any,
+            '\\' if i + 1 < chars.len() => {
+                i += 1;
+                Token::Char(chars[i])
+            }
+            '[' => match parse_class(&chars[i + 1..]) {
+                Some((token, len)) => {
+                    i += len;
+                    token
+                }
+                // an unterminated class is matched literally.
+                None => Token::Char('['),
+            },
+            c => Token::Char(c),
+        };
+        tokens.push(token);
+        i += 1;
+    }
+    tokens
+}
+
+/// Parses a character class after its `[`.
+///
+/// Returns the class and the number of characters up to and including its `]`.
+fn parse_class(chars: &[char]) -> Option<(Token, usize)> {
+    let negated = chars.first() == Some(&'^');
+    let mut i = if negated { 1 } else { 0 };
+    let mut ranges = Vec::new();
+    // a `]` right after the `[` is a member of the class.
+    let mut first = true;
+    while i < chars.len() {
+        let c = match chars[i] {
+            ']' if !first => return Some((Token::Class(ranges, negated), i + 1)),
+            '\\' if i + 1 < chars.len() => {
+                i += 1;
+                chars[i]
+            }
+            c => c,
+        };
+        first = false;
+        if i + 2 < chars.len() && chars[i + 1] == '-' && chars[i + 2] != ']' {
+            ranges.push((c, chars[i + 2]));
+            i += 3;
+        } else {
+            ranges.push((c, c));
+            i += 1;
+        }
+    }
+    None
+}
+
+fn glob_matches(tokens: &[Token], key: &[char]) -> bool {
+    // backtracking to the last `*` is enough, because a later `*` can match anything
+    // an earlier one would have.
+    let (mut t, mut k) = (0, 0);
+    let mut star = None;
+    while k < key.len() {
+        match tokens.get(t) {
+            Some(Token::Star) => {
+                star = Some((t, k));
+                t += 1;
+                continue;
+            }
+            Some(token) if token_matches(token, key[k]) => {
+                t += 1;
+                k += 1;
+                continue;
+            }
+            _ => {}
+        }
+        match star {
+            Some((star_t, star_k)) => {
+                t = star_t + 1;
+                k = star_k + 1;
+                star = Some((star_t, star_k + 1));
+            }
+            None => return false,
+        }
+    }
+    tokens[t..].iter().all(|token| *token == Token::Star)
+}
+
+fn token_matches(token: &Token, c: char) -> bool {
+    match token {
+        Token::Char(expected) => *expected == c,
+        Token::Any => true,
+        Token::Star => false,
+        Token::Class(ranges, negated) => {
+            ranges.iter().any(|&(low, high)| low <= c && c <= high) != *negated
+        }
+    }
+}
+
+/// Returns the literal prefix of a regular expression anchored with `^`.
+#[cfg(feature = "regex")]
+fn regex_prefix(pattern: &str) -> String {
+    let mut prefix = String::new();
+    let rest = match pattern.strip_prefix('^') {
+        Some(rest) => rest,
+        None => return prefix,
+    };
+    for c in rest.chars() {
+        match c {
+            // a quantifier applies to the last character.
+            '?' | '*' | '{' => {
+                prefix.pop();
+                break;
+            }
+            '\\' | '.' | '+' | '(' | ')' | '|' | '[' | ']' | '^' | '$' => break,
+            c => prefix.push(c),
+        }
+    }
+    prefix
+}
@@ -0,0 +1,87 @@
+use std::collections::BTreeMap;
+use std::ops::Bound;
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
+/// Settings applied to the keys starting with a prefix, configured with
+/// [`KvStore::configure_prefix`](crate::KvStore::configure_prefix).
+///
+/// ```rust
+/// # use kvs::{KvStore, PrefixConfig, Result};
+/// # use std::time::Duration;
+/// # fn try_main() -> Result<()> {
+/// # let mut store = KvStore::open(std::env::current_dir()?)?;
+/// let sessions = PrefixConfig::new()
+///     .ttl(Duration::from_secs(3600))
+///     .max_value_len(4096);
+/// store.configure_prefix("sessions:", sessions)?;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq, Eq)]
+pub struct PrefixConfig {
+    // default TTL in milliseconds.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    ttl: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    max_value_len: Option<usize>,
+    #[serde(default, skip_serializing_if = "is_false")]
+    pinned: bool,
+}
+
+fn is_false(b: &bool) -> bool {
+    !*b
+}
+
+impl PrefixConfig {
+    /// Creates a configuration that changes nothing.
+    pub fn new() -> PrefixConfig {
+        PrefixConfig::default()
+    }
+
+    /// Sets the TTL of values written without one, as with `set`.
+    ///
+    /// Values written with `set_with_ttl` keep their own TTL.
+    pub fn ttl(mut self, ttl: Duration) -> PrefixConfig {
+        self.ttl = Some(ttl.as_millis() as u64);
+        self
+    }
+
+    /// Rejects values longer than the given bytes with `KvsError::ValueTooLarge`.
+    pub fn max_value_len(mut self, len: usize) -> PrefixConfig {
+        self.max_value_len = Some(len);
+        self
+    }
+
+    /// Exempts the keys from eviction with `Options::cache_capacity`.
+    pub fn pinned(mut self, pinned: bool) -> PrefixConfig {
+        self.pinned = pinned;
+        self
+    }
+
+    pub(crate) fn default_ttl(&self) -> Option<Duration> {
+        self.ttl.map(Duration::from_millis)
+    }
+
+    pub(crate) fn value_limit(&self) -> Option<usize> {
+        self.max_value_len
+    }
+
+    pub(crate) fn is_pinned(&self) -> bool {
+        self.pinned
+    }
+}
+
+/// Returns the configuration of the longest configured prefix of a key.
+pub(crate) fn lookup<'a>(
+    configs: &'a BTreeMap<String, PrefixConfig>,
+    key: &str,
+) -> Option<&'a PrefixConfig> {
+    // prefixes of the key sort before it, the longest one last.
+    configs
+        .range::<str, _>((Bound::Unbounded, Bound::Included(key)))
+        .rev()
+        .find(|(prefix, _)| key.starts_with(prefix.as_str()))
+        .map(|(_, config)| config)
+}
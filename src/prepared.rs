@@ -0,0 +1,105 @@
+use std::collections::{BTreeMap, HashSet};
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+use log::warn;
+use serde_json::Value;
+
+use crate::{Result, WriteBatch};
+
+const PREPARED_DIR: &str = "prepared";
+// the metadata key naming the prepared batch a batch record commits.
+const META: &str = "prepared";
+
+/// A batch prepared with [`KvStore::prepare`](crate::KvStore::prepare), to be committed
+/// or rolled back by a transaction coordinator.
+///
+/// Its id stays valid across restarts, so a coordinator may record it and rebuild the
+/// token with `PreparedToken::from_id`.
+#[derive(Clone, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct PreparedToken {
+    id: String,
+}
+
+impl PreparedToken {
+    /// Rebuilds the token of the prepared batch with the given id.
+    pub fn from_id(id: String) -> PreparedToken {
+        PreparedToken { id }
+    }
+
+    /// Returns the unique id of the prepared batch.
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+}
+
+/// Returns the metadata of the batch record committing the prepared batch with the
+/// given id.
+pub(crate) fn meta(id: &str) -> BTreeMap<String, Value> {
+    let mut meta = BTreeMap::new();
+    meta.insert(META.to_owned(), Value::String(id.to_owned()));
+    meta
+}
+
+/// Returns the id of the prepared batch a batch record with the given metadata
+/// commits, if any.
+pub(crate) fn committed(meta: &BTreeMap<String, Value>) -> Option<&str> {
+    meta.get(META).and_then(Value::as_str)
+}
+
+/// Writes a prepared batch to the store in the given directory, synced to disk.
+pub(crate) fn save(dir: &Path, id: &str, batch: &WriteBatch) -> Result<()> {
+    fs::create_dir_all(dir.join(PREPARED_DIR))?;
+    let path = prepared_path(dir, id);
+    let tmp_path = path.with_extension("tmp");
+    let mut writer = BufWriter::new(File::create(&tmp_path)?);
+    serde_json::to_writer(&mut writer, batch)?;
+    writer.flush()?;
+    writer.get_ref().sync_all()?;
+    fs::rename(&tmp_path, path)?;
+    Ok(())
+}
+
+/// Reads the prepared batches of the store in the given directory, removing those the
+/// log shows were committed with the given ids.
+pub(crate) fn recover(
+    dir: &Path,
+    committed: &HashSet<String>,
+) -> Result<BTreeMap<String, WriteBatch>> {
+    let mut prepared = BTreeMap::new();
+    let entries = match fs::read_dir(dir.join(PREPARED_DIR)) {
+        Ok(entries) => entries,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(prepared),
+        Err(e) => return Err(e.into()),
+    };
+    for entry in entries {
+        let path = entry?.path();
+        let id = match path.file_name().and_then(|name| name.to_str()) {
+            Some(id) if path.extension() != Some("tmp".as_ref()) => id.to_owned(),
+            // a batch whose preparation was cut short was never prepared.
+            _ => {
+                warn!("removed leftover file {}", path.display());
+                fs::remove_file(&path)?;
+                continue;
+            }
+        };
+        if committed.contains(&id) {
+            fs::remove_file(&path)?;
+            continue;
+        }
+        let batch = serde_json::from_reader(BufReader::new(File::open(&path)?))?;
+        prepared.insert(id, batch);
+    }
+    Ok(prepared)
+}
+
+/// Removes a prepared batch of the store in the given directory.
+pub(crate) fn remove(dir: &Path, id: &str) -> Result<()> {
+    fs::remove_file(prepared_path(dir, id))?;
+    Ok(())
+}
+
+fn prepared_path(dir: &Path, id: &str) -> PathBuf {
+    dir.join(PREPARED_DIR).join(id)
+}
@@ -0,0 +1,332 @@
+//! The request/response protocol of `kvs-server`.
+//!
+//! Every message is a frame: a 4-byte big-endian length followed by that many bytes
+//! of JSON, such as `{"op":"get","key":"key1"}`. Frames are read defensively: the
+//! length is checked against [`Limits`] before anything is allocated, and malformed
+//! frames are reported as errors, so a malicious peer can neither exhaust memory nor
+//! cause a panic.
+
+use std::io::{self, Read, Write};
+use std::str::FromStr;
+
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+
+use crate::{KvsError, Result};
+
+// the largest allocation made up front for a frame; longer frames grow as they arrive.
+const INITIAL_CAPACITY: usize = 64 * 1024;
+
+/// The protocol spoken by `KvsServer`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Protocol {
+    /// The length-prefixed JSON frames of this module, used by `KvsClient`.
+    #[default]
+    Kvs,
+    /// The Redis protocol of [`resp`](crate::resp), for redis clients and `redis-cli`.
+    Resp,
+}
+
+impl Protocol {
+    /// The names accepted by `from_str`.
+    pub const NAMES: &'static [&'static str] = &["kvs", "resp"];
+}
+
+impl FromStr for Protocol {
+    type Err = KvsError;
+
+    fn from_str(name: &str) -> Result<Protocol> {
+        match name {
+            "kvs" => Ok(Protocol::Kvs),
+            "resp" => Ok(Protocol::Resp),
+            _ => Err(KvsError::UnknownProtocol(name.to_owned())),
+        }
+    }
+}
+
+/// A request sent to the server.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+#[serde(tag = "op", rename_all = "lowercase")]
+pub enum Request {
+    /// Reads the value of a key.
+    Get {
+        /// The key.
+        key: String,
+    },
+    /// Writes the value of a key.
+    Set {
+        /// The key.
+        key: String,
+        /// The value.
+        value: String,
+    },
+    /// Removes a key.
+    Remove {
+        /// The key.
+        key: String,
+    },
+    /// Reads the remaining time to live of a key in milliseconds, or `None` if it does
+    /// not expire.
+    Ttl {
+        /// The key.
+        key: String,
+    },
+    /// Sets the time to live of a key, replacing any previous one.
+    Expire {
+        /// The key.
+        key: String,
+        /// The time to live in milliseconds.
+        millis: u64,
+    },
+    /// Removes the time to live of a key.
+    Persist {
+        /// The key.
+        key: String,
+    },
+    /// Copies the value of a key to another key on the server, answered with `"true"`
+    /// if it was copied and `"false"` if `dst` exists and is kept.
+    Copy {
+        /// The key copied.
+        key: String,
+        /// The key written.
+        dst: String,
+        /// Whether an existing `dst` is overwritten.
+        #[serde(default)]
+        overwrite: bool,
+    },
+}
+
+impl Request {
+    /// Returns the key of the request, which is the source key of `Copy`.
+    pub fn key(&self) -> &str {
+        match self {
+            Request::Get { key }
+            | Request::Set { key, .. }
+            | Request::Remove { key }
+            | Request::Ttl { key }
+            | Request::Expire { key, .. }
+            | Request::Persist { key }
+            | Request::Copy { key, .. } => key,
+        }
+    }
+}
+
+/// The response of the server to a request.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+#[serde(tag = "status", content = "body", rename_all = "lowercase")]
+pub enum Response {
+    /// The request succeeded, with the value of a `get`, the time to live of a `ttl` or
+    /// the outcome of a `copy` if any.
+    Ok(Option<String>),
+    /// The request failed, such as
+    /// `{"code":"busy","retryable":true,"message":"Store is busy until ..."}`.
+    Err(ProtocolError),
+}
+
+/// Why the server failed a request.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    /// The key of the request does not exist.
+    NotFound,
+    /// The store rejected a write until stale data is compacted.
+    Busy,
+    /// The store only serves reads after a failed write.
+    ReadOnly,
+    /// The client is not allowed to make the request.
+    Unauthorized,
+    /// The request is not a valid frame or exceeds the limits of the server.
+    InvalidRequest,
+    /// The request failed on the server for another reason, such as an I/O error.
+    Internal,
+}
+
+impl ErrorCode {
+    /// Returns whether the same request may succeed if it is sent again later.
+    ///
+    /// Only `Busy` is retryable: the other failures persist until the request or the
+    /// store is changed.
+    pub fn retryable(self) -> bool {
+        self == ErrorCode::Busy
+    }
+}
+
+impl From<&KvsError> for ErrorCode {
+    fn from(e: &KvsError) -> ErrorCode {
+        match e {
+            KvsError::KeyNotFound => ErrorCode::NotFound,
+            KvsError::Busy => ErrorCode::Busy,
+            KvsError::ReadOnly => ErrorCode::ReadOnly,
+            KvsError::Unauthorized => ErrorCode::Unauthorized,
+            KvsError::FrameTooLarge { .. }
+            | KvsError::InvalidRequest(_)
+            | KvsError::Unsupported(_) => ErrorCode::InvalidRequest,
+            _ => ErrorCode::Internal,
+        }
+    }
+}
+
+/// The body of `Response::Err`.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct ProtocolError {
+    /// Why the request failed.
+    pub code: ErrorCode,
+    /// `code.retryable()`, for clients that don't know every code.
+    pub retryable: bool,
+    /// A description of the failure for humans.
+    pub message: String,
+}
+
+impl From<&KvsError> for ProtocolError {
+    fn from(e: &KvsError) -> ProtocolError {
+        let code = ErrorCode::from(e);
+        ProtocolError {
+            code,
+            retryable: code.retryable(),
+            message: e.to_string(),
+        }
+    }
+}
+
+/// Limits of the frames read with this module.
+///
+/// ```rust
+/// # use kvs::protocol::Limits;
+/// let limits = Limits::new().max_frame(1024 * 1024).max_key_len(1024);
+/// ```
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Limits {
+    pub(crate) max_frame: usize,
+    max_key_len: usize,
+}
+
+impl Limits {
+    /// Creates the default limits: frames of up to 4 MiB and keys of up to 64 KiB.
+    pub fn new() -> Limits {
+        Limits {
+            max_frame: 4 * 1024 * 1024,
+            max_key_len: 64 * 1024,
+        }
+    }
+
+    /// Sets the largest frame in bytes, excluding its length prefix.
+    pub fn max_frame(mut self, bytes: usize) -> Limits {
+        self.max_frame = bytes;
+        self
+    }
+
+    /// Sets the longest key of a request in bytes.
+    pub fn max_key_len(mut self, bytes: usize) -> Limits {
+        self.max_key_len = bytes;
+        self
+    }
+}
+
+impl Limits {
+    /// Checks a key of a request against `max_key_len`.
+    pub(crate) fn check_key(&self, key: &str) -> Result<()> {
+        if key.len() > self.max_key_len {
+            return Err(KvsError::InvalidRequest(format!(
+                "key of {} bytes exceeds the limit of {} bytes",
+                key.len(),
+                self.max_key_len
+            )));
+        }
+        Ok(())
+    }
+}
+
+impl Default for Limits {
+    fn default() -> Limits {
+        Limits::new()
+    }
+}
+
+/// Reads a request, or `None` if the peer closed the connection before sending one.
+///
+/// # Errors
+///
+/// It returns `KvsError::FrameTooLarge` if the frame exceeds `Limits::max_frame`, and
+/// `KvsError::InvalidRequest` if it is not a valid request.
+pub fn read_request(reader: &mut impl Read, limits: &Limits) -> Result<Option<Request>> {
+    let request: Option<Request> = read_frame(reader, limits)?;
+    if let Some(request) = &request {
+        limits.check_key(request.key())?;
+        if let Request::Copy { dst, .. } = request {
+            limits.check_key(dst)?;
+        }
+    }
+    Ok(request)
+}
+
+/// Reads a response.
+///
+/// # Errors
+///
+/// It returns `KvsError::FrameTooLarge` if the frame exceeds `Limits::max_frame`, and
+/// an I/O error if the connection is closed first.
+pub fn read_response(reader: &mut impl Read, limits: &Limits) -> Result<Response> {
+    read_frame(reader, limits)?.ok_or_else(|| io::Error::from(io::ErrorKind::UnexpectedEof).into())
+}
+
+/// Writes a request.
+///
+/// # Errors
+///
+/// It returns `KvsError::FrameTooLarge` if the request exceeds `Limits::max_frame`.
+pub fn write_request(writer: &mut impl Write, request: &Request, limits: &Limits) -> Result<()> {
+    write_frame(writer, request, limits)
+}
+
+/// Writes a response.
+///
+/// # Errors
+///
+/// It returns `KvsError::FrameTooLarge` if the response exceeds `Limits::max_frame`.
+pub fn write_response(writer: &mut impl Write, response: &Response, limits: &Limits) -> Result<()> {
+    write_frame(writer, response, limits)
+}
+
+fn read_frame<T: DeserializeOwned>(reader: &mut impl Read, limits: &Limits) -> Result<Option<T>> {
+    let mut prefix = [0; 4];
+    let mut filled = 0;
+    while filled < prefix.len() {
+        match reader.read(&mut prefix[filled..]) {
+            Ok(0) if filled == 0 => return Ok(None),
+            Ok(0) => return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into()),
+            Ok(len) => filled += len,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e.into()),
+        }
+    }
+    let len = u32::from_be_bytes(prefix) as usize;
+    if len > limits.max_frame {
+        return Err(KvsError::FrameTooLarge {
+            len,
+            limit: limits.max_frame,
+        });
+    }
+    // the length is only a claim of the peer, so memory is committed as bytes arrive.
+    let mut frame = Vec::with_capacity(len.min(INITIAL_CAPACITY));
+    reader.take(len as u64).read_to_end(&mut frame)?;
+    if frame.len() < len {
+        return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+    }
+    serde_json::from_slice(&frame)
+        .map(Some)
+        .map_err(|e| KvsError::InvalidRequest(e.to_string()))
+}
+
+fn write_frame<T: Serialize>(writer: &mut impl Write, msg: &T, limits: &Limits) -> Result<()> {
+    let frame = serde_json::to_vec(msg)?;
+    if frame.len() > limits.max_frame || frame.len() > u32::MAX as usize {
+        return Err(KvsError::FrameTooLarge {
+            len: frame.len(),
+            limit: limits.max_frame,
+        });
+    }
+    writer.write_all(&(frame.len() as u32).to_be_bytes())?;
+    writer.write_all(&frame)?;
+    writer.flush()?;
+    Ok(())
+}
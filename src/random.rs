@@ -0,0 +1,63 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// A small, fast pseudo-random number generator (SplitMix64).
+///
+/// It is not suitable for cryptographic purposes.
+pub(crate) struct Rng {
+    state: u64,
+}
+
+impl Rng {
+    /// Creates a generator from a seed. The same seed yields the same sequence.
+    pub(crate) fn with_seed(seed: u64) -> Rng {
+        Rng { state: seed }
+    }
+
+    /// Creates a generator seeded from the clock.
+    pub(crate) fn new() -> Rng {
+        // distinguishes generators created within the same clock tick.
+        static COUNTER: AtomicU64 = AtomicU64::new(0);
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_nanos() as u64)
+            .unwrap_or(0);
+        Rng::with_seed(nanos ^ COUNTER.fetch_add(1, Ordering::Relaxed).rotate_left(32))
+    }
+
+    /// Returns the next random `u64`.
+    pub(crate) fn next_u64(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    /// Returns a uniform draw in `[0, 1)` with 53 bits of precision.
+    pub(crate) fn unit(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    /// Returns a random number in `0..n`. `n` must not be 0.
+    pub(crate) fn below(&mut self, n: u64) -> u64 {
+        // the modulo bias is negligible for the sizes used here.
+        self.next_u64() % n
+    }
+}
+
+/// Returns a random (version 4) UUID in its hyphenated form.
+pub(crate) fn uuid() -> String {
+    let mut rng = Rng::new();
+    let (high, low) = (rng.next_u64(), rng.next_u64());
+    let high = (high & !0xf000) | 0x4000;
+    let low = (low & !(0b11 << 62)) | (0b10 << 62);
+    format!(
+        "{:08x}-{:04x}-{:04x}-{:04x}-{:012x}",
+        high >> 32,
+        (high >> 16) & 0xffff,
+        high & 0xffff,
+        low >> 48,
+        low & 0xffff_ffff_ffff
+    )
+}
@@ -0,0 +1,485 @@
+use std::borrow::Cow;
+use std::collections::BTreeMap;
+use std::convert::{TryFrom, TryInto};
+use std::ops::Range;
+use std::str;
+
+use serde::de::Error;
+use serde::{Deserialize, Serialize};
+use serde_json::{Deserializer, Map, Value};
+
+use crate::crc::Crc32;
+use crate::VectorClock;
+
+/// Metadata key marking a record that readers may ignore if they don't know its type.
+const SKIPPABLE: &str = "skippable";
+/// The start of a JSON record with a checksum.
+const CRC_PREFIX: &[u8] = b"{\"crc\":\"";
+/// The first byte of a binary record, which can't start a JSON record, nor occur in
+/// UTF-8 text.
+pub(crate) const BINARY: u8 = 0xff;
+/// The first byte of a binary record in a batch record, which is only read through
+/// the index, so that resyncing after corrupt data never applies part of a batch.
+const BATCHED: u8 = 0xfe;
+/// The length of the header of a binary record: `BINARY`, and the length and CRC-32
+/// of the payload as little-endian `u32`s.
+pub(crate) const HEADER_LEN: usize = 9;
+
+// the types of binary records.
+const SET: u8 = 1;
+const REMOVE: u8 = 2;
+const EXPIRE: u8 = 3;
+const RENAME: u8 = 4;
+const BATCH: u8 = 5;
+
+/// A record of the log.
+///
+/// Records are written by [`encode`] in a binary format: a header of `BINARY`, the
+/// length of the payload and its CRC-32, followed by the payload. The payload is the
+/// type of the command, the metadata, and the fields of the command in order. Integers
+/// are LEB128 varints, strings are prefixed with their length, options with a 0 or 1
+/// byte, and the metadata and vector clocks are JSON, or empty if they are.
+///
+/// Records of older versions are JSON objects with a `type` tag naming the command,
+/// the fields of the command, and an optional `meta` map. They may start with a `crc`
+/// field, the CRC-32 of the rest of the record in hex, checked with [`checksum_ok`]:
+///
+/// ```json
+/// {"crc":"77d1d775","type":"Set","key":"k","value":"v"}
+/// {"type":"Set","key":"k","value":"v","meta":{"origin":"replica-2"}}
+/// ```
+///
+/// A batch record holds the binary records of the commands of a `WriteBatch` after
+/// its type and metadata, starting with `BATCHED` instead of `BINARY`, so that they
+/// are applied together or not at all, while the index can still point at the record
+/// of every command.
+///
+/// Metadata is not interpreted by this version, but it is preserved when a record is
+/// rewritten by a compaction. Records of an unknown type are an error, unless their
+/// metadata has `"skippable": true`, which lets later versions add commands that
+/// older versions can safely ignore.
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(try_from = "Value")]
+pub(crate) struct Record {
+    #[serde(flatten)]
+    pub(crate) cmd: Command,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub(crate) meta: BTreeMap<String, Value>,
+}
+
+impl TryFrom<Value> for Record {
+    type Error = serde_json::Error;
+
+    fn try_from(value: Value) -> serde_json::Result<Record> {
+        let mut fields = match value {
+            Value::Object(fields) => fields,
+            _ => return Err(serde_json::Error::custom("record is not an object")),
+        };
+        fields.remove("crc");
+        if !fields.contains_key("type") {
+            fields = untagged_fields(fields)?;
+        }
+        let meta: BTreeMap<String, Value> = match fields.remove("meta") {
+            Some(meta) => serde_json::from_value(meta)?,
+            None => BTreeMap::new(),
+        };
+        let cmd = serde_json::from_value(Value::Object(fields))?;
+        if let Command::Unknown = cmd {
+            if meta.get(SKIPPABLE) != Some(&Value::Bool(true)) {
+                return Err(serde_json::Error::custom("unknown record type"));
+            }
+        }
+        Ok(Record { cmd, meta })
+    }
+}
+
+/// Serializes a command and its metadata to a binary record.
+pub(crate) fn encode(cmd: &Command, meta: &BTreeMap<String, Value>) -> serde_json::Result<Vec<u8>> {
+    let mut payload = Vec::new();
+    let ty = match cmd {
+        Command::Set { .. } => SET,
+        Command::Remove { .. } => REMOVE,
+        Command::Expire { .. } => EXPIRE,
+        Command::Rename { .. } => RENAME,
+        Command::Batch(_) | Command::Unknown => {
+            return Err(serde_json::Error::custom("unknown record type"))
+        }
+    };
+    payload.push(ty);
+    if meta.is_empty() {
+        put_bytes(&mut payload, b"");
+    } else {
+        put_bytes(&mut payload, &serde_json::to_vec(meta)?);
+    }
+    match cmd {
+        Command::Set {
+            key,
+            value,
+            clock,
+            ts,
+            expires_at,
+        } => {
+            put_bytes(&mut payload, key.as_bytes());
+            put_bytes(&mut payload, value.as_bytes());
+            if clock.is_empty() {
+                put_bytes(&mut payload, b"");
+            } else {
+                put_bytes(&mut payload, &serde_json::to_vec(clock)?);
+            }
+            put_option(&mut payload, *ts);
+            put_option(&mut payload, *expires_at);
+        }
+        Command::Remove { key } => put_bytes(&mut payload, key.as_bytes()),
+        Command::Expire { key, expires_at } => {
+            put_bytes(&mut payload, key.as_bytes());
+            put_option(&mut payload, *expires_at);
+        }
+        Command::Rename { key, new_key } => {
+            put_bytes(&mut payload, key.as_bytes());
+            put_bytes(&mut payload, new_key.as_bytes());
+        }
+        Command::Batch(_) | Command::Unknown => unreachable!(),
+    }
+    Ok(frame(BINARY, &payload))
+}
+
+/// Serializes commands and the metadata of the batch to a batch record.
+///
+/// Returns the record and the positions of the records of the commands in it.
+pub(crate) fn encode_batch(
+    cmds: &[Command],
+    meta: &BTreeMap<String, Value>,
+) -> serde_json::Result<(Vec<u8>, Vec<Range<u64>>)> {
+    let mut payload = vec![BATCH];
+    if meta.is_empty() {
+        put_bytes(&mut payload, b"");
+    } else {
+        put_bytes(&mut payload, &serde_json::to_vec(meta)?);
+    }
+    let mut ranges = Vec::with_capacity(cmds.len());
+    for cmd in cmds {
+        let start = (HEADER_LEN + payload.len()) as u64;
+        let mut record = encode(cmd, &BTreeMap::new())?;
+        record[0] = BATCHED;
+        payload.extend_from_slice(&record);
+        ranges.push(start..(HEADER_LEN + payload.len()) as u64);
+    }
+    Ok((frame(BINARY, &payload), ranges))
+}
+
+/// Prefixes a payload with the header of a binary record.
+fn frame(marker: u8, payload: &[u8]) -> Vec<u8> {
+    let mut crc = Crc32::new();
+    crc.update(payload);
+    let mut buf = Vec::with_capacity(HEADER_LEN + payload.len());
+    buf.push(marker);
+    buf.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+    buf.extend_from_slice(&crc.value().to_le_bytes());
+    buf.extend_from_slice(payload);
+    buf
+}
+
+/// Returns the length and CRC-32 of the payload of a binary record from its header.
+pub(crate) fn header(header: &[u8; HEADER_LEN]) -> (u64, u32) {
+    let len = u32::from_le_bytes(header[1..5].try_into().expect("slice of 4 bytes"));
+    let crc = u32::from_le_bytes(header[5..9].try_into().expect("slice of 4 bytes"));
+    (u64::from(len), crc)
+}
+
+/// Deserializes the payload of a binary record, checking it against the CRC-32 of its
+/// header.
+///
+/// Returns why the payload is corrupt if it is.
+pub(crate) fn decode_payload(payload: &[u8], crc: u32) -> Result<Record, String> {
+    let mut actual = Crc32::new();
+    actual.update(payload);
+    if actual.value() != crc {
+        return Err("checksum mismatch".to_owned());
+    }
+    let mut cursor = Cursor(payload);
+    let ty = cursor.byte()?;
+    let meta = match cursor.bytes()? {
+        b"" => BTreeMap::new(),
+        json => serde_json::from_slice(json).map_err(|e| e.to_string())?,
+    };
+    let cmd = match ty {
+        SET => Command::Set {
+            key: cursor.string()?,
+            value: cursor.string()?,
+            clock: match cursor.bytes()? {
+                b"" => VectorClock::new(),
+                json => serde_json::from_slice(json).map_err(|e| e.to_string())?,
+            },
+            ts: cursor.option()?,
+            expires_at: cursor.option()?,
+        },
+        REMOVE => Command::Remove {
+            key: cursor.string()?,
+        },
+        EXPIRE => Command::Expire {
+            key: cursor.string()?,
+            expires_at: cursor.option()?,
+        },
+        RENAME => Command::Rename {
+            key: cursor.string()?,
+            new_key: cursor.string()?,
+        },
+        BATCH => {
+            let mut records = Vec::new();
+            while !cursor.0.is_empty() {
+                let start = (HEADER_LEN + payload.len() - cursor.0.len()) as u64;
+                let record = cursor.record()?;
+                if let Command::Batch(_) = record.cmd {
+                    return Err("nested batch".to_owned());
+                }
+                let end = (HEADER_LEN + payload.len() - cursor.0.len()) as u64;
+                records.push((record, start..end));
+            }
+            Command::Batch(records)
+        }
+        _ if meta.get(SKIPPABLE) == Some(&Value::Bool(true)) => Command::Unknown,
+        _ => return Err(format!("unknown record type {}", ty)),
+    };
+    Ok(Record { cmd, meta })
+}
+
+/// Deserializes a binary record.
+///
+/// Returns why the record is corrupt if it is.
+pub(crate) fn decode(buf: &[u8]) -> Result<Record, String> {
+    let (header, payload) = split(buf)?;
+    decode_payload(payload, header.1)
+}
+
+/// Returns the value of a binary "set" record, borrowed from the record, or `None` for
+/// records of other types.
+///
+/// Returns why the record is corrupt if it is.
+pub(crate) fn set_value(buf: &[u8]) -> Result<Option<&str>, String> {
+    let ((_, crc), payload) = split(buf)?;
+    let mut actual = Crc32::new();
+    actual.update(payload);
+    if actual.value() != crc {
+        return Err("checksum mismatch".to_owned());
+    }
+    let mut cursor = Cursor(payload);
+    if cursor.byte()? != SET {
+        return Ok(None);
+    }
+    cursor.bytes()?; // meta
+    cursor.bytes()?; // key
+    cursor.str().map(Some)
+}
+
+/// Returns whether a serialized record is binary.
+pub(crate) fn is_binary(buf: &[u8]) -> bool {
+    matches!(buf.first(), Some(&BINARY) | Some(&BATCHED))
+}
+
+/// Returns whether a valid record, binary or JSON, starts at the start of a buffer.
+pub(crate) fn starts_record(buf: &[u8]) -> bool {
+    match buf.first() {
+        Some(&BINARY) if buf.len() >= HEADER_LEN => {
+            let (len, _) = header(buf[..HEADER_LEN].try_into().expect("slice of header"));
+            let end = HEADER_LEN as u64 + len;
+            end <= buf.len() as u64 && decode(&buf[..end as usize]).is_ok()
+        }
+        // `{"` can't occur in JSON strings because quotes are escaped in them.
+        Some(b'{') if buf.starts_with(b"{\"") => {
+            let mut stream = Deserializer::from_slice(buf).into_iter::<Record>();
+            matches!(stream.next(), Some(Ok(_))) && checksum_ok(&buf[..stream.byte_offset()])
+        }
+        _ => false,
+    }
+}
+
+/// Splits a binary record into the length and CRC-32 of its header, and its payload.
+fn split(buf: &[u8]) -> Result<((u64, u32), &[u8]), String> {
+    if buf.len() < HEADER_LEN || !is_binary(buf) {
+        return Err("not a binary record".to_owned());
+    }
+    let (len, crc) = header(buf[..HEADER_LEN].try_into().expect("slice of header"));
+    let payload = &buf[HEADER_LEN..];
+    if payload.len() as u64 != len {
+        return Err("truncated record".to_owned());
+    }
+    Ok(((len, crc), payload))
+}
+
+fn put_varint(buf: &mut Vec<u8>, mut n: u64) {
+    while n >= 0x80 {
+        buf.push(n as u8 | 0x80);
+        n >>= 7;
+    }
+    buf.push(n as u8);
+}
+
+fn put_bytes(buf: &mut Vec<u8>, bytes: &[u8]) {
+    put_varint(buf, bytes.len() as u64);
+    buf.extend_from_slice(bytes);
+}
+
+fn put_option(buf: &mut Vec<u8>, n: Option<u64>) {
+    match n {
+        Some(n) => {
+            buf.push(1);
+            put_varint(buf, n);
+        }
+        None => buf.push(0),
+    }
+}
+
+/// Reads the fields of the payload of a binary record.
+struct Cursor<'a>(&'a [u8]);
+
+impl<'a> Cursor<'a> {
+    fn byte(&mut self) -> Result<u8, String> {
+        let (&byte, rest) = self.0.split_first().ok_or("truncated payload")?;
+        self.0 = rest;
+        Ok(byte)
+    }
+
+    fn varint(&mut self) -> Result<u64, String> {
+        let mut n = 0u64;
+        for shift in (0..64).step_by(7) {
+            let byte = self.byte()?;
+            n |= u64::from(byte & 0x7f) << shift;
+            if byte < 0x80 {
+                return Ok(n);
+            }
+        }
+        Err("varint too long".to_owned())
+    }
+
+    fn bytes(&mut self) -> Result<&'a [u8], String> {
+        let len = self.varint()?;
+        if len > self.0.len() as u64 {
+            return Err("truncated payload".to_owned());
+        }
+        let (bytes, rest) = self.0.split_at(len as usize);
+        self.0 = rest;
+        Ok(bytes)
+    }
+
+    fn str(&mut self) -> Result<&'a str, String> {
+        str::from_utf8(self.bytes()?).map_err(|e| e.to_string())
+    }
+
+    fn string(&mut self) -> Result<String, String> {
+        self.str().map(str::to_owned)
+    }
+
+    fn record(&mut self) -> Result<Record, String> {
+        if self.0.len() < HEADER_LEN {
+            return Err("truncated payload".to_owned());
+        }
+        let (len, _) = header(self.0[..HEADER_LEN].try_into().expect("slice of header"));
+        let end = HEADER_LEN as u64 + len;
+        if end > self.0.len() as u64 {
+            return Err("truncated payload".to_owned());
+        }
+        let (record, rest) = self.0.split_at(end as usize);
+        self.0 = rest;
+        if record[0] != BATCHED {
+            return Err("unbatched record in batch".to_owned());
+        }
+        decode(record)
+    }
+
+    fn option(&mut self) -> Result<Option<u64>, String> {
+        match self.byte()? {
+            0 => Ok(None),
+            1 => self.varint().map(Some),
+            flag => Err(format!("invalid option flag {}", flag)),
+        }
+    }
+}
+
+/// Returns whether the checksum of a JSON record matches its content.
+///
+/// Records without a checksum always match.
+pub(crate) fn checksum_ok(buf: &[u8]) -> bool {
+    let start = buf
+        .iter()
+        .position(|b| !b.is_ascii_whitespace())
+        .unwrap_or(buf.len());
+    let rest = match buf[start..].strip_prefix(CRC_PREFIX) {
+        Some(rest) => rest,
+        None => return true,
+    };
+    if rest.len() < 10 || &rest[8..10] != b"\"," {
+        return false;
+    }
+    let expected = str::from_utf8(&rest[..8])
+        .ok()
+        .and_then(|digits| u32::from_str_radix(digits, 16).ok());
+    let mut crc = Crc32::new();
+    crc.update(&rest[10..]);
+    expected == Some(crc.value())
+}
+
+/// Converts a record written before records were tagged, `{"Set":{"key":..}}`, to
+/// the fields of a tagged record.
+fn untagged_fields(fields: Map<String, Value>) -> serde_json::Result<Map<String, Value>> {
+    let mut entries = fields.into_iter();
+    match (entries.next(), entries.next()) {
+        (Some((tag, Value::Object(mut fields))), None) => {
+            fields.insert("type".to_owned(), Value::String(tag));
+            Ok(fields)
+        }
+        _ => Err(serde_json::Error::missing_field("type")),
+    }
+}
+
+/// The value of a "set" record, borrowed from the serialized record if possible.
+///
+/// Both fields are empty for untagged records of older versions.
+#[derive(Deserialize)]
+pub(crate) struct SetValue<'a> {
+    #[serde(rename = "type", default, borrow)]
+    pub(crate) ty: Cow<'a, str>,
+    #[serde(default, borrow)]
+    pub(crate) value: Cow<'a, str>,
+}
+
+/// Struct representing a command.
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(tag = "type")]
+pub(crate) enum Command {
+    Set {
+        key: String,
+        value: String,
+        #[serde(default, skip_serializing_if = "VectorClock::is_empty")]
+        clock: VectorClock,
+        // milliseconds since the Unix epoch when the command was written.
+        // It is missing in logs written by older versions.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        ts: Option<u64>,
+        // milliseconds since the Unix epoch when the key expires.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        expires_at: Option<u64>,
+    },
+    Remove {
+        key: String,
+    },
+    Expire {
+        key: String,
+        expires_at: Option<u64>,
+    },
+    Rename {
+        key: String,
+        new_key: String,
+    },
+    /// The records of a batch, with their positions relative to the batch record.
+    #[serde(skip)]
+    Batch(Vec<(Record, Range<u64>)>),
+    /// A skippable command of a later version.
+    #[serde(other)]
+    Unknown,
+}
+
+impl Command {
+    pub(crate) fn remove(key: String) -> Command {
+        Command::Remove { key }
+    }
+}
@@ -0,0 +1,60 @@
+//! Redaction of values written to diagnostic output.
+//!
+//! A redactor rewrites values before they reach traces recorded with
+//! [`Options::redactor`](crate::Options::redactor), requests logged by
+//! [`KvsServer::redactor`](crate::KvsServer::redactor) and the output of `kvs diff`,
+//! so that the output can be shared without leaking secrets. Values stored in the
+//! log are never redacted.
+
+/// Rewrites values written to diagnostic output.
+///
+/// It is implemented for closures taking the key and the value:
+///
+/// ```rust
+/// # use kvs::Options;
+/// let options = Options::new().redactor(|key: &str, value: &str| {
+///     if key.starts_with("token/") {
+///         "***".to_owned()
+///     } else {
+///         value.to_owned()
+///     }
+/// });
+/// ```
+pub trait Redactor: Send + Sync {
+    /// Returns the text written in place of the value of the given key.
+    fn redact(&self, key: &str, value: &str) -> String;
+}
+
+impl<F> Redactor for F
+where
+    F: Fn(&str, &str) -> String + Send + Sync,
+{
+    fn redact(&self, key: &str, value: &str) -> String {
+        self(key, value)
+    }
+}
+
+/// Replaces every value with its length, such as `<12 bytes>`.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Lengths;
+
+impl Redactor for Lengths {
+    fn redact(&self, _key: &str, value: &str) -> String {
+        format!("<{} bytes>", value.len())
+    }
+}
+
+/// Replaces the values of the keys starting with any of the given prefixes with their
+/// lengths, and keeps the other values.
+#[derive(Clone, Debug, Default)]
+pub struct Prefixes(pub Vec<String>);
+
+impl Redactor for Prefixes {
+    fn redact(&self, key: &str, value: &str) -> String {
+        if self.0.iter().any(|prefix| key.starts_with(prefix.as_str())) {
+            Lengths.redact(key, value)
+        } else {
+            value.to_owned()
+        }
+    }
+}
@@ -0,0 +1,138 @@
+//! The Redis serialization protocol (RESP) spoken by `kvs-server --protocol resp`.
+//!
+//! A command is an array of bulk strings, such as `*2\r\n$3\r\nGET\r\n$4\r\nkey1\r\n`,
+//! or an inline command such as `GET key1\r\n` typed into a terminal. Commands are read
+//! within the same [`Limits`] as frames of [`protocol`](crate::protocol): the lengths
+//! claimed by the peer are checked before anything is allocated for them.
+
+use std::io::{self, BufRead, Read, Write};
+
+use crate::protocol::Limits;
+use crate::{KvsError, Result};
+
+// the largest allocation made up front for a bulk string.
+const INITIAL_CAPACITY: usize = 64 * 1024;
+
+/// A reply to a command.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Reply {
+    /// A simple string, such as `+OK`.
+    Status(String),
+    /// An error starting with its kind, such as `-ERR unknown command 'foo'`.
+    Error(String),
+    /// An integer, such as the number of keys removed.
+    Integer(i64),
+    /// A bulk string, or the null bulk string for `None`.
+    Bulk(Option<String>),
+}
+
+/// Reads the arguments of a command, starting with its name, or `None` if the peer
+/// closed the connection before sending one.
+///
+/// An empty array or blank line is read as a command without arguments.
+///
+/// # Errors
+///
+/// It returns `KvsError::FrameTooLarge` if the command exceeds `Limits::max_frame`, and
+/// `KvsError::InvalidRequest` if it is not valid RESP or an argument is not UTF-8.
+pub fn read_command(reader: &mut impl BufRead, limits: &Limits) -> Result<Option<Vec<String>>> {
+    let line = match read_line(reader, limits.max_frame)? {
+        Some(line) => line,
+        None => return Ok(None),
+    };
+    let count = match line.strip_prefix(b"*") {
+        Some(count) => parse_len(count)?,
+        None => {
+            let line = utf8(line)?;
+            return Ok(Some(line.split_whitespace().map(str::to_owned).collect()));
+        }
+    };
+    let mut total = line.len();
+    let mut args = Vec::new();
+    for _ in 0..count {
+        let header = read_line(reader, limits.max_frame)?
+            .ok_or_else(|| io::Error::from(io::ErrorKind::UnexpectedEof))?;
+        let len = match header.strip_prefix(b"$") {
+            Some(len) => parse_len(len)?,
+            None => return Err(invalid("expected a bulk string")),
+        };
+        total = total.saturating_add(header.len()).saturating_add(len);
+        if total > limits.max_frame {
+            return Err(KvsError::FrameTooLarge {
+                len: total,
+                limit: limits.max_frame,
+            });
+        }
+        // the length is only a claim of the peer, so memory is committed as bytes arrive.
+        let mut arg = Vec::with_capacity(len.min(INITIAL_CAPACITY));
+        reader.take(len as u64 + 2).read_to_end(&mut arg)?;
+        if arg.len() < len + 2 {
+            return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+        }
+        if !arg.ends_with(b"\r\n") {
+            return Err(invalid("bulk string longer than its length"));
+        }
+        arg.truncate(len);
+        args.push(utf8(arg)?);
+    }
+    Ok(Some(args))
+}
+
+/// Writes a reply.
+///
+/// Line breaks in status and error messages are replaced with spaces, as they can't be
+/// sent in them.
+///
+/// # Errors
+///
+/// It propagates I/O errors during writing.
+pub fn write_reply(writer: &mut impl Write, reply: &Reply) -> Result<()> {
+    let single_line = |message: &str| message.replace(&['\r', '\n'][..], " ");
+    match reply {
+        Reply::Status(message) => write!(writer, "+{}\r\n", single_line(message))?,
+        Reply::Error(message) => write!(writer, "-{}\r\n", single_line(message))?,
+        Reply::Integer(n) => write!(writer, ":{}\r\n", n)?,
+        Reply::Bulk(Some(value)) => write!(writer, "${}\r\n{}\r\n", value.len(), value)?,
+        Reply::Bulk(None) => writer.write_all(b"$-1\r\n")?,
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+/// Reads a line ending with `\n` or `\r\n` of up to `limit` bytes without the ending.
+fn read_line(reader: &mut impl BufRead, limit: usize) -> Result<Option<Vec<u8>>> {
+    let mut line = Vec::new();
+    reader.take(limit as u64 + 2).read_until(b'\n', &mut line)?;
+    if line.is_empty() {
+        return Ok(None);
+    }
+    if !line.ends_with(b"\n") {
+        if line.len() < limit + 2 {
+            return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+        }
+        return Err(KvsError::FrameTooLarge {
+            len: line.len(),
+            limit,
+        });
+    }
+    line.pop();
+    if line.ends_with(b"\r") {
+        line.pop();
+    }
+    Ok(Some(line))
+}
+
+fn parse_len(digits: &[u8]) -> Result<usize> {
+    std::str::from_utf8(digits)
+        .ok()
+        .and_then(|digits| digits.parse().ok())
+        .ok_or_else(|| invalid("invalid length"))
+}
+
+fn utf8(bytes: Vec<u8>) -> Result<String> {
+    String::from_utf8(bytes).map_err(|_| invalid("argument is not valid UTF-8"))
+}
+
+fn invalid(reason: &str) -> KvsError {
+    KvsError::InvalidRequest(reason.to_owned())
+}
@@ -0,0 +1,63 @@
+//! Stable serialized forms for external tooling.
+//!
+//! [`Stats`](crate::Stats), [`StoreMeta`](crate::StoreMeta), [`Change`](crate::Change)
+//! and trace [`Event`](crate::trace::Event)s implement serde's `Serialize` and
+//! `Deserialize`. Within a format [`VERSION`]:
+//!
+//! - fields are only added, never renamed, removed or changed in meaning;
+//! - missing fields are deserialized as their defaults, so data written by an older
+//!   release of the same version is read by a newer one;
+//! - unknown fields are ignored, so data written by a newer release is read by an
+//!   older one.
+//!
+//! A change that breaks these rules increments [`VERSION`]. Tooling that stores or
+//! ships the data should wrap it in [`Versioned`] to detect that:
+//!
+//! ```rust
+//! # use kvs::schema::Versioned;
+//! # use kvs::Stats;
+//! let json = serde_json::to_string(&Versioned::new(Stats::default())).unwrap();
+//! let stats: Versioned<Stats> = serde_json::from_str(&json).unwrap();
+//! assert!(stats.into_inner().is_ok());
+//! ```
+
+use serde::{Deserialize, Serialize};
+
+use crate::{KvsError, Result};
+
+/// The version of the serialized forms.
+pub const VERSION: u32 = 1;
+
+/// A value serialized together with the [`VERSION`] of its form, such as
+/// `{"version":1,"id":"...",...}`.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Versioned<T> {
+    /// The version of the form of the value.
+    pub version: u32,
+    /// The value, whose fields are flattened next to the version.
+    #[serde(flatten)]
+    pub value: T,
+}
+
+impl<T> Versioned<T> {
+    /// Wraps a value with the current version.
+    pub fn new(value: T) -> Versioned<T> {
+        Versioned {
+            version: VERSION,
+            value,
+        }
+    }
+
+    /// Returns the value if it was serialized with the current version.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::UnsupportedVersion` for any other version.
+    pub fn into_inner(self) -> Result<T> {
+        if self.version == VERSION {
+            Ok(self.value)
+        } else {
+            Err(KvsError::UnsupportedVersion(self.version))
+        }
+    }
+}
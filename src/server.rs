@@ -0,0 +1,315 @@
+use std::io::{BufReader, BufWriter};
+use std::mem::take;
+use std::net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use log::{info, warn};
+
+use crate::protocol::{self, ErrorCode, Limits, Protocol, ProtocolError, Request, Response};
+use crate::random::Rng;
+use crate::redact::{Lengths, Redactor};
+use crate::resp::{self, Reply};
+use crate::{KvsEngine, KvsError, Result};
+
+// how long a connection may stay silent before it is closed, so that an idle client
+// doesn't hold up the others.
+const IDLE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Serves the requests of the [`protocol`](crate::protocol) with a storage engine.
+///
+/// Connections are served one at a time, each until the client closes it.
+///
+/// ```rust,no_run
+/// # use kvs::{KvStore, KvsServer, Result};
+/// # fn try_main() -> Result<()> {
+/// let store = KvStore::open(std::env::current_dir()?)?;
+/// KvsServer::new(store).run("127.0.0.1:4000")?;
+/// # Ok(())
+/// # }
+/// ```
+pub struct KvsServer<E: KvsEngine> {
+    engine: E,
+    protocol: Protocol,
+    limits: Limits,
+    // the fraction of requests logged in full.
+    sample: f64,
+    redactor: Option<Arc<dyn Redactor>>,
+    rng: Rng,
+}
+
+impl<E: KvsEngine> KvsServer<E> {
+    /// Creates a server with the default protocol limits.
+    pub fn new(engine: E) -> KvsServer<E> {
+        KvsServer {
+            engine,
+            protocol: Protocol::Kvs,
+            limits: Limits::default(),
+            sample: 0.0,
+            redactor: None,
+            rng: Rng::new(),
+        }
+    }
+
+    /// Sets the protocol spoken with clients, `Protocol::Kvs` by default.
+    ///
+    /// With `Protocol::Resp`, the `GET`, `SET`, `DEL`, `EXISTS` and `PING` commands of
+    /// Redis are served, so that redis clients and `redis-cli` can be pointed at the
+    /// server.
+    pub fn protocol(mut self, protocol: Protocol) -> KvsServer<E> {
+        self.protocol = protocol;
+        self
+    }
+
+    /// Sets the limits of the frames read from clients.
+    pub fn limits(mut self, limits: Limits) -> KvsServer<E> {
+        self.limits = limits;
+        self
+    }
+
+    /// Logs the given fraction of requests together with their responses, at the info
+    /// level with the `kvs::sample` target.
+    ///
+    /// Sampling a small fraction of traffic helps to chase errors clients see rarely.
+    pub fn sample(mut self, ratio: f64) -> KvsServer<E> {
+        self.sample = ratio;
+        self
+    }
+
+    /// Replaces the values in sampled requests and responses with their lengths.
+    ///
+    /// It is a shorthand for `redactor(Lengths)`.
+    pub fn redact_values(mut self, redact: bool) -> KvsServer<E> {
+        self.redactor = if redact {
+            Some(Arc::new(Lengths))
+        } else {
+            None
+        };
+        self
+    }
+
+    /// Rewrites the values in sampled requests and responses with the given
+    /// [`Redactor`](crate::redact::Redactor).
+    pub fn redactor(mut self, redactor: impl Redactor + 'static) -> KvsServer<E> {
+        self.redactor = Some(Arc::new(redactor));
+        self
+    }
+
+    /// Listens on the given address and serves connections until an error occurs.
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O errors binding the address.
+    pub fn run(mut self, addr: impl ToSocketAddrs) -> Result<()> {
+        let listener = TcpListener::bind(addr)?;
+        self.serve(&listener)
+    }
+
+    /// Serves the connections of a bound listener.
+    ///
+    /// Errors of a connection are logged and close it without stopping the server.
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O errors accepting connections.
+    pub fn serve(&mut self, listener: &TcpListener) -> Result<()> {
+        self.serve_until(listener, &AtomicBool::new(false))
+    }
+
+    /// Serves the connections of a bound listener until `stop` is set.
+    ///
+    /// The flag is checked after each connection, so a connection may be needed to
+    /// wake the server up. Connections accepted are always served.
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O errors accepting connections.
+    pub fn serve_until(&mut self, listener: &TcpListener, stop: &AtomicBool) -> Result<()> {
+        while !stop.load(Ordering::SeqCst) {
+            let (stream, peer) = listener.accept()?;
+            if let Err(e) = self.handle(stream, peer) {
+                warn!("closed the connection of {} after an error: {}", peer, e);
+            }
+        }
+        Ok(())
+    }
+
+    fn handle(&mut self, stream: TcpStream, peer: SocketAddr) -> Result<()> {
+        stream.set_read_timeout(Some(IDLE_TIMEOUT))?;
+        let reader = BufReader::new(stream.try_clone()?);
+        let writer = BufWriter::new(stream);
+        match self.protocol {
+            Protocol::Kvs => self.handle_kvs(reader, writer, peer),
+            Protocol::Resp => self.handle_resp(reader, writer, peer),
+        }
+    }
+
+    fn handle_kvs(
+        &mut self,
+        mut reader: BufReader<TcpStream>,
+        mut writer: BufWriter<TcpStream>,
+        peer: SocketAddr,
+    ) -> Result<()> {
+        loop {
+            let request = match protocol::read_request(&mut reader, &self.limits) {
+                Ok(Some(request)) => request,
+                Ok(None) => return Ok(()),
+                // the rest of the stream can't be framed, so the connection ends here.
+                Err(e @ KvsError::FrameTooLarge { .. }) | Err(e @ KvsError::InvalidRequest(_)) => {
+                    let response = Response::Err(ProtocolError::from(&e));
+                    protocol::write_response(&mut writer, &response, &self.limits)?;
+                    return Err(e);
+                }
+                Err(e) => return Err(e),
+            };
+            let response = self.execute(peer, request);
+            protocol::write_response(&mut writer, &response, &self.limits)?;
+        }
+    }
+
+    fn handle_resp(
+        &mut self,
+        mut reader: BufReader<TcpStream>,
+        mut writer: BufWriter<TcpStream>,
+        peer: SocketAddr,
+    ) -> Result<()> {
+        loop {
+            let args = match resp::read_command(&mut reader, &self.limits) {
+                Ok(Some(args)) => args,
+                Ok(None) => return Ok(()),
+                // the rest of the stream can't be parsed, so the connection ends here.
+                Err(e @ KvsError::FrameTooLarge { .. }) | Err(e @ KvsError::InvalidRequest(_)) => {
+                    let reply = Reply::Error(format!("ERR Protocol error: {}", e));
+                    resp::write_reply(&mut writer, &reply)?;
+                    return Err(e);
+                }
+                Err(e) => return Err(e),
+            };
+            // redis ignores empty commands without replying.
+            if !args.is_empty() {
+                let reply = self.execute_resp(peer, args);
+                resp::write_reply(&mut writer, &reply)?;
+            }
+        }
+    }
+
+    /// Serves a command of `Protocol::Resp` with the requests of the kvs protocol.
+    fn execute_resp(&mut self, peer: SocketAddr, mut args: Vec<String>) -> Reply {
+        let name = args.remove(0).to_ascii_lowercase();
+        let result = match (name.as_str(), &mut args[..]) {
+            ("ping", []) => Ok(Reply::Status("PONG".to_owned())),
+            ("ping", [message]) => Ok(Reply::Bulk(Some(take(message)))),
+            ("get", [key]) => self
+                .execute_key(peer, Request::Get { key: take(key) })
+                .map(Reply::Bulk),
+            ("set", [key, value]) => {
+                let (key, value) = (take(key), take(value));
+                self.execute_key(peer, Request::Set { key, value })
+                    .map(|_| Reply::Status("OK".to_owned()))
+            }
+            ("del", keys) if !keys.is_empty() => keys
+                .iter_mut()
+                .try_fold(0, |count, key| {
+                    match self.execute_key(peer, Request::Remove { key: take(key) }) {
+                        Ok(_) => Ok(count + 1),
+                        Err(e) if e.code == ErrorCode::NotFound => Ok(count),
+                        Err(e) => Err(e),
+                    }
+                })
+                .map(Reply::Integer),
+            ("exists", keys) if !keys.is_empty() => keys
+                .iter_mut()
+                .try_fold(0, |count, key| {
+                    let found = self.execute_key(peer, Request::Get { key: take(key) })?;
+                    Ok(count + found.map_or(0, |_| 1))
+                })
+                .map(Reply::Integer),
+            ("ping", _) | ("get", _) | ("set", _) | ("del", _) | ("exists", _) => {
+                return Reply::Error(format!(
+                    "ERR wrong number of arguments for '{}' command",
+                    name
+                ))
+            }
+            _ => return Reply::Error(format!("ERR unknown command '{}'", name)),
+        };
+        result.unwrap_or_else(|e| {
+            let kind = match e.code {
+                ErrorCode::Busy => "BUSY",
+                ErrorCode::ReadOnly => "READONLY",
+                _ => "ERR",
+            };
+            Reply::Error(format!("{} {}", kind, e.message))
+        })
+    }
+
+    /// Applies a request of a command of `Protocol::Resp`, whose keys are not checked
+    /// while the command is read.
+    fn execute_key(
+        &mut self,
+        peer: SocketAddr,
+        request: Request,
+    ) -> std::result::Result<Option<String>, ProtocolError> {
+        self.limits
+            .check_key(request.key())
+            .map_err(|e| ProtocolError::from(&e))?;
+        match self.execute(peer, request) {
+            Response::Ok(value) => Ok(value),
+            Response::Err(e) => Err(e),
+        }
+    }
+
+    /// Applies a request, logging it if it is sampled.
+    fn execute(&mut self, peer: SocketAddr, request: Request) -> Response {
+        let draw = self.rng.unit();
+        let sampled = (draw < self.sample).then(|| request.clone());
+        let response = match self.apply(request) {
+            Ok(value) => Response::Ok(value),
+            Err(e) => Response::Err(ProtocolError::from(&e)),
+        };
+        if let Some(request) = sampled {
+            self.log_sample(peer, request, &response);
+        }
+        response
+    }
+
+    fn log_sample(&self, peer: SocketAddr, mut request: Request, response: &Response) {
+        let mut response = response.clone();
+        if let Some(redactor) = &self.redactor {
+            if let Request::Set { key, value } = &mut request {
+                *value = redactor.redact(key, value);
+            }
+            if let Response::Ok(Some(value)) = &mut response {
+                *value = redactor.redact(request.key(), value);
+            }
+        }
+        info!(
+            target: "kvs::sample",
+            "{} {} -> {}",
+            peer,
+            serde_json::to_string(&request).unwrap_or_default(),
+            serde_json::to_string(&response).unwrap_or_default()
+        );
+    }
+
+    fn apply(&mut self, request: Request) -> Result<Option<String>> {
+        match request {
+            Request::Get { key } => self.engine.get(key),
+            Request::Set { key, value } => self.engine.set(key, value).map(|_| None),
+            Request::Remove { key } => self.engine.remove(key).map(|_| None),
+            Request::Ttl { key } => {
+                Ok(self.engine.ttl(key)?.map(|ttl| ttl.as_millis().to_string()))
+            }
+            Request::Expire { key, millis } => self
+                .engine
+                .expire(key, Duration::from_millis(millis))
+                .map(|_| None),
+            Request::Persist { key } => self.engine.persist(key).map(|_| None),
+            Request::Copy {
+                key,
+                dst,
+                overwrite,
+            } => Ok(Some(self.engine.copy(key, dst, overwrite)?.to_string())),
+        }
+    }
+}
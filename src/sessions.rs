@@ -0,0 +1,91 @@
+//! Session storage on top of key TTLs.
+//!
+//! A session is a value stored under [`key`] of a random id that expires unless it
+//! is [`touch`]ed within its TTL:
+//!
+//! ```rust,no_run
+//! # use kvs::{sessions, KvStore, Result};
+//! # use std::time::Duration;
+//! # fn main() -> Result<()> {
+//! let mut store = KvStore::open("sessions")?;
+//! let id = sessions::create_session(&mut store, "user=42".to_owned(), Duration::from_secs(1800))?;
+//! if let Some(data) = sessions::validate(&mut store, &id)? {
+//!     sessions::touch(&mut store, &id, Duration::from_secs(1800))?;
+//!     println!("{}", data);
+//! }
+//! # Ok(())
+//! # }
+//! ```
+
+use std::time::Duration;
+
+use crate::{KvStore, KvsError, Result};
+
+/// The prefix of the keys sessions are stored under.
+pub const PREFIX: &str = "session:";
+
+/// Returns the key the session with the given id is stored under.
+pub fn key(id: &str) -> String {
+    format!("{}{}", PREFIX, id)
+}
+
+/// Stores `data` under a new session id that expires after `ttl`, and returns the id.
+///
+/// The id holds 128 random bits from the operating system, and an existing session is
+/// never overwritten even if an id is drawn twice.
+///
+/// # Errors
+///
+/// It propagates I/O errors during reading random bits or writing the log.
+pub fn create_session(store: &mut KvStore, data: String, ttl: Duration) -> Result<String> {
+    loop {
+        let id = session_id()?;
+        // `store` is borrowed mutably, so nothing can create the key in between.
+        if store.get(key(&id))?.is_none() {
+            store.set_with_ttl(key(&id), data, ttl)?;
+            return Ok(id);
+        }
+    }
+}
+
+/// Extends a live session so that it expires after `ttl` from now.
+///
+/// Returns `false` if the session does not exist or has expired.
+///
+/// # Errors
+///
+/// It propagates I/O errors during writing the log.
+pub fn touch(store: &mut KvStore, id: &str, ttl: Duration) -> Result<bool> {
+    match store.expire(key(id), ttl) {
+        Ok(()) => Ok(true),
+        Err(KvsError::KeyNotFound) => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+/// Returns the data of a live session, or `None` if it does not exist or has expired.
+///
+/// # Errors
+///
+/// It propagates I/O errors during reading the log.
+pub fn validate(store: &mut KvStore, id: &str) -> Result<Option<String>> {
+    store.get(key(id))
+}
+
+/// Returns 32 hex digits of random bits.
+#[cfg(unix)]
+fn session_id() -> Result<String> {
+    use std::fs::File;
+    use std::io::Read;
+
+    let mut bytes = [0; 16];
+    File::open("/dev/urandom")?.read_exact(&mut bytes)?;
+    Ok(bytes.iter().map(|b| format!("{:02x}", b)).collect())
+}
+
+/// Returns 32 hex digits of random bits.
+#[cfg(not(unix))]
+fn session_id() -> Result<String> {
+    let mut rng = crate::random::Rng::new();
+    Ok(format!("{:016x}{:016x}", rng.next_u64(), rng.next_u64()))
+}
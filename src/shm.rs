@@ -0,0 +1,378 @@
+use std::fs::{self, File, OpenOptions};
+use std::io;
+use std::path::Path;
+use std::sync::atomic::{fence, AtomicU64, Ordering};
+
+use crate::crc::Crc32;
+
+const INDEX: &str = "INDEX";
+const MAGIC: u64 = 0x6b76_735f_696e_6478; // "kvs_indx"
+const MIN_CAPACITY: u64 = 1024;
+// how often a reader retries a slot the writer is updating.
+const MAX_SPINS: usize = 1 << 20;
+
+// words of the header: magic, capacity, state, live entries, used slots.
+const HEADER_WORDS: usize = 8;
+const MAGIC_WORD: usize = 0;
+const CAPACITY_WORD: usize = 1;
+const STATE_WORD: usize = 2;
+const LEN_WORD: usize = 3;
+const USED_WORD: usize = 4;
+
+// states of a table.
+const LIVE: u64 = 0;
+const STALE: u64 = 1;
+
+// words of a slot: version, tag, hash, check, gen, pos, len, expiration time.
+const SLOT_WORDS: usize = 8;
+const EMPTY: u64 = 0;
+const OCCUPIED: u64 = 1;
+const DELETED: u64 = 2;
+
+/// The location of a value in the log, as published in the shared index.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) struct Entry {
+    pub(crate) gen: u64,
+    pub(crate) pos: u64,
+    pub(crate) len: u64,
+    // milliseconds since the Unix epoch, or zero if the key does not expire.
+    pub(crate) expires_at: u64,
+}
+
+/// A hash table of the index of a store in a file mapped into memory, which reader
+/// processes look keys up in without replaying the log.
+///
+/// Keys are identified by a 64-bit hash and a CRC-32 of them. Every slot has a version
+/// which is odd while the writer updates it, so readers retry torn reads like with a
+/// seqlock. The table doesn't grow in place: the writer replaces the file with a larger
+/// table and marks the old one as stale, which tells readers to map the new file.
+pub(crate) struct SharedIndex {
+    map: Mmap,
+    capacity: u64,
+}
+
+/// A lookup in the shared index, which is only valid while its slot is unchanged.
+pub(crate) struct Lookup {
+    pub(crate) entry: Option<Entry>,
+    slot: Option<(usize, u64)>,
+}
+
+impl SharedIndex {
+    /// Creates the shared index of the store in the given directory with the given
+    /// entries, replacing any previous one.
+    pub(crate) fn create<'a>(
+        dir: &Path,
+        len: usize,
+        entries: impl Iterator<Item = (&'a str, Entry)>,
+    ) -> io::Result<SharedIndex> {
+        let capacity = (len as u64 * 2).next_power_of_two().max(MIN_CAPACITY);
+        let tmp_path = dir.join(INDEX).with_extension("tmp");
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&tmp_path)?;
+        file.set_len(((HEADER_WORDS as u64) + capacity * SLOT_WORDS as u64) * 8)?;
+        let mut index = SharedIndex {
+            map: Mmap::new(&file, true)?,
+            capacity,
+        };
+        index.word(MAGIC_WORD).store(MAGIC, Ordering::Relaxed);
+        index.word(CAPACITY_WORD).store(capacity, Ordering::Relaxed);
+        for (key, entry) in entries {
+            index.update(key, Some(entry));
+        }
+        let previous = SharedIndex::open(dir, true).unwrap_or(None);
+        fs::rename(&tmp_path, dir.join(INDEX))?;
+        // readers holding the previous table are told to map the new one.
+        if let Some(previous) = previous {
+            previous.mark_stale();
+        }
+        Ok(index)
+    }
+
+    /// Maps the shared index of the store in the given directory for reading.
+    ///
+    /// Returns `None` if there is none, or if it has been replaced.
+    pub(crate) fn open(dir: &Path, writable: bool) -> io::Result<Option<SharedIndex>> {
+        let file = match OpenOptions::new()
+            .read(true)
+            .write(writable)
+            .open(dir.join(INDEX))
+        {
+            Ok(file) => file,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        let size = file.metadata()?.len();
+        if size < HEADER_WORDS as u64 * 8 {
+            return Ok(None);
+        }
+        let index = SharedIndex {
+            map: Mmap::new(&file, writable)?,
+            capacity: 0,
+        };
+        let capacity = index.word(CAPACITY_WORD).load(Ordering::Relaxed);
+        let valid = index.word(MAGIC_WORD).load(Ordering::Relaxed) == MAGIC
+            && size == (HEADER_WORDS as u64 + capacity * SLOT_WORDS as u64) * 8;
+        if !valid {
+            return Ok(None);
+        }
+        let index = SharedIndex { capacity, ..index };
+        Ok(if index.is_stale() { None } else { Some(index) })
+    }
+
+    /// Marks the shared index in the given directory as stale and removes it, so
+    /// that readers go back to reading the log.
+    ///
+    /// Errors are ignored; readers check the log files they read anyway.
+    pub(crate) fn discard(dir: &Path) {
+        if let Ok(Some(index)) = SharedIndex::open(dir, true) {
+            let _ = fs::remove_file(dir.join(INDEX));
+            index.mark_stale();
+        }
+    }
+
+    fn mark_stale(&self) {
+        self.word(STATE_WORD).store(STALE, Ordering::Release);
+    }
+
+    /// Returns whether the table has been replaced or removed by the writer.
+    pub(crate) fn is_stale(&self) -> bool {
+        self.word(STATE_WORD).load(Ordering::Acquire) != LIVE
+    }
+
+    /// Returns whether adding another key would make probing slow.
+    pub(crate) fn is_full(&self) -> bool {
+        self.word(USED_WORD).load(Ordering::Relaxed) + 1 > self.capacity / 4 * 3
+    }
+
+    /// Sets or clears the entry of a key.
+    ///
+    /// The table must not be full when a key is added.
+    pub(crate) fn update(&mut self, key: &str, entry: Option<Entry>) {
+        let (hash, check) = hash(key);
+        let mut free = None;
+        let mut found = None;
+        for slot in self.probe(hash) {
+            match self.slot(slot, 1).load(Ordering::Relaxed) {
+                EMPTY => {
+                    free = free.or(Some(slot));
+                    break;
+                }
+                OCCUPIED
+                    if self.slot(slot, 2).load(Ordering::Relaxed) == hash
+                        && self.slot(slot, 3).load(Ordering::Relaxed) == check =>
+                {
+                    found = Some(slot);
+                    break;
+                }
+                DELETED => free = free.or(Some(slot)),
+                _ => {}
+            }
+        }
+        let slot = match (found, entry) {
+            (Some(slot), _) => slot,
+            (None, Some(_)) => free.expect("shared index is full"),
+            (None, None) => return,
+        };
+        let tag = self.slot(slot, 1).load(Ordering::Relaxed);
+
+        let version = self.slot(slot, 0).load(Ordering::Relaxed);
+        self.slot(slot, 0).store(version + 1, Ordering::Relaxed);
+        fence(Ordering::Release);
+        match entry {
+            Some(entry) => {
+                self.slot(slot, 1).store(OCCUPIED, Ordering::Relaxed);
+                self.slot(slot, 2).store(hash, Ordering::Relaxed);
+                self.slot(slot, 3).store(check, Ordering::Relaxed);
+                self.slot(slot, 4).store(entry.gen, Ordering::Relaxed);
+                self.slot(slot, 5).store(entry.pos, Ordering::Relaxed);
+                self.slot(slot, 6).store(entry.len, Ordering::Relaxed);
+                self.slot(slot, 7)
+                    .store(entry.expires_at, Ordering::Relaxed);
+            }
+            None => self.slot(slot, 1).store(DELETED, Ordering::Relaxed),
+        }
+        self.slot(slot, 0).store(version + 2, Ordering::Release);
+
+        let (len, used) = (self.word(LEN_WORD), self.word(USED_WORD));
+        match (tag, entry) {
+            (EMPTY, Some(_)) => {
+                len.fetch_add(1, Ordering::Relaxed);
+                used.fetch_add(1, Ordering::Relaxed);
+            }
+            (DELETED, Some(_)) => {
+                len.fetch_add(1, Ordering::Relaxed);
+            }
+            (OCCUPIED, None) => {
+                len.fetch_sub(1, Ordering::Relaxed);
+            }
+            _ => {}
+        }
+    }
+
+    /// Looks up the entry of a key.
+    ///
+    /// Returns `None` if a slot stays locked, which happens if the writer crashed
+    /// while updating it.
+    pub(crate) fn get(&self, key: &str) -> Option<Lookup> {
+        let (hash, check) = hash(key);
+        for slot in self.probe(hash) {
+            let mut spins = 0;
+            loop {
+                let version = self.slot(slot, 0).load(Ordering::Acquire);
+                if version % 2 == 1 {
+                    spins += 1;
+                    if spins > MAX_SPINS {
+                        return None;
+                    }
+                    std::hint::spin_loop();
+                    continue;
+                }
+                let tag = self.slot(slot, 1).load(Ordering::Relaxed);
+                let matches = self.slot(slot, 2).load(Ordering::Relaxed) == hash
+                    && self.slot(slot, 3).load(Ordering::Relaxed) == check;
+                let entry = Entry {
+                    gen: self.slot(slot, 4).load(Ordering::Relaxed),
+                    pos: self.slot(slot, 5).load(Ordering::Relaxed),
+                    len: self.slot(slot, 6).load(Ordering::Relaxed),
+                    expires_at: self.slot(slot, 7).load(Ordering::Relaxed),
+                };
+                fence(Ordering::Acquire);
+                if self.slot(slot, 0).load(Ordering::Relaxed) != version {
+                    continue;
+                }
+                match tag {
+                    EMPTY => {
+                        return Some(Lookup {
+                            entry: None,
+                            slot: None,
+                        })
+                    }
+                    OCCUPIED if matches => {
+                        return Some(Lookup {
+                            entry: Some(entry),
+                            slot: Some((slot, version)),
+                        })
+                    }
+                    _ => break,
+                }
+            }
+        }
+        Some(Lookup {
+            entry: None,
+            slot: None,
+        })
+    }
+
+    /// Returns whether the entry of a lookup is still current.
+    ///
+    /// The writer updates an entry before it removes or rewrites the log file the
+    /// previous entry points into, so data read from the log is valid if the entry is
+    /// still current after reading it.
+    pub(crate) fn is_current(&self, lookup: &Lookup) -> bool {
+        fence(Ordering::Acquire);
+        !self.is_stale()
+            && lookup
+                .slot
+                .is_none_or(|(slot, version)| self.slot(slot, 0).load(Ordering::Relaxed) == version)
+    }
+
+    /// Returns the slots to probe for a hash.
+    fn probe(&self, hash: u64) -> impl Iterator<Item = usize> {
+        let capacity = self.capacity;
+        (0..capacity).map(move |i| ((hash + i) % capacity) as usize)
+    }
+
+    fn word(&self, index: usize) -> &AtomicU64 {
+        self.map.word(index)
+    }
+
+    fn slot(&self, slot: usize, word: usize) -> &AtomicU64 {
+        self.map.word(HEADER_WORDS + slot * SLOT_WORDS + word)
+    }
+}
+
+/// Returns the FNV-1a hash and the CRC-32 of a key.
+fn hash(key: &str) -> (u64, u64) {
+    let mut hash = 0xcbf2_9ce4_8422_2325u64;
+    for &byte in key.as_bytes() {
+        hash = (hash ^ u64::from(byte)).wrapping_mul(0x0100_0000_01b3);
+    }
+    let mut crc = Crc32::new();
+    crc.update(key.as_bytes());
+    (hash, u64::from(crc.value()))
+}
+
+/// A file mapped into memory as an array of atomic words.
+struct Mmap {
+    ptr: *mut u8,
+    len: usize,
+}
+
+// the mapping is only accessed through atomics.
+unsafe impl Send for Mmap {}
+unsafe impl Sync for Mmap {}
+
+impl Mmap {
+    fn word(&self, index: usize) -> &AtomicU64 {
+        assert!((index + 1) * 8 <= self.len, "shared index is truncated");
+        // SAFETY: the mapping is page-aligned and lives as long as `self`.
+        unsafe { &*(self.ptr as *const AtomicU64).add(index) }
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl Mmap {
+    fn new(file: &File, writable: bool) -> io::Result<Mmap> {
+        use std::os::unix::io::AsRawFd;
+
+        let len = file.metadata()?.len() as usize;
+        let prot = if writable {
+            libc::PROT_READ | libc::PROT_WRITE
+        } else {
+            libc::PROT_READ
+        };
+        // SAFETY: a new mapping doesn't alias any Rust memory.
+        let ptr = unsafe {
+            libc::mmap(
+                std::ptr::null_mut(),
+                len,
+                prot,
+                libc::MAP_SHARED,
+                file.as_raw_fd(),
+                0,
+            )
+        };
+        if ptr == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Mmap {
+            ptr: ptr as *mut u8,
+            len,
+        })
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl Drop for Mmap {
+    fn drop(&mut self) {
+        // SAFETY: the mapping was created by `Mmap::new` and isn't used afterwards.
+        unsafe {
+            libc::munmap(self.ptr as *mut libc::c_void, self.len);
+        }
+    }
+}
+
+/// Shared indexes are only supported on Linux.
+#[cfg(not(target_os = "linux"))]
+impl Mmap {
+    fn new(_file: &File, _writable: bool) -> io::Result<Mmap> {
+        Err(io::Error::new(
+            io::ErrorKind::Other,
+            "shared indexes are only supported on Linux",
+        ))
+    }
+}
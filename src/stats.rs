@@ -0,0 +1,256 @@
+use std::convert::TryFrom;
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
+use crate::metrics::Operation;
+
+// values below 2^SUB_BITS nanoseconds get a bucket each; larger values are bucketed
+// by their 2^SUB_BITS most significant bits, which bounds the relative error to 1/16.
+const SUB_BITS: u32 = 4;
+const SUB_BUCKETS: usize = 1 << SUB_BITS;
+const BUCKETS: usize = (64 - SUB_BITS as usize + 1) * SUB_BUCKETS;
+
+/// A histogram of latencies with logarithmic buckets.
+///
+/// Recorded values are bucketed with a relative error of at most 1/16, so percentiles
+/// are accurate to about 6% while the histogram takes a fixed amount of memory.
+///
+/// It is serialized with its count, sum, extremes and common percentiles for
+/// monitoring, and the non-empty buckets to be read back as the same histogram.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(into = "HistogramForm", try_from = "HistogramForm")]
+pub struct Histogram {
+    buckets: Vec<u64>,
+    count: u64,
+    sum: u128,
+    min: u64,
+    max: u64,
+}
+
+impl Histogram {
+    /// Creates an empty histogram.
+    pub fn new() -> Histogram {
+        Histogram {
+            buckets: vec![0; BUCKETS],
+            count: 0,
+            sum: 0,
+            min: u64::MAX,
+            max: 0,
+        }
+    }
+
+    /// Records a latency.
+    pub fn record(&mut self, latency: Duration) {
+        let nanos = latency.as_nanos().min(u128::from(u64::MAX)) as u64;
+        self.buckets[bucket(nanos)] += 1;
+        self.count += 1;
+        self.sum += u128::from(nanos);
+        self.min = self.min.min(nanos);
+        self.max = self.max.max(nanos);
+    }
+
+    /// Returns the number of recorded latencies.
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    /// Returns the smallest recorded latency, or zero if none was recorded.
+    pub fn min(&self) -> Duration {
+        if self.count == 0 {
+            Duration::from_nanos(0)
+        } else {
+            Duration::from_nanos(self.min)
+        }
+    }
+
+    /// Returns the largest recorded latency.
+    pub fn max(&self) -> Duration {
+        Duration::from_nanos(self.max)
+    }
+
+    /// Returns the sum of the recorded latencies.
+    pub fn sum(&self) -> Duration {
+        let secs = self.sum / 1_000_000_000;
+        let nanos = (self.sum % 1_000_000_000) as u32;
+        Duration::new(secs.min(u128::from(u64::MAX)) as u64, nanos)
+    }
+
+    /// Returns the mean of the recorded latencies.
+    pub fn mean(&self) -> Duration {
+        if self.count == 0 {
+            Duration::from_nanos(0)
+        } else {
+            Duration::from_nanos((self.sum / u128::from(self.count)) as u64)
+        }
+    }
+
+    /// Returns the latency below which the given percentage of recorded latencies
+    /// fall, e.g. `percentile(99.0)`.
+    pub fn percentile(&self, percentile: f64) -> Duration {
+        if self.count == 0 {
+            return Duration::from_nanos(0);
+        }
+        let rank = ((percentile / 100.0 * self.count as f64).ceil() as u64).clamp(1, self.count);
+        let mut seen = 0;
+        for (index, &count) in self.buckets.iter().enumerate() {
+            seen += count;
+            if seen >= rank {
+                return Duration::from_nanos(upper_bound(index).clamp(self.min, self.max));
+            }
+        }
+        self.max()
+    }
+}
+
+impl Default for Histogram {
+    fn default() -> Histogram {
+        Histogram::new()
+    }
+}
+
+/// The serialized form of a `Histogram`.
+#[derive(Serialize, Deserialize, Default)]
+#[serde(default)]
+struct HistogramForm {
+    count: u64,
+    sum: Duration,
+    min: Duration,
+    max: Duration,
+    // derived from the buckets, so they are not read back.
+    #[serde(skip_deserializing)]
+    p50: Duration,
+    #[serde(skip_deserializing)]
+    p90: Duration,
+    #[serde(skip_deserializing)]
+    p99: Duration,
+    // the indices and counts of the non-empty buckets.
+    buckets: Vec<(usize, u64)>,
+}
+
+impl From<Histogram> for HistogramForm {
+    fn from(histogram: Histogram) -> HistogramForm {
+        HistogramForm {
+            count: histogram.count(),
+            sum: histogram.sum(),
+            min: histogram.min(),
+            max: histogram.max(),
+            p50: histogram.percentile(50.0),
+            p90: histogram.percentile(90.0),
+            p99: histogram.percentile(99.0),
+            buckets: histogram
+                .buckets
+                .iter()
+                .cloned()
+                .enumerate()
+                .filter(|&(_, count)| count > 0)
+                .collect(),
+        }
+    }
+}
+
+impl TryFrom<HistogramForm> for Histogram {
+    type Error = String;
+
+    fn try_from(form: HistogramForm) -> Result<Histogram, String> {
+        let mut histogram = Histogram::new();
+        for (index, count) in form.buckets {
+            if index >= BUCKETS {
+                return Err(format!("bucket {} out of range", index));
+            }
+            histogram.buckets[index] += count;
+        }
+        if histogram.buckets.iter().sum::<u64>() != form.count {
+            return Err("bucket counts don't add up to the count".to_owned());
+        }
+        histogram.count = form.count;
+        histogram.sum = form.sum.as_nanos();
+        if form.count > 0 {
+            histogram.min = form.min.as_nanos().min(u128::from(u64::MAX)) as u64;
+            histogram.max = form.max.as_nanos().min(u128::from(u64::MAX)) as u64;
+        }
+        Ok(histogram)
+    }
+}
+
+/// Returns the bucket of a value.
+fn bucket(value: u64) -> usize {
+    if value < SUB_BUCKETS as u64 {
+        return value as usize;
+    }
+    let shift = 63 - value.leading_zeros() - SUB_BITS;
+    // the leading bit is implied by the shift; the remaining bits pick the sub-bucket.
+    (shift as usize + 1) * SUB_BUCKETS + ((value >> shift) as usize - SUB_BUCKETS)
+}
+
+/// Returns the largest value of a bucket.
+fn upper_bound(index: usize) -> u64 {
+    if index < SUB_BUCKETS {
+        return index as u64;
+    }
+    let shift = (index / SUB_BUCKETS - 1) as u32;
+    let sub = (index % SUB_BUCKETS + SUB_BUCKETS) as u128;
+    (((sub + 1) << shift) - 1).min(u128::from(u64::MAX)) as u64
+}
+
+/// Latency histograms of the operations of a `KvStore`, counts of purged keys, and the
+/// bytes written to the log.
+///
+/// Its serialized form is stable as described in [`schema`](crate::schema).
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+#[serde(default)]
+pub struct Stats {
+    /// Reads, including `get_with_meta` and `get_in`.
+    pub get: Histogram,
+    /// Writes of values, including compactions they trigger.
+    pub set: Histogram,
+    /// Removals, including compactions they trigger.
+    pub remove: Histogram,
+    /// Compactions, whether triggered automatically or explicitly.
+    pub compaction: Histogram,
+    /// Expired keys removed by `purge_expired`.
+    pub purged_keys: u64,
+    /// Bytes of the log records of the expired keys removed by `purge_expired`.
+    pub purged_bytes: u64,
+    /// Least recently used keys removed by `Options::cache_capacity`.
+    pub evicted_keys: u64,
+    /// Bytes of the log records of the evicted keys.
+    pub evicted_bytes: u64,
+    /// Bytes appended to the log by writes.
+    pub written_bytes: u64,
+    /// Bytes written to the log by compactions.
+    pub compacted_bytes: u64,
+    /// Bytes of stale commands a compaction would reclaim when the stats were taken.
+    pub compaction_debt: u64,
+    /// Writes slowed down by `Options::write_stall`.
+    pub stalled_writes: u64,
+    /// Time writes were slowed down for.
+    pub stall_time: Duration,
+    /// Writes rejected with `KvsError::Busy`.
+    pub rejected_writes: u64,
+    /// Bytes of log records read by `Options::warm` when the store was opened.
+    pub warmed_bytes: u64,
+}
+
+impl Stats {
+    /// Returns the bytes written to disk per byte written by writes, which is 1 until a
+    /// compaction rewrites live entries.
+    ///
+    /// Returns 1 if nothing was written.
+    pub fn write_amplification(&self) -> f64 {
+        if self.written_bytes == 0 {
+            return 1.0;
+        }
+        (self.written_bytes + self.compacted_bytes) as f64 / self.written_bytes as f64
+    }
+
+    /// Records the latency of an operation in its histogram.
+    pub(crate) fn record(&mut self, op: Operation, latency: Duration) {
+        match op {
+            Operation::Get => self.get.record(latency),
+            Operation::Set => self.set.record(latency),
+            Operation::Remove => self.remove.record(latency),
+            Operation::Compaction => self.compaction.record(latency),
+        }
+    }
+}
@@ -0,0 +1,161 @@
+//! Recording and replaying traces of store operations.
+//!
+//! A trace is a file with one JSON event per line, such as
+//! `{"at":1500,"op":"set","key":"key1","value":"value1"}`, where `at` is the time of
+//! the operation in microseconds since the start of the trace. Traces are recorded
+//! with [`Options::record_trace`](crate::Options::record_trace) and replayed with
+//! [`replay`] to evaluate performance changes on a realistic workload.
+
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use log::warn;
+use serde::{Deserialize, Serialize};
+
+use crate::redact::Redactor;
+use crate::{KvStore, KvsError, Result, Stats};
+
+/// An operation of a trace.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+#[serde(tag = "op", rename_all = "lowercase")]
+pub enum Op {
+    /// A read of a key.
+    Get {
+        /// The key.
+        key: String,
+    },
+    /// A write of a value.
+    Set {
+        /// The key.
+        key: String,
+        /// The value.
+        value: String,
+    },
+    /// A removal of a key.
+    Remove {
+        /// The key.
+        key: String,
+    },
+}
+
+/// An operation together with its time in the trace.
+///
+/// Its serialized form is stable as described in [`schema`](crate::schema).
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct Event {
+    /// Microseconds since the start of the trace.
+    pub at: u64,
+    /// The operation.
+    #[serde(flatten)]
+    pub op: Op,
+}
+
+/// Appends the operations of a store to a trace file.
+pub(crate) struct Recorder {
+    start: Instant,
+    writer: Mutex<BufWriter<File>>,
+    redactor: Option<Arc<dyn Redactor>>,
+}
+
+impl Recorder {
+    /// Creates the trace file, replacing any existing one.
+    ///
+    /// The values of recorded "set" operations are rewritten by the redactor, if any.
+    pub(crate) fn create(path: &Path, redactor: Option<Arc<dyn Redactor>>) -> Result<Recorder> {
+        Ok(Recorder {
+            start: Instant::now(),
+            writer: Mutex::new(BufWriter::new(File::create(path)?)),
+            redactor,
+        })
+    }
+
+    /// Records an operation happening now.
+    ///
+    /// Failures are logged instead of failing the operation.
+    pub(crate) fn record(&self, op: Op) {
+        let op = match (op, &self.redactor) {
+            (Op::Set { key, value }, Some(redactor)) => {
+                let value = redactor.redact(&key, &value);
+                Op::Set { key, value }
+            }
+            (op, _) => op,
+        };
+        let event = Event {
+            at: self.start.elapsed().as_micros() as u64,
+            op,
+        };
+        let mut writer = self.writer.lock().unwrap();
+        let written = serde_json::to_writer(&mut *writer, &event)
+            .map_err(KvsError::from)
+            .and_then(|_| Ok(writer.write_all(b"\n")?));
+        if let Err(e) = written {
+            warn!("failed to record a trace event: {}", e);
+        }
+    }
+}
+
+impl Drop for Recorder {
+    fn drop(&mut self) {
+        if let Err(e) = self.writer.get_mut().unwrap().flush() {
+            warn!("failed to flush the trace: {}", e);
+        }
+    }
+}
+
+/// Reads the events of a trace file.
+///
+/// # Errors
+///
+/// It propagates I/O or deserialization errors during reading the trace.
+pub fn read(path: impl AsRef<Path>) -> Result<Vec<Event>> {
+    let reader = BufReader::new(File::open(path)?);
+    let mut events = Vec::new();
+    for line in reader.lines() {
+        let line = line?;
+        if !line.trim().is_empty() {
+            events.push(serde_json::from_str(&line)?);
+        }
+    }
+    Ok(events)
+}
+
+/// Replays the events of a trace against a store and returns the latencies of the
+/// operations.
+///
+/// With `timed`, every operation waits for its time in the trace, so the store sees the
+/// recorded rate. Otherwise the operations are issued back to back. Removals of missing
+/// keys are ignored, as they may have failed when the trace was recorded too.
+///
+/// The stats of the store are reset before replaying.
+///
+/// # Errors
+///
+/// It propagates errors of the operations.
+pub fn replay(store: &mut KvStore, events: &[Event], timed: bool) -> Result<Stats> {
+    store.reset_stats();
+    let start = Instant::now();
+    for event in events {
+        if timed {
+            let at = start + Duration::from_micros(event.at);
+            let now = Instant::now();
+            if at > now {
+                thread::sleep(at - now);
+            }
+        }
+        match &event.op {
+            Op::Get { key } => {
+                store.get(key.clone())?;
+            }
+            Op::Set { key, value } => store.set(key.clone(), value.clone())?,
+            Op::Remove { key } => match store.remove(key.clone()) {
+                Ok(()) | Err(KvsError::KeyNotFound) => {}
+                Err(e) => return Err(e),
+            },
+        }
+    }
+    Ok(store.stats())
+}
@@ -0,0 +1,45 @@
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+use log::warn;
+
+use crate::Result;
+
+/// The file of the keys read most often, for `HotSet::Frequent`.
+const HOT: &str = "HOT";
+
+/// Writes the keys read most often, most often first.
+pub(crate) fn save(dir: &Path, keys: &[&str]) -> Result<()> {
+    let path = hot_path(dir);
+    let tmp_path = path.with_extension("tmp");
+    let mut writer = BufWriter::new(File::create(&tmp_path)?);
+    serde_json::to_writer(&mut writer, keys)?;
+    writer.flush()?;
+    writer.get_ref().sync_all()?;
+    fs::rename(&tmp_path, path)?;
+    Ok(())
+}
+
+/// Reads the keys read most often, most often first.
+///
+/// Returns `None` if they were never saved or can't be read.
+pub(crate) fn load(dir: &Path) -> Result<Option<Vec<String>>> {
+    let path = hot_path(dir);
+    let reader = match File::open(&path) {
+        Ok(file) => BufReader::new(file),
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    match serde_json::from_reader(reader) {
+        Ok(keys) => Ok(Some(keys)),
+        Err(e) => {
+            warn!("ignored {}: {}", path.display(), e);
+            Ok(None)
+        }
+    }
+}
+
+fn hot_path(dir: &Path) -> PathBuf {
+    dir.join(HOT)
+}